@@ -0,0 +1,95 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStore, Options, SegmentHeader};
+use std::fs;
+use std::time::SystemTime;
+use tempfile::TempDir;
+
+const KEYS: u64 = 10_000;
+
+fn populate() -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    for key_id in 0..KEYS {
+        store
+            .set(format!("key{}", key_id), format!("value{:0>100}", key_id))
+            .unwrap();
+    }
+    temp_dir
+}
+
+// The same keys and values as `populate`, in segments of format version 1, which encoded records with
+// msgpack.
+fn populate_msgpack() -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let header = SegmentHeader {
+        version: 1,
+        created_at: SystemTime::now(),
+        created_by: "0.1.0".to_owned(),
+    };
+
+    let mut segment_id = 0;
+    let mut buf = header.encode();
+    for key_id in 0..KEYS {
+        let record = (
+            key_id + 1,
+            0u8,
+            format!("key{}", key_id),
+            format!("value{:0>100}", key_id),
+        );
+        let payload = rmp_serde::to_vec(&record).unwrap();
+        buf.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        buf.extend_from_slice(&payload);
+
+        if buf.len() > 1000 || key_id == KEYS - 1 {
+            fs::write(temp_dir.path().join(format!("{:08}.bcd", segment_id)), &buf).unwrap();
+            segment_id += 1;
+            buf = header.encode();
+        }
+    }
+    temp_dir
+}
+
+// Opening a store decodes every record, comparing the current layout with msgpack.
+fn open(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+
+    for (name, temp_dir) in [("binary", populate()), ("msgpack", populate_msgpack())].iter() {
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| KvStore::open(temp_dir.path()).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+// Reads keys spread over every segment, comparing reads through the file with reads from memory maps.
+fn get(c: &mut Criterion) {
+    let temp_dir = populate();
+    let mut group = c.benchmark_group("get");
+
+    for mmap in [false, true].iter() {
+        let name = if *mmap { "mmap" } else { "file" };
+        let mut store = KvStore::open_with_options(
+            temp_dir.path(),
+            Options {
+                mmap: *mmap,
+                ..Options::default()
+            },
+        )
+        .unwrap();
+
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            let mut key_id = 0;
+            b.iter(|| {
+                // a stride coprime with KEYS visits every key in a scattered order
+                key_id = (key_id + 7919) % KEYS;
+                store.get(format!("key{}", key_id)).unwrap().unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, get, open);
+criterion_main!(benches);
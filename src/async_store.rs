@@ -0,0 +1,95 @@
+use crate::{KvStore, KvsError, Result};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce(&mut KvStore) + Send>;
+
+/// An asynchronous handle to a `KvStore`.
+///
+/// The store is owned by a dedicated worker thread, so file I/O never runs on the async runtime.
+/// Operations are queued in submission order the moment they are called: dropping the returned
+/// future only discards the result, a submitted `set` or `remove` is still applied in full.
+#[derive(Clone)]
+pub struct AsyncKvStore {
+    sender: mpsc::Sender<Job>,
+}
+
+impl AsyncKvStore {
+    pub fn new(store: KvStore) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+
+        thread::Builder::new()
+            .name("kvs-worker".to_string())
+            .spawn(move || {
+                let mut store = store;
+                for job in receiver {
+                    job(&mut store);
+                }
+            })
+            .expect("failed to spawn kvs worker thread");
+
+        AsyncKvStore { sender }
+    }
+
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let (tx, rx) = oneshot::channel();
+
+        thread::Builder::new().name("kvs-open".to_string()).spawn(move || {
+            let _ = tx.send(KvStore::open(&path).map(AsyncKvStore::new));
+        })?;
+
+        KvsFuture { rx }.await
+    }
+
+    pub fn get(&self, key: String) -> KvsFuture<Option<String>> {
+        self.execute(move |store| store.get(key))
+    }
+
+    pub fn set(&self, key: String, value: String) -> KvsFuture<()> {
+        self.execute(move |store| store.set(key, value))
+    }
+
+    pub fn remove(&self, key: String) -> KvsFuture<()> {
+        self.execute(move |store| store.remove(key))
+    }
+
+    /// Runs `f` against the store on the worker thread, after every previously submitted operation.
+    pub fn execute<T, F>(&self, f: F) -> KvsFuture<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut KvStore) -> Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        // If the worker is gone the job is dropped together with `tx`, which resolves the future
+        // with `KvsError::WorkerStopped`.
+        let _ = self.sender.send(Box::new(move |store| {
+            let _ = tx.send(f(store));
+        }));
+
+        KvsFuture { rx }
+    }
+}
+
+/// The result of an operation submitted to an `AsyncKvStore`.
+pub struct KvsFuture<T> {
+    rx: oneshot::Receiver<Result<T>>,
+}
+
+impl<T> Future for KvsFuture<T> {
+    type Output = Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match Pin::new(&mut self.rx).poll(cx) {
+            Poll::Ready(Ok(result)) => Poll::Ready(result),
+            Poll::Ready(Err(_)) => Poll::Ready(Err(KvsError::WorkerStopped)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
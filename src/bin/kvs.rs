@@ -1,9 +1,19 @@
 use clap::load_yaml;
 use clap::App;
-use kvs;
+use kvs::dump::SegmentDump;
+use kvs::replication::{Follower, Leader};
+use kvs::verify;
+use kvs::{CompactOptions, CompactProgress, ExportEncoding, ExportFormat, KvStore, KvsError, RestorePoint, Tail};
+use serde_json::json;
 use std::env;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
 use std::path::Path;
 use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 fn main() -> kvs::Result<()> {
     let yaml = load_yaml!("cli.yml");
@@ -20,15 +30,32 @@ fn main() -> kvs::Result<()> {
     }
 
     let curr_path = std::env::current_dir()?;
-    let mut store = kvs::KvStore::open(&curr_path)?;
 
     match app_m.subcommand() {
         ("get", Some(sub_m)) => {
             if let Some(key) = sub_m.value_of("key") {
-                if let Some(value) = store.get(key.to_string())? {
-                    println!("{}", value);
+                let mut store = KvStore::open(&curr_path)?;
+                let ns = sub_m.value_of("ns");
+                if let Some(out) = sub_m.value_of("out") {
+                    let rdr = match ns {
+                        Some(ns) => store.namespace(ns)?.get_reader(key)?,
+                        None => store.get_reader(key)?,
+                    };
+                    if let Some(mut rdr) = rdr {
+                        io::copy(&mut rdr, &mut fs::File::create(out)?)?;
+                    } else {
+                        println!("Key not found");
+                    }
                 } else {
-                    println!("Key not found");
+                    let value = match ns {
+                        Some(ns) => store.namespace(ns)?.get(key.to_string())?,
+                        None => store.get(key.to_string())?,
+                    };
+                    if let Some(value) = value {
+                        println!("{}", value);
+                    } else {
+                        println!("Key not found");
+                    }
                 }
                 process::exit(0);
             } else {
@@ -37,8 +64,21 @@ fn main() -> kvs::Result<()> {
             }
         }
         ("set", Some(sub_m)) => {
-            if let (Some(key), Some(value)) = (sub_m.value_of("key"), sub_m.value_of("value")) {
-                store.set(key.to_string(), value.to_string())?;
+            if let (Some(key), Some(file)) = (sub_m.value_of("key"), sub_m.value_of("file")) {
+                let f = fs::File::open(file)?;
+                let len = f.metadata()?.len();
+                let mut store = KvStore::open(&curr_path)?;
+                match sub_m.value_of("ns") {
+                    Some(ns) => store.namespace(ns)?.set_reader(key.to_string(), f, len)?,
+                    None => store.set_reader(key.to_string(), f, len)?,
+                }
+                process::exit(0);
+            } else if let (Some(key), Some(value)) = (sub_m.value_of("key"), sub_m.value_of("value")) {
+                let mut store = KvStore::open(&curr_path)?;
+                match sub_m.value_of("ns") {
+                    Some(ns) => store.namespace(ns)?.set(key.to_string(), value.to_string())?,
+                    None => store.set(key.to_string(), value.to_string())?,
+                }
                 process::exit(0);
             } else {
                 app_m.usage();
@@ -47,7 +87,12 @@ fn main() -> kvs::Result<()> {
         }
         ("rm", Some(sub_m)) => {
             if let Some(key) = sub_m.value_of("key") {
-                match store.remove(key.to_string()) {
+                let mut store = KvStore::open(&curr_path)?;
+                let removed = match sub_m.value_of("ns") {
+                    Some(ns) => store.namespace(ns)?.remove(key.to_string()),
+                    None => store.remove(key.to_string()),
+                };
+                match removed {
                     Ok(_) => {
                         process::exit(0);
                     }
@@ -61,9 +106,485 @@ fn main() -> kvs::Result<()> {
                 process::exit(1);
             }
         }
+        ("lead", Some(sub_m)) => {
+            if let Some(addr) = sub_m.value_of("addr") {
+                let store = Arc::new(Mutex::new(KvStore::open(&curr_path)?));
+                let leader = Leader::bind(addr, store.clone())?;
+                println!("Listening on {}", leader.local_addr());
+
+                // Followers may only be written to through their leader, so it takes the writes in on
+                // stdin while it serves them.
+                for line in io::stdin().lock().lines() {
+                    let line = line?;
+                    let mut args = line.splitn(3, ' ');
+                    let written = match (args.next(), args.next(), args.next()) {
+                        (Some("set"), Some(key), Some(value)) => {
+                            store.lock().unwrap().set(key.to_string(), value.to_string())
+                        }
+                        (Some("rm"), Some(key), None) => store.lock().unwrap().remove(key.to_string()),
+                        _ => {
+                            println!("Unknown command {:?}", line);
+                            continue;
+                        }
+                    };
+                    match written {
+                        Ok(()) => println!("OK"),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+                process::exit(0);
+            } else {
+                app_m.usage();
+                process::exit(1);
+            }
+        }
+        ("follow", Some(sub_m)) => {
+            if let Some(addr) = sub_m.value_of("addr") {
+                let store = KvStore::open(&curr_path)?;
+                let follower = Follower::connect(addr, Arc::new(Mutex::new(store)))?;
+                if sub_m.is_present("once") {
+                    if !follower.wait_synced() {
+                        println!("Leader disconnected before the follower caught up");
+                        process::exit(1);
+                    }
+                    follower.promote()?;
+                } else {
+                    follower.join()?;
+                }
+                process::exit(0);
+            } else {
+                app_m.usage();
+                process::exit(1);
+            }
+        }
+        ("watch", Some(sub_m)) => {
+            let prefix = sub_m.value_of("prefix").unwrap_or("");
+            let since = sub_m.value_of("since").map(|since| since.parse::<u64>());
+            let interval = sub_m.value_of("interval").map(|interval| interval.parse::<u64>());
+
+            let (mut tail, interval) = match (since, interval.unwrap_or(Ok(500))) {
+                (None, Ok(interval)) => (Tail::new(&curr_path)?, interval),
+                (Some(Ok(since)), Ok(interval)) => (Tail::since(&curr_path, since), interval),
+                _ => {
+                    app_m.usage();
+                    process::exit(1);
+                }
+            };
+
+            // Another process owns the store, so its files are only ever read.
+            loop {
+                match tail.poll() {
+                    Ok(events) => {
+                        for event in events {
+                            if !event.key.starts_with(prefix) {
+                                continue;
+                            }
+                            match event.value {
+                                Some(value) => println!("{} set {} {}", event.timestamp, event.key, value),
+                                None => println!("{} rm {}", event.timestamp, event.key),
+                            }
+                        }
+                    }
+                    Err(KvsError::Compacted(horizon)) => {
+                        eprintln!("changes up to {} have been compacted away, skipping ahead", horizon);
+                    }
+                    // a segment was removed by a concurrent compaction, try again on the next poll
+                    Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+
+                if sub_m.is_present("once") {
+                    process::exit(0);
+                }
+                thread::sleep(Duration::from_millis(interval));
+            }
+        }
+        ("backup", Some(sub_m)) => {
+            if let Some(dest) = sub_m.value_of("dest") {
+                let mut store = KvStore::open(&curr_path)?;
+                store.checkpoint(Path::new(dest))?;
+                process::exit(0);
+            } else {
+                app_m.usage();
+                process::exit(1);
+            }
+        }
+        ("restore", Some(sub_m)) => {
+            let until = sub_m.value_of("until").map(parse_restore_point);
+            match (sub_m.value_of("src"), until) {
+                (Some(src), None) => KvStore::restore(Path::new(src), &curr_path)?,
+                (Some(src), Some(Some(point))) => {
+                    let restored = KvStore::restore_until(Path::new(src), &curr_path, point)?;
+                    println!("restored {} keys", restored);
+                }
+                _ => {
+                    app_m.usage();
+                    process::exit(1);
+                }
+            }
+            process::exit(0);
+        }
+        ("export", Some(sub_m)) => {
+            let store = KvStore::open(&curr_path)?;
+            let format = match sub_m.value_of("format") {
+                Some("csv") => ExportFormat::Csv,
+                _ => ExportFormat::Jsonl,
+            };
+            let encoding = if sub_m.is_present("base64") {
+                ExportEncoding::Base64
+            } else {
+                ExportEncoding::Text
+            };
+
+            match sub_m.value_of("output") {
+                Some(output) => store.export(fs::File::create(output)?, format, encoding)?,
+                None => store.export(io::stdout().lock(), format, encoding)?,
+            };
+            process::exit(0);
+        }
+        ("import", Some(sub_m)) => {
+            let mut store = KvStore::open(&curr_path)?;
+            let format = match sub_m.value_of("format") {
+                Some("csv") => ExportFormat::Csv,
+                _ => ExportFormat::Jsonl,
+            };
+
+            if sub_m.is_present("truncate") {
+                store.clear()?;
+            }
+
+            match sub_m.value_of("input") {
+                Some(input) if input != "-" => store.import(fs::File::open(input)?, format)?,
+                _ => store.import(io::stdin().lock(), format)?,
+            };
+            process::exit(0);
+        }
+        ("stats", Some(sub_m)) => {
+            let mut store = KvStore::open(&curr_path)?;
+            if let Some(ns) = sub_m.value_of("ns") {
+                let stats = store.namespace(ns)?.stats();
+                if sub_m.is_present("json") {
+                    let stats = json!({
+                        "live_keys": stats.live_keys,
+                        "live_bytes": stats.live_bytes,
+                        "value_bytes": stats.value_bytes,
+                    });
+                    println!("{}", stats);
+                } else {
+                    println!("live keys: {}", stats.live_keys);
+                    println!("bytes: {} live, {} in values", stats.live_bytes, stats.value_bytes);
+                }
+                process::exit(0);
+            }
+            let stats = store.stats();
+
+            if sub_m.is_present("json") {
+                let segments: Vec<_> = stats
+                    .segments
+                    .iter()
+                    .map(|segment| {
+                        json!({
+                            "file_name": segment.file_name,
+                            "records": segment.records,
+                            "tombstones": segment.tombstones,
+                            "total_bytes": segment.total_bytes,
+                            "live_bytes": segment.live_bytes,
+                            "dead_bytes": segment.dead_bytes,
+                        })
+                    })
+                    .collect();
+                let last_compaction = stats.last_compaction.as_ref().map(|compaction| {
+                    json!({
+                        "finished_at_ms": compaction
+                            .finished_at
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(0, |since| since.as_millis() as u64),
+                        "duration_ms": compaction.duration.as_millis() as u64,
+                        "bytes_reclaimed": compaction.bytes_reclaimed,
+                    })
+                });
+                let stats = json!({
+                    "live_keys": stats.live_keys,
+                    "keydir_bytes": stats.keydir_bytes,
+                    "total_bytes": stats.total_bytes(),
+                    "live_bytes": stats.live_bytes(),
+                    "dead_bytes": stats.dead_bytes(),
+                    "tombstones": stats.tombstones(),
+                    "value_log_bytes": stats.value_log_bytes,
+                    "live_data_bytes": stats.live_data_bytes,
+                    "evictions": stats.evictions,
+                    "cache_hits": stats.cache_hits,
+                    "cache_misses": stats.cache_misses,
+                    "cache_bytes": stats.cache_bytes,
+                    "namespaces": store.namespaces(),
+                    "segments": segments,
+                    "last_compaction": last_compaction,
+                });
+                println!("{}", stats);
+            } else {
+                println!("live keys: {} ({} bytes indexed)", stats.live_keys, stats.keydir_bytes);
+                let namespaces = store.namespaces();
+                if !namespaces.is_empty() {
+                    println!("namespaces: {}", namespaces.join(", "));
+                }
+                println!("segments: {}", stats.segments.len());
+                println!(
+                    "bytes: {} total, {} live, {} dead",
+                    stats.total_bytes(),
+                    stats.live_bytes(),
+                    stats.dead_bytes()
+                );
+                println!("tombstones: {}", stats.tombstones());
+                println!("live data: {} bytes", stats.live_data_bytes);
+                println!("evictions: {}", stats.evictions);
+                if stats.value_log_bytes > 0 {
+                    println!("value log: {} bytes", stats.value_log_bytes);
+                }
+                println!(
+                    "cache: {} hits, {} misses, {} bytes",
+                    stats.cache_hits, stats.cache_misses, stats.cache_bytes
+                );
+                if let Some(compaction) = stats.last_compaction.as_ref() {
+                    println!(
+                        "last compaction: {} bytes reclaimed in {:?}",
+                        compaction.bytes_reclaimed, compaction.duration
+                    );
+                }
+                for segment in stats.segments.iter() {
+                    println!(
+                        "{}: {} records ({} tombstones), {} bytes total, {} live, {} dead",
+                        segment.file_name,
+                        segment.records,
+                        segment.tombstones,
+                        segment.total_bytes,
+                        segment.live_bytes,
+                        segment.dead_bytes
+                    );
+                }
+            }
+            process::exit(0);
+        }
+        ("compact", Some(sub_m)) => {
+            let dry_run = sub_m.is_present("dry-run");
+            let mut options = CompactOptions::new()
+                .dry_run(dry_run)
+                .progress(|progress: &CompactProgress| {
+                    eprintln!(
+                        "compacted {}/{} segments, {} bytes read, {} bytes written",
+                        progress.segments_done, progress.segments_total, progress.bytes_read, progress.bytes_written
+                    );
+                });
+            if let Some(segments) = sub_m.values_of("segments") {
+                options = options.segments(segments.map(String::from).collect());
+            }
+            if let Some(rate) = sub_m.value_of("rate") {
+                match rate.parse::<u64>() {
+                    Ok(rate) => options = options.rate_limit(rate),
+                    Err(_) => {
+                        app_m.usage();
+                        process::exit(1);
+                    }
+                }
+            }
+
+            let mut store = KvStore::open(&curr_path)?;
+            let reclaimed = store.compact(options)?;
+            if dry_run {
+                println!("would reclaim up to {} bytes", reclaimed);
+            } else {
+                println!("reclaimed {} bytes", reclaimed);
+            }
+            process::exit(0);
+        }
+        ("dump", Some(sub_m)) => {
+            let width = sub_m.value_of("width").unwrap_or("32").parse::<usize>();
+            let (segment, width) = match (sub_m.value_of("segment"), width) {
+                (Some(segment), Ok(width)) => (Path::new(segment), width),
+                _ => {
+                    app_m.usage();
+                    process::exit(1);
+                }
+            };
+
+            let mut records = SegmentDump::open(segment)?;
+            if !sub_m.is_present("json") && !sub_m.is_present("key") {
+                match records.header() {
+                    Some(header) => println!(
+                        "# format version {}, created by kvs {} at {}",
+                        header.version,
+                        header.created_by,
+                        header
+                            .created_at
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(0, |since| since.as_secs())
+                    ),
+                    None => println!("# format version 0"),
+                }
+            }
+            let mut failure = None;
+            for record in records.by_ref() {
+                let mut record = match record {
+                    Ok(record) => record,
+                    Err(e) => {
+                        failure = Some(e);
+                        break;
+                    }
+                };
+                if sub_m.value_of("key").is_some_and(|key| key != record.key) {
+                    continue;
+                }
+
+                record.value = truncate(record.value, width);
+                if sub_m.is_present("json") {
+                    println!("{}", serde_json::to_string(&record).map_err(io::Error::from)?);
+                } else if record.tombstone {
+                    println!(
+                        "{} {} {} rm {:?}",
+                        record.offset, record.len, record.timestamp, record.key
+                    );
+                } else if let Some((file_name, offset)) = record.value_log {
+                    println!(
+                        "{} {} {} set {:?} -> {}@{}",
+                        record.offset, record.len, record.timestamp, record.key, file_name, offset
+                    );
+                } else {
+                    println!(
+                        "{} {} {} set {:?} {:?}",
+                        record.offset, record.len, record.timestamp, record.key, record.value
+                    );
+                }
+            }
+
+            let offset = records.offset();
+            if let Some(e) = failure {
+                eprintln!("undecodable record at offset {}: {}", offset, e);
+                process::exit(1);
+            }
+            let len = fs::metadata(segment)?.len();
+            if offset < len {
+                eprintln!(
+                    "{} bytes at offset {} do not form a complete record",
+                    len - offset,
+                    offset
+                );
+            }
+            process::exit(0);
+        }
+        ("migrate", Some(_)) => {
+            let migrated = KvStore::migrate(&curr_path)?;
+            println!("migrated {} segments", migrated);
+            process::exit(0);
+        }
+        ("verify", Some(sub_m)) => {
+            let report = verify::verify(&curr_path)?;
+            println!("{}", report);
+
+            if let Some(dest) = sub_m.value_of("repair") {
+                let salvaged = verify::repair(&curr_path, Path::new(dest))?;
+                println!("salvaged {} keys into {}", salvaged, dest);
+            }
+
+            process::exit(if report.is_ok() { 0 } else { 1 });
+        }
         _ => {
             app_m.usage();
             process::exit(1);
         }
     }
 }
+
+// Shortens `value` to `width` characters, leaving it whole when `width` is 0.
+fn truncate(value: String, width: usize) -> String {
+    if width == 0 || value.chars().count() <= width {
+        return value;
+    }
+
+    let mut truncated: String = value.chars().take(width).collect();
+    truncated.push_str("...");
+    truncated
+}
+
+// Reads `--until` as a record timestamp if it is a number, and as an RFC 3339 time otherwise.
+fn parse_restore_point(until: &str) -> Option<RestorePoint> {
+    if let Ok(timestamp) = until.parse::<u64>() {
+        return Some(RestorePoint::Timestamp(timestamp));
+    }
+    parse_datetime(until).map(RestorePoint::Time)
+}
+
+// Parses a time like `2024-05-01T12:00:00.250+02:00`, with `Z` for UTC and optional fractional
+// seconds, as RFC 3339 has it.
+fn parse_datetime(s: &str) -> Option<SystemTime> {
+    let digits = |text: &str, range: std::ops::Range<usize>| {
+        let field = text.get(range)?;
+        if !field.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        field.parse::<i64>().ok()
+    };
+    let field = |range| digits(s, range);
+    let bytes = s.as_bytes();
+    if bytes.len() < 20 || bytes[4] != b'-' || bytes[7] != b'-' || bytes[13] != b':' || bytes[16] != b':' {
+        return None;
+    }
+    if !matches!(bytes[10], b'T' | b't' | b' ') {
+        return None;
+    }
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    if !(1..=12).contains(&month) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_len = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=month_len).contains(&day) {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    let mut nanos = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        let padded = format!("{:0<9}", &fraction[..digits.min(9)]);
+        nanos = padded.parse::<u32>().ok()?;
+        rest = &fraction[digits..];
+    }
+    let offset = match rest {
+        "Z" | "z" => 0,
+        _ if rest.len() == 6 && (rest.starts_with('+') || rest.starts_with('-')) && rest.as_bytes()[3] == b':' => {
+            let minutes = digits(rest, 1..3)? * 60 + digits(rest, 4..6)?;
+            if rest.starts_with('-') {
+                -minutes * 60
+            } else {
+                minutes * 60
+            }
+        }
+        _ => return None,
+    };
+
+    // days since the Unix epoch of the civil date, counting years from March so leap days come last
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second - offset;
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::new(secs as u64, nanos))
+}
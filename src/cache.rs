@@ -0,0 +1,82 @@
+use crate::keydir::SegmentId;
+use lru::LruCache;
+
+/// Values recently read by `KvStore::get`, keyed by where their record sits in the log.
+///
+/// A `set` or `remove` moves a key to a new record, so entries for the old one are simply never
+/// looked up again and age out. Compaction reuses segment names, so it drops their entries.
+pub(crate) struct ValueCache {
+    entries: LruCache<(SegmentId, u64), String>,
+    capacity: usize,
+    bytes: usize,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+impl ValueCache {
+    /// A cache holding up to `capacity` bytes of values.
+    pub(crate) fn new(capacity: usize) -> Self {
+        ValueCache {
+            entries: LruCache::unbounded(),
+            capacity,
+            bytes: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, segment_id: SegmentId, record_pos: u64) -> Option<String> {
+        match self.entries.get(&(segment_id, record_pos)) {
+            Some(value) => {
+                self.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub(crate) fn insert(&mut self, segment_id: SegmentId, record_pos: u64, value: String) {
+        if value.len() > self.capacity {
+            return;
+        }
+
+        self.bytes += value.len();
+        if let Some(old) = self.entries.put((segment_id, record_pos), value) {
+            self.bytes -= old.len();
+        }
+        while self.bytes > self.capacity {
+            match self.entries.pop_lru() {
+                Some((_, evicted)) => self.bytes -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Drops every entry for records in `segment_id`.
+    pub(crate) fn invalidate_segment(&mut self, segment_id: SegmentId) {
+        let stale: Vec<(SegmentId, u64)> = self
+            .entries
+            .iter()
+            .filter(|((entry_segment_id, _), _)| *entry_segment_id == segment_id)
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in stale {
+            if let Some(value) = self.entries.pop(&key) {
+                self.bytes -= value.len();
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
@@ -0,0 +1,300 @@
+use crate::format::{SegmentHeader, FORMAT_VERSION, MIN_READABLE_VERSION};
+use crate::keydir::segment_file_name;
+use crate::reader;
+use crate::record::RecordRef;
+use crate::value_log;
+use crate::{write_horizon, KvStore, KvsError, Result};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Written last, so a directory without it is an incomplete checkpoint.
+const MANIFEST: &str = "CHECKPOINT";
+
+impl KvStore {
+    /// Writes a consistent copy of the store to `dest`.
+    ///
+    /// Sealed segments are never modified again, so they are hard linked (or copied if `dest` is on
+    /// another file system); the active segment is copied up to its current length. The value log
+    /// is checkpointed the same way, and the horizon is recorded in the manifest. The checkpoint
+    /// should be treated as read-only and brought back with `KvStore::restore`.
+    pub fn checkpoint(&mut self, dest: &Path) -> Result<()> {
+        fs::create_dir_all(dest)?;
+        ensure_no_store(dest)?;
+
+        let mut manifest = format!("last_timestamp {}\n", self.last_timestamp());
+        manifest.push_str(&format!("horizon {}\n", self.horizon));
+        let active = self.segments.len() - 1;
+
+        for (i, segment_id) in self.segments.iter().enumerate() {
+            let file_name = segment_file_name(*segment_id);
+            let src = self.path.join(&file_name);
+            let dst = dest.join(&file_name);
+            let len = self.file_handles[segment_id].metadata()?.len();
+
+            if i == active || fs::hard_link(&src, &dst).is_err() {
+                copy_prefix(&src, &dst, len)?;
+            }
+
+            manifest.push_str(&format!("segment {} {}\n", file_name, len));
+        }
+
+        // the value log is laid out like the segments, and only its last file is still written to
+        let value_log_files: Vec<(String, u64)> = self.value_log.files().collect();
+        for (i, (file_name, len)) in value_log_files.iter().enumerate() {
+            let src = self.path.join(file_name);
+            let dst = dest.join(file_name);
+            if i == value_log_files.len() - 1 || fs::hard_link(&src, &dst).is_err() {
+                copy_prefix(&src, &dst, *len)?;
+            }
+
+            manifest.push_str(&format!("value_log {} {}\n", file_name, len));
+        }
+
+        let mut f = fs::File::create(dest.join(MANIFEST))?;
+        f.write_all(manifest.as_bytes())?;
+        f.sync_all()?;
+
+        Ok(())
+    }
+
+    /// Copies the checkpoint in `checkpoint` into `path`, which must not already contain a store.
+    pub fn restore(checkpoint: &Path, path: &Path) -> Result<()> {
+        let manifest = read_manifest(checkpoint)?;
+
+        fs::create_dir_all(path)?;
+        ensure_no_store(path)?;
+
+        for (file_name, len) in manifest.segments.iter().chain(manifest.value_logs.iter()) {
+            copy_prefix(&checkpoint.join(file_name), &path.join(file_name), *len)?;
+        }
+        // checkpoints from before the horizon was recorded leave it to be worked out from the log
+        if let Some(horizon) = manifest.horizon {
+            write_horizon(path, horizon)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rebuilds the store as it was at `point` from the checkpoint in `checkpoint` into `path`,
+    /// which must not already contain a store, and returns the number of keys restored.
+    ///
+    /// The segments are replayed in timestamp order, stopping before the first change past `point`,
+    /// and the live value of each key is written out with its original timestamp. Compaction only
+    /// keeps the versions the retention asks for, so a point further back than those may find
+    /// values missing or newer than they were; with `Retention::SinceTimestamp(t)`, restoring to
+    /// any point from timestamp `t` on is exact. `collect_value_log` keeps the values of the same
+    /// versions, so it takes nothing more away.
+    ///
+    /// The segments are read one record at a time, and values from the value log are written back
+    /// into the segments. Only the last version of each key is restored, so the history before
+    /// `point` is gone from the new store as if compacted away.
+    pub fn restore_until(checkpoint: &Path, path: &Path, point: RestorePoint) -> Result<u64> {
+        let manifest = read_manifest(checkpoint)?;
+        let mut value_logs = HashMap::new();
+        for (file_name, _) in manifest.value_logs {
+            if let Ok(file_id) = file_name.trim_end_matches(".vlog").parse::<u32>() {
+                value_logs.insert(file_id, fs::File::open(checkpoint.join(&file_name))?);
+            }
+        }
+        let mut segments = Vec::new();
+        for (file_name, len) in manifest.segments {
+            let file = fs::File::open(checkpoint.join(&file_name))?;
+            let header = SegmentHeader::read(&file)?;
+            let version = SegmentHeader::version_of(header.as_ref());
+            if !(MIN_READABLE_VERSION..=FORMAT_VERSION).contains(&version) {
+                return Err(KvsError::UnsupportedFormat { file_name, version });
+            }
+            segments.push((file, len, SegmentHeader::records_start(header.as_ref()), version));
+        }
+
+        let cut = match point {
+            RestorePoint::Timestamp(timestamp) => timestamp,
+            RestorePoint::Time(time) => {
+                let time = time
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis() as u64);
+                let mut cut = u64::MAX;
+                for_each_record(&segments, |record, _, _, _| {
+                    if record.written_at > time {
+                        cut = std::cmp::min(cut, record.timestamp - 1);
+                    }
+                })?;
+                cut
+            }
+        };
+
+        // the last record of each key up to the cut, as its timestamp, whether its value is gone, and
+        // its segment, position and length; garbage collection leaves a copy of a record whose value
+        // it moved, under the same timestamp, and that copy is the one to take
+        let mut latest: HashMap<String, (u64, bool, usize, u64, u64)> = HashMap::new();
+        let mut horizon = std::cmp::min(manifest.horizon.unwrap_or(0), cut);
+        for_each_record(&segments, |record, i, pos, len| {
+            if record.timestamp > cut {
+                return;
+            }
+            horizon = std::cmp::max(horizon, record.timestamp);
+            let orphaned = record
+                .value_pointer
+                .is_some_and(|pointer| !value_logs.contains_key(&pointer.file_id));
+            let newer = latest.get(record.key).is_none_or(|&(timestamp, was_orphaned, ..)| {
+                timestamp < record.timestamp || (timestamp == record.timestamp && was_orphaned && !orphaned)
+            });
+            if newer {
+                latest.insert(record.key.to_string(), (record.timestamp, orphaned, i, pos, len));
+            }
+        })?;
+
+        let mut positions: Vec<(u64, usize, u64, u64)> = latest
+            .into_values()
+            .filter(|&(_, orphaned, ..)| !orphaned)
+            .map(|(timestamp, _, i, pos, len)| (timestamp, i, pos, len))
+            .collect();
+        positions.sort_unstable();
+
+        fs::create_dir_all(path)?;
+        ensure_no_store(path)?;
+        let mut store = KvStore::open(path)?;
+        let mut restored = 0;
+        let mut buf = Vec::new();
+        for chunk in positions.chunks(1024) {
+            let mut batch = Vec::with_capacity(chunk.len());
+            for &(_, i, pos, len) in chunk {
+                let (file, _, _, version) = &segments[i];
+                buf.resize(len as usize, 0);
+                reader::read_exact_at(file, &mut buf, pos)?;
+                let (mut record, _) = reader::decode_record_at(&buf, 0, *version)?;
+                if record.tombstone == 1 {
+                    continue;
+                }
+                if let Some(pointer) = record.value_pointer.take() {
+                    record.value = value_log::read_value(&value_logs[&pointer.file_id], &record.key, pointer)?;
+                }
+                batch.push(record);
+            }
+            store.write_records(&batch)?;
+            restored += batch.len() as u64;
+        }
+        store.sync_active_segment()?;
+        store.value_log.sync()?;
+        store.set_horizon(horizon)?;
+
+        Ok(restored)
+    }
+}
+
+/// How far `KvStore::restore_until` replays a checkpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestorePoint {
+    /// Up to and including the change made at this timestamp.
+    Timestamp(u64),
+    /// Up to the first change written after this time. Records from before format version 3 carry
+    /// no time and never end the replay.
+    Time(SystemTime),
+}
+
+// Calls `f` with every record of `segments`, given as their files, the length of them the
+// checkpoint holds, where their records start and their format version, along with the index of its
+// segment and its position and length in it.
+fn for_each_record<F>(segments: &[(fs::File, u64, u64, u16)], mut f: F) -> Result<()>
+where
+    F: FnMut(RecordRef, usize, u64, u64),
+{
+    for (i, (file, len, start, version)) in segments.iter().enumerate() {
+        let mut reader = reader::Reader::new(file, *version);
+        let mut pos = *start;
+        let mut next_pos = 0;
+        while let Some(record) = reader.read_record_ref(io::SeekFrom::Start(pos), &mut next_pos)? {
+            if next_pos > *len {
+                break;
+            }
+            f(record, i, pos, next_pos - pos);
+            pos = next_pos;
+        }
+    }
+    Ok(())
+}
+
+// What the manifest of a checkpoint lists.
+struct Manifest {
+    horizon: Option<u64>,
+    // the files with the length of them the checkpoint holds
+    segments: Vec<(String, u64)>,
+    value_logs: Vec<(String, u64)>,
+}
+
+// Reads the manifest of `checkpoint`, checking that each of the files it lists is there in full.
+fn read_manifest(checkpoint: &Path) -> Result<Manifest> {
+    let manifest = match fs::read_to_string(checkpoint.join(MANIFEST)) {
+        Ok(manifest) => manifest,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(KvsError::InvalidCheckpoint(format!("no {} file", MANIFEST)));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let bad_line = |line: &str| KvsError::InvalidCheckpoint(format!("bad line: {}", line));
+    let mut parsed = Manifest {
+        horizon: None,
+        segments: Vec::new(),
+        value_logs: Vec::new(),
+    };
+    for line in manifest.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["last_timestamp", _] => {}
+            ["horizon", horizon] => parsed.horizon = Some(horizon.parse().map_err(|_| bad_line(line))?),
+            [tag @ ("segment" | "value_log"), file_name, len] => {
+                let len: u64 = len.parse().map_err(|_| bad_line(line))?;
+                // checkpoints from before value log files had a tag of their own list them as segments
+                if *tag == "value_log" || Path::new(file_name).extension() == Some(OsStr::new("vlog")) {
+                    parsed.value_logs.push((file_name.to_string(), len));
+                } else {
+                    parsed.segments.push((file_name.to_string(), len));
+                }
+            }
+            _ => return Err(bad_line(line)),
+        }
+    }
+
+    for (file_name, len) in parsed.segments.iter().chain(parsed.value_logs.iter()) {
+        let actual_len = match fs::metadata(checkpoint.join(file_name)) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        if actual_len < *len {
+            return Err(KvsError::InvalidCheckpoint(format!(
+                "{} is missing or truncated",
+                file_name
+            )));
+        }
+    }
+
+    Ok(parsed)
+}
+
+pub(crate) fn ensure_no_store(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.path().extension() == Some(OsStr::new("bcd")) && entry.metadata()?.len() > SegmentHeader::LEN {
+            let message = format!("{} already contains a store", path.display());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, message).into());
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_prefix(src: &Path, dst: &Path, len: u64) -> Result<()> {
+    let src = fs::File::open(src)?;
+    let mut dst = fs::File::create(dst)?;
+
+    io::copy(&mut src.take(len), &mut dst)?;
+    dst.sync_all()?;
+
+    Ok(())
+}
@@ -0,0 +1,554 @@
+use crate::format::{SegmentHeader, FORMAT_VERSION};
+use crate::history::Retention;
+use crate::keydir::{segment_file_name, KeyInfo, SegmentId};
+use crate::record::RecordRef;
+use crate::stats::SegmentUsage;
+use crate::{
+    create_segment_file, reader, write_horizon, writer, CompactionStats, KvStore, KvsError, RateLimiter, Result,
+    SegmentStats, SEGMENT_SIZE_LIMIT,
+};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Seek};
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime};
+
+/// Decides which segments are worth rewriting.
+///
+/// The policy is consulted after every write that finds no compaction under way. Compacting a
+/// segment rewrites its live records (and the tombstones still shadowing older records) and drops
+/// everything else.
+pub trait CompactionPolicy: Send {
+    /// Returns indexes into `segments` of the segments to compact, or nothing to skip compaction.
+    ///
+    /// `segments` holds the sealed segments, oldest first; the segment being written to is never
+    /// offered.
+    fn select(&self, segments: &[SegmentStats]) -> Vec<usize>;
+}
+
+/// Compacts segments that are mostly garbage, leaving segments that are mostly live alone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GarbageRatioPolicy {
+    /// A segment is compacted once more than this fraction of its bytes is dead.
+    pub max_garbage_ratio: f64,
+    /// Once the sealed segments hold more dead bytes than this in total, every segment with any
+    /// dead bytes is compacted.
+    pub max_dead_bytes: u64,
+}
+
+impl Default for GarbageRatioPolicy {
+    fn default() -> Self {
+        GarbageRatioPolicy {
+            max_garbage_ratio: 0.5,
+            max_dead_bytes: 16 * SEGMENT_SIZE_LIMIT,
+        }
+    }
+}
+
+impl CompactionPolicy for GarbageRatioPolicy {
+    fn select(&self, segments: &[SegmentStats]) -> Vec<usize> {
+        let dead_bytes: u64 = segments.iter().map(|segment| segment.dead_bytes).sum();
+        let over_budget = dead_bytes > self.max_dead_bytes;
+
+        segments
+            .iter()
+            .enumerate()
+            .filter(|(_, segment)| {
+                segment.dead_bytes > 0
+                    && (over_budget || segment.dead_bytes as f64 > self.max_garbage_ratio * segment.total_bytes as f64)
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+/// Progress of a compaction, reported after each segment is rewritten.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactProgress {
+    pub segments_done: usize,
+    pub segments_total: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+type ProgressCallback<'a> = Box<dyn FnMut(&CompactProgress) + 'a>;
+
+/// What `KvStore::compact` rewrites and how.
+#[derive(Default)]
+pub struct CompactOptions<'a> {
+    pub(crate) segments: Option<Vec<String>>,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    pub(crate) dry_run: bool,
+    pub(crate) progress: Option<ProgressCallback<'a>>,
+}
+
+impl<'a> CompactOptions<'a> {
+    /// Compacts every segment, including the one being written to.
+    pub fn new() -> Self {
+        CompactOptions::default()
+    }
+
+    /// Compacts only the segments with these file names.
+    pub fn segments(mut self, file_names: Vec<String>) -> Self {
+        self.segments = Some(file_names);
+        self
+    }
+
+    /// Limits the bytes read and written by the compaction to about `bytes_per_sec`, instead of
+    /// the store's compaction rate limit.
+    pub fn rate_limit(self, bytes_per_sec: u64) -> Self {
+        self.rate_limiter(RateLimiter::new(bytes_per_sec))
+    }
+
+    /// Draws the compaction's I/O from `rate_limiter`, instead of the store's compaction rate limit.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Only works out how many bytes compacting would reclaim.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    pub fn progress<F: FnMut(&CompactProgress) + 'a>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub(crate) fn report(&mut self, progress: &CompactProgress) {
+        if let Some(callback) = self.progress.as_mut() {
+            callback(progress);
+        }
+    }
+}
+
+impl KvStore {
+    /// Compacts the segments chosen by `options`, returning the number of bytes reclaimed.
+    ///
+    /// With `CompactOptions::dry_run` nothing is rewritten and the result is the number of dead
+    /// bytes in those segments, an upper bound on what compacting them would reclaim.
+    pub fn compact(&mut self, mut options: CompactOptions) -> Result<u64> {
+        let to_be_compacted: Vec<usize> = match options.segments.as_ref() {
+            None => (0..self.segments.len()).collect(),
+            Some(file_names) => {
+                let mut to_be_compacted = Vec::new();
+                for file_name in file_names.iter() {
+                    match self
+                        .segments
+                        .iter()
+                        .position(|&segment_id| segment_file_name(segment_id) == *file_name)
+                    {
+                        Some(i) => to_be_compacted.push(i),
+                        None => return Err(KvsError::UnknownSegment(file_name.clone())),
+                    }
+                }
+                to_be_compacted.sort_unstable();
+                to_be_compacted.dedup();
+                to_be_compacted
+            }
+        };
+
+        if options.dry_run {
+            let stats = self.stats();
+            return Ok(to_be_compacted.iter().map(|&i| stats.segments[i].dead_bytes).sum());
+        }
+        if to_be_compacted.is_empty() {
+            return Ok(0);
+        }
+
+        self.compaction(&to_be_compacted, &mut options)
+    }
+}
+
+/// A compaction under way, which the writes after the one that started it carry on with.
+///
+/// The kept records are copied to the merged files without touching the keydir, so that the store
+/// goes on reading from the compacted segments until the merged files replace them; what was copied
+/// is only pointed at then, if it is still current.
+pub(crate) struct CompactionJob {
+    started: Instant,
+    segment_ids: Vec<SegmentId>,
+    // tombstones at or below this may shadow nothing, as no segment left alone is older
+    oldest_kept: u64,
+    // the index into `segment_ids` of the segment being read, and how far
+    source: usize,
+    source_pos: u64,
+    merged_files: Vec<(PathBuf, fs::File)>,
+    copied: Vec<CopiedRecord>,
+    // the versions dropped, to take out of the history once the merged files are in
+    dropped: Vec<(String, u64, SegmentId, u64)>,
+    // the newest tombstone dropped
+    horizon: u64,
+    progress: CompactProgress,
+}
+
+// A record copied to a merged file, with where it was read from.
+struct CopiedRecord {
+    key: String,
+    timestamp: u64,
+    tombstone: bool,
+    source: (SegmentId, u64),
+    // the index into `segment_ids` of the segment whose name the merged file takes over
+    merged: usize,
+    record_pos: u64,
+    record_len: u64,
+    value_len: u32,
+    in_value_log: bool,
+}
+
+impl CompactionJob {
+    // Deletes the merged files, leaving the compacted segments as they are.
+    fn discard(self) {
+        for (merged_file_path, _) in self.merged_files {
+            let _ = fs::remove_file(merged_file_path);
+        }
+    }
+}
+
+impl KvStore {
+    /// Rewrites the segments at `to_be_compacted`, given in ascending order, keeping only what is
+    /// still needed, and gives up on any compaction the policy has under way.
+    ///
+    /// The kept records are written to as few merged files as they fit in, each taking over the name
+    /// of one of the compacted segments; the compacted segments left over are deleted. Returns the
+    /// number of bytes reclaimed.
+    pub(crate) fn compaction(&mut self, to_be_compacted: &[usize], options: &mut CompactOptions) -> Result<u64> {
+        self.cancel_compaction();
+        let rate_limiter = options
+            .rate_limiter
+            .clone()
+            .unwrap_or_else(|| self.compaction_rate_limiter.clone());
+
+        let mut job = self.start_compaction(to_be_compacted);
+        match self.advance_compaction(&mut job, &rate_limiter, true, options) {
+            Ok(_) => self.finish_compaction(job),
+            Err(e) => {
+                job.discard();
+                Err(e)
+            }
+        }
+    }
+
+    /// Starts compacting the segments the policy selects, or carries on with the compaction it has
+    /// under way.
+    ///
+    /// Each write only does as much of the merge as the store's compaction rate limit allows right
+    /// away, so a throttled compaction is spread over many writes rather than holding one of them
+    /// back, and the store stays usable in between.
+    pub(crate) fn compact_by_policy(&mut self) -> Result<()> {
+        let mut job = match self.compaction_job.take() {
+            Some(job) => job,
+            None => {
+                let stats = self.stats();
+                let sealed = &stats.segments[..stats.segments.len() - 1];
+
+                let mut selected = self.compaction_policy.select(sealed);
+                selected.retain(|&i| i < sealed.len());
+                selected.sort_unstable();
+                selected.dedup();
+                if selected.is_empty() {
+                    return Ok(());
+                }
+                self.start_compaction(&selected)
+            }
+        };
+
+        let rate_limiter = self.compaction_rate_limiter.clone();
+        match self.advance_compaction(&mut job, &rate_limiter, false, &mut CompactOptions::new()) {
+            Ok(true) => {
+                self.finish_compaction(job)?;
+            }
+            Ok(false) => self.compaction_job = Some(job),
+            Err(e) => {
+                job.discard();
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives up on the compaction the policy has under way, if any.
+    pub(crate) fn cancel_compaction(&mut self) {
+        if let Some(job) = self.compaction_job.take() {
+            job.discard();
+        }
+    }
+
+    fn start_compaction(&self, to_be_compacted: &[usize]) -> CompactionJob {
+        let segment_ids: Vec<SegmentId> = to_be_compacted.iter().map(|&i| self.segments[i]).collect();
+
+        // A tombstone can only be dropped if no other segment may hold an older record of its key.
+        let oldest_kept = self
+            .segments
+            .iter()
+            .filter(|segment_id| !segment_ids.contains(segment_id))
+            .filter_map(|segment_id| self.segment_usage.get(segment_id))
+            .map(|usage| usage.min_timestamp)
+            .min()
+            .unwrap_or(u64::MAX);
+
+        CompactionJob {
+            started: Instant::now(),
+            progress: CompactProgress {
+                segments_done: 0,
+                segments_total: segment_ids.len(),
+                bytes_read: 0,
+                bytes_written: 0,
+            },
+            segment_ids,
+            oldest_kept,
+            source: 0,
+            source_pos: SegmentHeader::LEN,
+            merged_files: Vec::new(),
+            copied: Vec::new(),
+            dropped: Vec::new(),
+            horizon: 0,
+        }
+    }
+
+    // Copies the records `job` keeps to the merged files, waiting on `rate_limiter` if `wait` is set
+    // or otherwise stopping once it runs out. Returns whether every segment has been read.
+    fn advance_compaction(
+        &mut self,
+        job: &mut CompactionJob,
+        rate_limiter: &RateLimiter,
+        wait: bool,
+        options: &mut CompactOptions,
+    ) -> Result<bool> {
+        let charge = |bytes| {
+            if wait {
+                rate_limiter.acquire(bytes);
+            } else {
+                rate_limiter.try_acquire(bytes);
+            }
+        };
+
+        while job.source < job.segment_ids.len() {
+            let source_segment_id = job.segment_ids[job.source];
+            let mut rdr = self.file_handles.get(&source_segment_id).unwrap();
+            rdr.seek(io::SeekFrom::Start(job.source_pos))?;
+
+            let buf_reader = io::BufReader::with_capacity(1024, rdr);
+            let mut reader = reader::Reader::new(buf_reader, self.segment_versions[&source_segment_id]);
+
+            let mut curr_offset = job.source_pos;
+            let mut next_offset = 0;
+            loop {
+                if !wait && !rate_limiter.try_acquire(0) {
+                    job.source_pos = curr_offset;
+                    return Ok(false);
+                }
+
+                // records are only copied out of the read buffer if they are kept
+                let record = match reader.read_record_ref(io::SeekFrom::Current(0), &mut next_offset)? {
+                    Some(record) => record,
+                    None => break,
+                };
+                let record_pos = curr_offset;
+                job.progress.bytes_read += next_offset - curr_offset;
+                charge(next_offset - curr_offset);
+                curr_offset = next_offset;
+
+                let keyinfo = self.keydir.get(record.key);
+                // the value of an orphaned record was deleted by garbage collection, after moving it
+                // to a new record under the same timestamp if it was live or retained
+                let orphaned = record
+                    .value_pointer
+                    .is_some_and(|pointer| !self.value_log.contains(pointer));
+                let live = !orphaned && keyinfo.is_some_and(|keyinfo| keyinfo.timestamp == record.timestamp);
+                let retained = !orphaned
+                    && self.options.retention != Retention::Latest
+                    && self.history.retains(
+                        self.options.retention,
+                        record.key,
+                        record.timestamp,
+                        keyinfo.map(|keyinfo| keyinfo.timestamp),
+                    );
+                let keep = if record.tombstone == 1 {
+                    (keyinfo.is_none() && record.timestamp > job.oldest_kept) || retained
+                } else {
+                    live || retained
+                };
+
+                if !keep {
+                    // the history of an orphaned record already points at where its value was moved
+                    if !orphaned {
+                        let dropped = (record.key.to_string(), record.timestamp, source_segment_id, record_pos);
+                        job.dropped.push(dropped);
+                    }
+                    if record.tombstone == 1 {
+                        // followers behind a dropped tombstone can no longer catch up from the log
+                        job.horizon = std::cmp::max(job.horizon, record.timestamp);
+                    }
+                    continue;
+                }
+
+                let roll = match job.merged_files.last() {
+                    Some((_, merged_file)) => {
+                        job.merged_files.len() < job.segment_ids.len()
+                            && merged_file.metadata()?.len() > SEGMENT_SIZE_LIMIT
+                    }
+                    None => true,
+                };
+                if roll {
+                    let file_name = segment_file_name(job.segment_ids[job.merged_files.len()]);
+                    let merged_file_path = self.path.join(format!("{}1.merge", file_name.trim_end_matches(".bcd")));
+                    // left over from an interrupted compaction
+                    if merged_file_path.exists() {
+                        fs::remove_file(&merged_file_path)?;
+                    }
+
+                    let merged_file = create_segment_file(&merged_file_path)?;
+                    job.merged_files.push((merged_file_path, merged_file));
+                }
+
+                let merged_file = &job.merged_files.last().unwrap().1;
+                let file_offset = (&*merged_file).seek(io::SeekFrom::End(0))?;
+                let mut writer = writer::Writer::new(merged_file);
+                let record_len = writer.write_record_ref(record)?;
+                job.progress.bytes_written += record_len;
+                charge(record_len);
+
+                job.copied.push(CopiedRecord {
+                    key: record.key.to_string(),
+                    timestamp: record.timestamp,
+                    tombstone: record.tombstone == 1,
+                    source: (source_segment_id, record_pos),
+                    merged: job.merged_files.len() - 1,
+                    record_pos: file_offset,
+                    record_len,
+                    value_len: record.value_len() as u32,
+                    in_value_log: record.value_pointer.is_some(),
+                });
+            }
+
+            job.source += 1;
+            job.source_pos = SegmentHeader::LEN;
+            job.progress.segments_done += 1;
+            options.report(&job.progress);
+        }
+
+        Ok(true)
+    }
+
+    // Puts the merged files of `job` in place of the segments it compacted, returning the number of
+    // bytes reclaimed.
+    fn finish_compaction(&mut self, job: CompactionJob) -> Result<u64> {
+        let mut bytes_before = 0;
+        for &segment_id in job.segment_ids.iter() {
+            if let Some(usage) = self.segment_usage.insert(segment_id, SegmentUsage::default()) {
+                bytes_before += usage.total_bytes;
+            }
+        }
+
+        // the records copied are only pointed at if nothing has replaced them since
+        for copied in job.copied {
+            let segment_id = job.segment_ids[copied.merged];
+            let record = RecordRef {
+                timestamp: copied.timestamp,
+                tombstone: copied.tombstone as u8,
+                key: &copied.key,
+                value: "",
+                value_pointer: None,
+                written_at: 0,
+                expires_at: 0,
+            };
+            let usage = self.segment_usage.get_mut(&segment_id).unwrap();
+            usage.add(record, copied.record_len);
+            if copied.tombstone {
+                usage.live_bytes += copied.record_len;
+                continue;
+            }
+
+            let is_source = |keyinfo: &KeyInfo| (keyinfo.segment_id, keyinfo.record_pos) == copied.source;
+            if self.keydir.get(&copied.key).is_some_and(is_source) {
+                let keyinfo = KeyInfo {
+                    segment_id,
+                    record_pos: copied.record_pos,
+                    record_len: copied.record_len as u32,
+                    value_len: copied.value_len,
+                    timestamp: copied.timestamp,
+                    in_value_log: copied.in_value_log,
+                };
+                self.keydir.insert(&copied.key, keyinfo);
+            } else if self
+                .history
+                .version(&copied.key, copied.timestamp)
+                .is_some_and(|version| is_source(&version.keyinfo))
+            {
+                let (record_pos, record_len) = (copied.record_pos, copied.record_len as u32);
+                self.history
+                    .moved(&copied.key, copied.timestamp, segment_id, record_pos, record_len);
+            } else {
+                usage.live_bytes -= copied.record_len;
+            }
+        }
+        for (key, timestamp, segment_id, record_pos) in job.dropped {
+            if self.history.version(&key, timestamp).is_some_and(|version| {
+                (version.keyinfo.segment_id, version.keyinfo.record_pos) == (segment_id, record_pos)
+            }) {
+                self.history.remove(&key, timestamp);
+            }
+        }
+
+        // saved before the tombstones are gone for good
+        if job.horizon > self.horizon {
+            self.horizon = job.horizon;
+            write_horizon(&self.path, self.horizon)?;
+        }
+
+        self.rewrites += 1;
+        let mut bytes_after = 0;
+        let mut removed = HashSet::new();
+        let mut merged_files = job.merged_files.into_iter();
+        for &segment_id in job.segment_ids.iter() {
+            let file_path = self.path.join(segment_file_name(segment_id));
+            fs::remove_file(&file_path)?;
+
+            match merged_files.next() {
+                Some((merged_file_path, merged_file)) => {
+                    fs::rename(&merged_file_path, &file_path)?;
+                    self.file_handles.insert(segment_id, merged_file);
+                    self.segment_versions.insert(segment_id, FORMAT_VERSION);
+                    bytes_after += self.segment_usage[&segment_id].total_bytes;
+                }
+                None => {
+                    self.file_handles.remove(&segment_id);
+                    self.segment_usage.remove(&segment_id);
+                    self.segment_versions.remove(&segment_id);
+                    removed.insert(segment_id);
+                }
+            }
+        }
+
+        self.segments.retain(|segment_id| !removed.contains(segment_id));
+        if self.segments.is_empty() {
+            self.create_segment()?;
+        }
+
+        // the segment written to next may have been sealed before and must not stay mapped
+        let active = *self.segments.last().unwrap();
+        self.maps.remove(&active);
+        for &segment_id in job.segment_ids.iter() {
+            if let Some(cache) = self.cache.as_mut() {
+                cache.invalidate_segment(segment_id);
+            }
+            self.maps.remove(&segment_id);
+            if !removed.contains(&segment_id) && segment_id != active {
+                self.map_segment(segment_id)?;
+            }
+        }
+
+        // rewriting segments from an older format can take more room than it frees
+        let bytes_reclaimed = bytes_before.saturating_sub(bytes_after);
+        self.last_compaction = Some(CompactionStats {
+            finished_at: SystemTime::now(),
+            duration: job.started.elapsed(),
+            bytes_reclaimed,
+        });
+
+        Ok(bytes_reclaimed)
+    }
+}
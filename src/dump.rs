@@ -0,0 +1,112 @@
+//! Record by record view of a single segment, for debugging.
+
+use crate::format::SegmentHeader;
+use crate::reader::Reader;
+use crate::record::Record;
+use crate::value_log::value_log_file_name;
+use crate::Result;
+use serde::Serialize;
+use std::fs::File;
+use std::io::SeekFrom;
+use std::path::Path;
+
+/// A record as laid out in a segment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DumpedRecord {
+    pub offset: u64,
+    /// Length on disk, including the length prefix.
+    pub len: u64,
+    pub timestamp: u64,
+    pub tombstone: bool,
+    pub key: String,
+    pub value: String,
+    /// The value log file and offset of the record holding the value, if it was moved there; `value`
+    /// is then empty.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_log: Option<(String, u64)>,
+    /// When the record was written, in milliseconds since the Unix epoch, for segments in format
+    /// version 3 or later.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub written_at: Option<u64>,
+    /// When the key expires, in milliseconds since the Unix epoch, if it was set with a TTL.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+/// Iterator over the records of a segment, in the order they were written.
+///
+/// Segments from before the format had headers are read from their first byte. Iteration stops at
+/// the first record that cannot be decoded, after yielding the error, and at a record cut short by the
+/// end of the file. `offset` then tells how far the segment was read.
+pub struct SegmentDump {
+    header: Option<SegmentHeader>,
+    reader: Reader<File>,
+    offset: u64,
+    done: bool,
+}
+
+impl SegmentDump {
+    pub fn open(path: &Path) -> Result<SegmentDump> {
+        let f = File::open(path)?;
+        let header = SegmentHeader::read(&f)?;
+        Ok(SegmentDump {
+            offset: SegmentHeader::records_start(header.as_ref()),
+            reader: Reader::new(f, SegmentHeader::version_of(header.as_ref())),
+            header,
+            done: false,
+        })
+    }
+
+    /// The header of the segment, or `None` if it predates headers.
+    pub fn header(&self) -> Option<&SegmentHeader> {
+        self.header.as_ref()
+    }
+
+    /// Offset of the first byte not yet read as part of a record.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl Iterator for SegmentDump {
+    type Item = Result<DumpedRecord>;
+
+    fn next(&mut self) -> Option<Result<DumpedRecord>> {
+        if self.done {
+            return None;
+        }
+
+        let mut record = Record::new();
+        let mut next_offset = 0;
+        match self
+            .reader
+            .read_record(SeekFrom::Start(self.offset), &mut record, &mut next_offset)
+        {
+            Ok(true) => {
+                let dumped = DumpedRecord {
+                    offset: self.offset,
+                    len: next_offset - self.offset,
+                    timestamp: record.timestamp,
+                    tombstone: record.tombstone == 1,
+                    key: record.key,
+                    value: record.value,
+                    value_log: record
+                        .value_pointer
+                        .map(|pointer| (value_log_file_name(pointer.file_id), pointer.record_pos)),
+                    written_at: Some(record.written_at).filter(|&written_at| written_at != 0),
+                    expires_at: Some(record.expires_at).filter(|&expires_at| expires_at != 0),
+                };
+                self.offset = next_offset;
+                Some(Ok(dumped))
+            }
+            Ok(false) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e.into()))
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use crate::index;
+use crate::KvStore;
+use crate::Result;
+use lru::LruCache;
+
+/// Which keys are evicted first once the store grows past `Options::max_live_bytes`.
+///
+/// Keys whose TTL has run out always go before any other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// The keys least recently read or written.
+    #[default]
+    LeastRecentlyUsed,
+    /// The keys least recently written, whether or not they were read since.
+    OldestWritten,
+    /// The keys set with a TTL, soonest to expire first, then the others least recently written.
+    TtlFirst,
+}
+
+/// The live keys in the order the policy evicts them, only kept when the store has a size limit.
+pub(crate) struct EvictionQueue {
+    policy: EvictionPolicy,
+    // least recently used, or written, first
+    keys: LruCache<Box<str>, ()>,
+}
+
+impl EvictionQueue {
+    pub(crate) fn new(policy: EvictionPolicy) -> Self {
+        EvictionQueue {
+            policy,
+            keys: LruCache::unbounded(),
+        }
+    }
+
+    pub(crate) fn written(&mut self, key: &str) {
+        if index::is_index_key(key) {
+            return;
+        }
+        if self.keys.contains(key) {
+            self.keys.promote(key);
+        } else {
+            self.keys.put(key.into(), ());
+        }
+    }
+
+    pub(crate) fn read(&mut self, key: &str) {
+        if self.policy == EvictionPolicy::LeastRecentlyUsed {
+            self.keys.promote(key);
+        }
+    }
+
+    pub(crate) fn removed(&mut self, key: &str) {
+        self.keys.pop(key);
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+impl KvStore {
+    /// Writes tombstones for keys, in the order of the eviction policy, until the live records are
+    /// back under `Options::max_live_bytes`, and leaves reclaiming their space to compaction.
+    pub(crate) fn evict(&mut self) -> Result<()> {
+        let max_live_bytes = match self.options.max_live_bytes {
+            Some(max_live_bytes) => max_live_bytes,
+            None => return Ok(()),
+        };
+        if self.keydir.record_bytes() <= max_live_bytes {
+            return Ok(());
+        }
+
+        self.evictions += self.write_expired_tombstones()?;
+        while self.keydir.record_bytes() > max_live_bytes {
+            let key = match self.next_victim() {
+                Some(key) => key,
+                None => break,
+            };
+            self.write_tombstone(key)?;
+            self.evictions += 1;
+        }
+
+        Ok(())
+    }
+
+    fn next_victim(&self) -> Option<String> {
+        if self.options.eviction == EvictionPolicy::TtlFirst {
+            if let Some((_, key)) = self.expiries.iter().next() {
+                return Some(key.to_string());
+            }
+        }
+        let queue = self.eviction_queue.as_ref()?;
+        queue.keys.peek_lru().map(|(key, _)| key.to_string())
+    }
+}
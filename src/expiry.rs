@@ -0,0 +1,92 @@
+use crate::namespace;
+use crate::record::{self, Record};
+use crate::{KvStore, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+/// When each key set with a TTL expires, in milliseconds since the Unix epoch.
+#[derive(Debug, Default)]
+pub(crate) struct Expiries {
+    by_key: HashMap<Box<str>, u64>,
+    // soonest first
+    by_time: BTreeSet<(u64, Box<str>)>,
+}
+
+impl Expiries {
+    /// Records that `key` now expires at `expires_at`, or never if it is 0.
+    pub(crate) fn set(&mut self, key: &str, expires_at: u64) {
+        if let Some(old) = self.by_key.remove(key) {
+            self.by_time.remove(&(old, key.into()));
+        }
+        if expires_at != 0 {
+            self.by_key.insert(key.into(), expires_at);
+            self.by_time.insert((expires_at, key.into()));
+        }
+    }
+
+    pub(crate) fn is_expired(&self, key: &str) -> bool {
+        !self.by_key.is_empty()
+            && self
+                .by_key
+                .get(key)
+                .is_some_and(|&expires_at| expires_at <= record::wall_clock())
+    }
+
+    /// The keys with a TTL, soonest to expire first.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.by_time.iter().map(|(expires_at, key)| (*expires_at, &key[..]))
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.by_key.clear();
+        self.by_time.clear();
+    }
+}
+
+impl KvStore {
+    /// Sets `key` to `value` for `ttl`, after which the key reads as missing.
+    ///
+    /// Expired keys stay in the log until `remove_expired` or eviction writes tombstones for them,
+    /// but `get`, `contains_key` and iteration leave them out. Setting the key again without a TTL
+    /// makes it permanent.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        namespace::check_key(&key)?;
+        let written_at = record::wall_clock();
+        let new_record = Record {
+            timestamp: self.counter,
+            tombstone: 0,
+            key,
+            value,
+            value_pointer: None,
+            written_at,
+            // a TTL too long to represent never runs out
+            expires_at: written_at.saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX).max(1)),
+        };
+
+        self.write_indexed(new_record)?;
+        self.maybe_compact()
+    }
+
+    /// Writes a tombstone for every key whose TTL has run out, returning how many there were.
+    pub fn remove_expired(&mut self) -> Result<u64> {
+        let removed = self.write_expired_tombstones()?;
+        self.maybe_compact()?;
+        Ok(removed)
+    }
+
+    pub(crate) fn write_expired_tombstones(&mut self) -> Result<u64> {
+        let now = record::wall_clock();
+        let expired: Vec<String> = self
+            .expiries
+            .iter()
+            .take_while(|&(expires_at, _)| expires_at <= now)
+            .map(|(_, key)| key.to_string())
+            .collect();
+
+        for key in expired.iter() {
+            self.write_tombstone(key.clone())?;
+        }
+        Ok(expired.len() as u64)
+    }
+}
@@ -0,0 +1,101 @@
+use crate::reader;
+use crate::Result;
+use byteorder::{BigEndian, ByteOrder};
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The segment format written by this version of the crate.
+///
+/// Version 4 adds the time a key expires at, version 3 the wall-clock time each record was written
+/// at, version 2 lays records out in a fixed binary format with a checksum, version 1 encoded them
+/// with msgpack, and version 0 is the original headerless layout of version 1.
+pub const FORMAT_VERSION: u16 = 4;
+
+/// The oldest segment format `KvStore::open` reads in place; older stores have to be migrated first.
+pub const MIN_READABLE_VERSION: u16 = 1;
+
+const MAGIC: [u8; 4] = *b"KVSG";
+const CREATED_BY_LEN: usize = 18;
+
+/// The header at the start of every segment, ahead of its records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub version: u16,
+    pub created_at: SystemTime,
+    /// Version of the crate that created the segment.
+    pub created_by: String,
+}
+
+impl SegmentHeader {
+    /// Length of the header on disk: the magic number, version, creation time in milliseconds, then
+    /// the crate version padded with zeros.
+    pub const LEN: u64 = 4 + 2 + 8 + CREATED_BY_LEN as u64;
+
+    /// A header for a segment created now in the current format.
+    pub(crate) fn new() -> SegmentHeader {
+        SegmentHeader {
+            version: FORMAT_VERSION,
+            created_at: SystemTime::now(),
+            created_by: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// The header as it is laid out at the start of a segment.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0; SegmentHeader::LEN as usize];
+        buf[..4].copy_from_slice(&MAGIC);
+        BigEndian::write_u16(&mut buf[4..6], self.version);
+        let created_at = self.created_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        BigEndian::write_u64(&mut buf[6..14], created_at.as_millis() as u64);
+
+        let created_by = self.created_by.as_bytes();
+        let len = created_by.len().min(CREATED_BY_LEN);
+        buf[14..14 + len].copy_from_slice(&created_by[..len]);
+
+        buf
+    }
+
+    /// Parses the header at the start of `buf`, or returns `None` if there is none because the
+    /// segment predates headers.
+    pub fn decode(buf: &[u8]) -> Result<Option<SegmentHeader>> {
+        // a headerless segment starts with the length of its first record, whose high bytes are zero
+        if buf.len() < MAGIC.len() || buf[..4] != MAGIC {
+            return Ok(None);
+        }
+        if (buf.len() as u64) < SegmentHeader::LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "segment header cut short").into());
+        }
+
+        let created_at = UNIX_EPOCH + Duration::from_millis(BigEndian::read_u64(&buf[6..14]));
+        let created_by = &buf[14..SegmentHeader::LEN as usize];
+        let created_by = &created_by[..created_by.iter().position(|&b| b == 0).unwrap_or(CREATED_BY_LEN)];
+
+        Ok(Some(SegmentHeader {
+            version: BigEndian::read_u16(&buf[4..6]),
+            created_at,
+            created_by: String::from_utf8_lossy(created_by).into_owned(),
+        }))
+    }
+
+    /// Reads the header of the segment in `file`, as `decode` does.
+    pub(crate) fn read(file: &fs::File) -> Result<Option<SegmentHeader>> {
+        let len = std::cmp::min(file.metadata()?.len(), SegmentHeader::LEN);
+        let mut buf = vec![0; len as usize];
+        reader::read_exact_at(file, &mut buf, 0)?;
+        SegmentHeader::decode(&buf)
+    }
+
+    /// The offset of the first record in a segment with this header, or without one.
+    pub(crate) fn records_start(header: Option<&SegmentHeader>) -> u64 {
+        match header {
+            Some(_) => SegmentHeader::LEN,
+            None => 0,
+        }
+    }
+
+    /// The format version of a segment with this header, or without one.
+    pub(crate) fn version_of(header: Option<&SegmentHeader>) -> u16 {
+        header.map_or(0, |header| header.version)
+    }
+}
@@ -0,0 +1,240 @@
+use crate::index;
+use crate::keydir::{KeyInfo, SegmentId};
+use crate::record::RecordRef;
+use crate::{KvStore, Result};
+use std::collections::HashMap;
+
+/// Which past versions of each key compaction keeps, set with `Options::retention`.
+///
+/// Kept versions can be read back with `KvStore::history` and `KvStore::get_at`, and count as live
+/// data in the stats, so compaction policies leave them alone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Retention {
+    /// Only the live version of each key.
+    #[default]
+    Latest,
+    /// The last `n` versions of each key, counting the live one and removals.
+    Versions(usize),
+    /// Every version a key had at or after timestamp `t`, so its value can be read as of any
+    /// timestamp since then. `t` is a timestamp of the store, as returned by
+    /// `KvStore::last_timestamp`, not a wall-clock time.
+    SinceTimestamp(u64),
+}
+
+impl Retention {
+    // The versions in `past`, oldest first, that are kept along with the live version written at
+    // `live`, if the key is live. They are always the newest ones.
+    fn retained(self, past: &[PastVersion], live: Option<u64>) -> &[PastVersion] {
+        let start = match self {
+            Retention::Latest => past.len(),
+            Retention::Versions(n) => past.len().saturating_sub(n.saturating_sub(live.is_some() as usize)),
+            Retention::SinceTimestamp(t) if live.is_some_and(|live| live <= t) => past.len(),
+            Retention::SinceTimestamp(t) => past.partition_point(|v| v.keyinfo.timestamp <= t).saturating_sub(1),
+        };
+        &past[start..]
+    }
+}
+
+/// A version of a key, as listed by `KvStore::history`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub timestamp: u64,
+    /// `None` if the key was removed.
+    pub value: Option<String>,
+}
+
+/// A record of a key that is not its live one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PastVersion {
+    pub(crate) keyinfo: KeyInfo,
+    pub(crate) tombstone: bool,
+}
+
+/// The records each key still has in the log besides its live one, oldest first.
+///
+/// Only kept when the retention is not `Retention::Latest`, since without it compaction needs no
+/// more than the keydir.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    versions: HashMap<Box<str>, Vec<PastVersion>>,
+}
+
+impl History {
+    pub(crate) fn push(&mut self, key: &str, version: PastVersion) {
+        let versions = match self.versions.get_mut(key) {
+            Some(versions) => versions,
+            None => self.versions.entry(key.into()).or_default(),
+        };
+        let pos = versions.partition_point(|v| v.keyinfo.timestamp < version.keyinfo.timestamp);
+        versions.insert(pos, version);
+    }
+
+    fn get(&self, key: &str) -> &[PastVersion] {
+        self.versions.get(key).map_or(&[], Vec::as_slice)
+    }
+
+    /// Whether `retention` keeps the version `key` had at `timestamp`, given the live version.
+    pub(crate) fn retains(&self, retention: Retention, key: &str, timestamp: u64, live: Option<u64>) -> bool {
+        retention
+            .retained(self.get(key), live)
+            .iter()
+            .any(|v| v.keyinfo.timestamp == timestamp)
+    }
+
+    /// The version `key` had at `timestamp`, if it is not the live one.
+    pub(crate) fn version(&self, key: &str, timestamp: u64) -> Option<PastVersion> {
+        self.get(key).iter().find(|v| v.keyinfo.timestamp == timestamp).copied()
+    }
+
+    /// Points the version `key` had at `timestamp` at where compaction moved its record.
+    pub(crate) fn moved(&mut self, key: &str, timestamp: u64, segment_id: SegmentId, record_pos: u64, record_len: u32) {
+        if let Some(v) = self
+            .versions
+            .get_mut(key)
+            .and_then(|versions| versions.iter_mut().find(|v| v.keyinfo.timestamp == timestamp))
+        {
+            v.keyinfo.segment_id = segment_id;
+            v.keyinfo.record_pos = record_pos;
+            v.keyinfo.record_len = record_len;
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str, timestamp: u64) {
+        if let Some(versions) = self.versions.get_mut(key) {
+            versions.retain(|v| v.keyinfo.timestamp != timestamp);
+            if versions.is_empty() {
+                self.versions.remove(key);
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.versions.clear();
+    }
+
+    /// Every past version, for going over once the store is opened.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &[PastVersion])> {
+        self.versions
+            .iter()
+            .map(|(key, versions)| (&key[..], versions.as_slice()))
+    }
+}
+
+impl KvStore {
+    /// The versions of `key` kept by the retention, oldest first, ending with the live one.
+    pub fn history(&self, key: &str) -> Result<Vec<Version>> {
+        let mut history = Vec::new();
+        for version in self.versions(key) {
+            if let Some(version) = self.read_version(&version)? {
+                history.push(version);
+            }
+        }
+        Ok(history)
+    }
+
+    /// The value `key` had right after the change made at `timestamp`, `None` if it did not exist.
+    ///
+    /// Only retained versions are looked at, so a time from before them reads as the key not
+    /// existing yet.
+    pub fn get_at(&self, key: &str, timestamp: u64) -> Result<Option<String>> {
+        match self
+            .versions(key)
+            .into_iter()
+            .rev()
+            .find(|v| v.keyinfo.timestamp <= timestamp)
+        {
+            Some(version) => Ok(self.read_version(&version)?.and_then(|version| version.value)),
+            None => Ok(None),
+        }
+    }
+
+    // The retained past versions of `key` followed by its live one, if any.
+    fn versions(&self, key: &str) -> Vec<PastVersion> {
+        let live = self.keydir.get(key).copied();
+        let mut versions = self
+            .options
+            .retention
+            .retained(self.history.get(key), live.map(|keyinfo| keyinfo.timestamp))
+            .to_vec();
+        if let Some(keyinfo) = live {
+            versions.push(PastVersion {
+                keyinfo,
+                tombstone: false,
+            });
+        }
+        versions
+    }
+
+    fn read_version(&self, version: &PastVersion) -> Result<Option<Version>> {
+        if version.tombstone {
+            return Ok(Some(Version {
+                timestamp: version.keyinfo.timestamp,
+                value: None,
+            }));
+        }
+
+        let mut record = self.read_raw_record_at(&version.keyinfo)?;
+        if let Some(pointer) = record.value_pointer.take() {
+            if !self.value_log.contains(pointer) {
+                return Ok(None);
+            }
+            record.value = self.value_log.read(&record.key, pointer)?;
+        }
+        Ok(Some(Version {
+            timestamp: record.timestamp,
+            value: Some(record.value),
+        }))
+    }
+
+    /// Records `record` in the history if it is a tombstone, along with `replaced`, the live record
+    /// it took the place of, and moves the space taken by the versions the retention now keeps in
+    /// or out of the live bytes.
+    pub(crate) fn track_version(&mut self, record: RecordRef, keyinfo: KeyInfo, replaced: Option<KeyInfo>) {
+        let retention = self.options.retention;
+        if retention == Retention::Latest || index::is_index_key(record.key) {
+            return;
+        }
+
+        let before: Vec<PastVersion> = retention
+            .retained(self.history.get(record.key), replaced.map(|keyinfo| keyinfo.timestamp))
+            .to_vec();
+        if let Some(replaced) = replaced {
+            self.history.push(
+                record.key,
+                PastVersion {
+                    keyinfo: replaced,
+                    tombstone: false,
+                },
+            );
+        }
+        if record.tombstone == 1 {
+            self.history.push(
+                record.key,
+                PastVersion {
+                    keyinfo,
+                    tombstone: true,
+                },
+            );
+        }
+        let live = self.keydir.get(record.key).map(|keyinfo| keyinfo.timestamp);
+        let after = retention.retained(self.history.get(record.key), live);
+
+        let retained = |versions: &[PastVersion], v: &PastVersion| {
+            versions
+                .iter()
+                .any(|other| other.keyinfo.timestamp == v.keyinfo.timestamp)
+        };
+        let mut changes: Vec<(SegmentId, i64)> = Vec::new();
+        for v in before.iter().filter(|v| !retained(after, v)) {
+            changes.push((v.keyinfo.segment_id, -i64::from(v.keyinfo.record_len)));
+        }
+        for v in after.iter().filter(|v| !retained(&before, v)) {
+            changes.push((v.keyinfo.segment_id, i64::from(v.keyinfo.record_len)));
+        }
+        for (segment_id, change) in changes {
+            if let Some(usage) = self.segment_usage.get_mut(&segment_id) {
+                usage.live_bytes = usage.live_bytes.saturating_add_signed(change);
+            }
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use crate::namespace;
+use crate::record::Record;
+use crate::{KvStore, KvsError, Result};
+use std::collections::{BTreeSet, HashMap};
+
+// Index entries are stored as keys of their own, `\0\0index\0name\0index_key\0timestamp\0primary_key`
+// with an empty value. No namespace has an empty name, so the prefix cannot clash with one.
+const INDEX_PREFIX: &str = "\0\0index\0";
+const SEPARATOR: char = '\0';
+
+// How many entries are written at a time while catching an index up with the store.
+const REBUILD_BATCH_SIZE: usize = 1024;
+
+// Maps a value to the index keys it is found under.
+type Extractor = Box<dyn Fn(&str) -> Vec<String> + Send>;
+
+/// An index registered with `KvStore::register_index`.
+pub(crate) struct SecondaryIndex {
+    extractor: Extractor,
+    // every entry of the index in the log, without the index prefix, so the entries of one index key
+    // are next to each other
+    entries: BTreeSet<String>,
+    // the entries of each primary key
+    by_primary: HashMap<String, Vec<String>>,
+}
+
+impl SecondaryIndex {
+    fn insert(&mut self, entry: &str) {
+        if let Some((_, _, primary)) = split_entry(entry) {
+            if self.entries.insert(entry.to_string()) {
+                self.by_primary
+                    .entry(primary.to_string())
+                    .or_default()
+                    .push(entry.to_string());
+            }
+        }
+    }
+
+    fn remove(&mut self, entry: &str) {
+        if let Some((_, _, primary)) = split_entry(entry) {
+            self.entries.remove(entry);
+            if let Some(entries) = self.by_primary.get_mut(primary) {
+                entries.retain(|e| e != entry);
+                if entries.is_empty() {
+                    self.by_primary.remove(primary);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.by_primary.clear();
+    }
+
+    // The index keys `value` is found under. A value with none still gets an entry, under the empty
+    // index key, so an index can tell the keys it has seen from the ones written while it was not
+    // registered.
+    fn index_keys(&self, value: &str) -> BTreeSet<String> {
+        let mut keys: BTreeSet<String> = (self.extractor)(value)
+            .into_iter()
+            .filter(|key| !key.is_empty() && !key.contains(SEPARATOR))
+            .collect();
+        if keys.is_empty() {
+            keys.insert(String::new());
+        }
+        keys
+    }
+}
+
+impl KvStore {
+    /// Registers the index `name`, which maps every value of the store to the index keys returned
+    /// by `extractor` and is queried with `query_index`.
+    ///
+    /// Indexes are kept in the log next to the records they were made from: `set`, `remove` and
+    /// `import` write a key's entries in the same batch as the key, and lookups only trust entries
+    /// made from a key's current record, so an interrupted write never shows up in them. Extractors
+    /// are code, so they have to be registered again each time the store is opened; the entries
+    /// already in the log are reused, and the keys written while the index was not registered are
+    /// indexed again by reading their values. Call `drop_index` first if the extractor changed.
+    ///
+    /// Keys in namespaces are not indexed, and neither are empty index keys or ones containing NUL
+    /// characters. Entries are left out of iteration, exports, watches, replication and the live
+    /// keys and bytes of `stats`.
+    ///
+    /// Values set with `set_reader` are the exception to writing entries in the same batch: the value
+    /// is only known once it has been streamed, so its entries follow in a batch of their own. A
+    /// crash in between leaves the key without current entries, so it is missing from lookups until
+    /// the index is registered again and indexes it like any key written while it was not.
+    ///
+    /// A follower does not replicate the leader's entries but indexes the records it applies with
+    /// the indexes it has registered, keeping those entries in memory only until it registers them
+    /// again. Register indexes on a follower before it starts following.
+    pub fn register_index<F>(&mut self, name: &str, extractor: F) -> Result<()>
+    where
+        F: Fn(&str) -> Vec<String> + Send + 'static,
+    {
+        let prefix = entry_prefix(name)?;
+        let mut index = SecondaryIndex {
+            extractor: Box::new(extractor),
+            entries: BTreeSet::new(),
+            by_primary: HashMap::new(),
+        };
+        for (key, _) in self.keydir.iter() {
+            if let Some(entry) = key.strip_prefix(prefix.as_str()) {
+                index.insert(entry);
+            }
+        }
+
+        // entries made from records that have since been overwritten or removed
+        let mut stale = Vec::new();
+        for entries in index.by_primary.values() {
+            for entry in entries {
+                let (_, timestamp, primary) = split_entry(entry).unwrap();
+                if !self.is_current(primary, timestamp) {
+                    stale.push(format!("{}{}", prefix, entry));
+                }
+            }
+        }
+        let mut missing: Vec<String> = self
+            .keydir
+            .iter()
+            .filter(|(key, _)| !namespace::is_namespaced(key))
+            .filter(|(key, keyinfo)| {
+                !index.by_primary.get(&key[..]).is_some_and(|entries| {
+                    entries
+                        .iter()
+                        .any(|entry| split_entry(entry).unwrap().1 == keyinfo.timestamp)
+                })
+            })
+            .map(|(key, _)| key.to_string())
+            .collect();
+        missing.sort_unstable();
+        self.indexes.insert(name.to_string(), index);
+
+        let mut batch: Vec<Record> = stale
+            .into_iter()
+            .enumerate()
+            .map(|(i, key)| entry_record(key, self.counter + i as u64, true))
+            .collect();
+        for key in missing {
+            let keyinfo = *self.keydir.get(&key).unwrap();
+            let record = self.read_record_at(&keyinfo)?;
+            for index_key in self.indexes[name].index_keys(&record.value) {
+                let entry = entry_key(name, &index_key, keyinfo.timestamp, &key);
+                batch.push(entry_record(entry, self.counter + batch.len() as u64, false));
+            }
+
+            if batch.len() >= REBUILD_BATCH_SIZE {
+                self.write_records(&batch)?;
+                batch.clear();
+            }
+        }
+        self.write_records(&batch)?;
+
+        self.maybe_compact()
+    }
+
+    /// The primary keys whose values the index `name` finds under `key`, in order.
+    ///
+    /// Fails with `KvsError::UnknownIndex` if no index of that name is registered.
+    pub fn query_index(&self, name: &str, key: &str) -> Result<Vec<String>> {
+        let index = self
+            .indexes
+            .get(name)
+            .ok_or_else(|| KvsError::UnknownIndex(name.to_string()))?;
+        if key.is_empty() || key.contains(SEPARATOR) {
+            return Ok(Vec::new());
+        }
+
+        let start = format!("{}{}", key, SEPARATOR);
+        let mut primaries: Vec<String> = index
+            .entries
+            .range(start.clone()..)
+            .take_while(|entry| entry.starts_with(start.as_str()))
+            .filter_map(|entry| split_entry(entry))
+            .filter(|(_, timestamp, primary)| self.is_current(primary, *timestamp))
+            .map(|(_, _, primary)| primary.to_string())
+            .collect();
+        primaries.sort_unstable();
+        primaries.dedup();
+        Ok(primaries)
+    }
+
+    /// Unregisters the index `name` and removes its entries from the log, returning how many there
+    /// were. Indexes that are not registered can be dropped too.
+    pub fn drop_index(&mut self, name: &str) -> Result<u64> {
+        let prefix = entry_prefix(name)?;
+        self.indexes.remove(name);
+
+        let timestamp = self.counter;
+        let tombstones: Vec<Record> = self
+            .keydir
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix.as_str()))
+            .enumerate()
+            .map(|(i, (key, _))| entry_record(key.to_string(), timestamp + i as u64, true))
+            .collect();
+
+        self.write_records(&tombstones)?;
+        self.maybe_compact()?;
+        Ok(tombstones.len() as u64)
+    }
+
+    /// Adds to `records` the index entries they make and remove, numbering the records from the
+    /// next timestamp.
+    ///
+    /// The entries made by a record are written before it and point at its timestamp, and the ones
+    /// it replaces are removed after it, so the index is right wherever a crash cuts the batch off.
+    pub(crate) fn with_index_entries(&self, records: Vec<Record>) -> Vec<Record> {
+        let mut batch: Vec<Record> = Vec::with_capacity(records.len());
+        // entries made earlier in the batch, which the indexes do not know of yet
+        let mut pending: HashMap<String, Vec<String>> = HashMap::new();
+
+        for mut record in records {
+            if self.indexes.is_empty() || namespace::is_namespaced(&record.key) {
+                record.timestamp = self.counter + batch.len() as u64;
+                batch.push(record);
+                continue;
+            }
+
+            let index_keys: Vec<(&str, BTreeSet<String>)> = if record.tombstone == 1 {
+                Vec::new()
+            } else {
+                self.indexes
+                    .iter()
+                    .map(|(name, index)| (name.as_str(), index.index_keys(&record.value)))
+                    .collect()
+            };
+            let count: usize = index_keys.iter().map(|(_, keys)| keys.len()).sum();
+            record.timestamp = self.counter + (batch.len() + count) as u64;
+
+            let mut entries = Vec::with_capacity(count);
+            for (name, keys) in index_keys {
+                for index_key in keys {
+                    let entry = entry_key(name, &index_key, record.timestamp, &record.key);
+                    batch.push(entry_record(entry.clone(), self.counter + batch.len() as u64, false));
+                    entries.push(entry);
+                }
+            }
+
+            let replaced = match pending.remove(&record.key) {
+                Some(replaced) => replaced,
+                None => self.entry_keys(&record.key),
+            };
+            pending.insert(record.key.clone(), entries);
+            batch.push(record);
+            for entry in replaced {
+                batch.push(entry_record(entry, self.counter + batch.len() as u64, true));
+            }
+        }
+
+        batch
+    }
+
+    // Indexes the value `record` has just been given with `set_reader`, in a batch after it.
+    pub(crate) fn index_streamed(&mut self, record: &Record) -> Result<()> {
+        let mut batch = Vec::new();
+        for (name, index) in self.indexes.iter() {
+            for index_key in index.index_keys(&record.value) {
+                let entry = entry_key(name, &index_key, record.timestamp, &record.key);
+                batch.push(entry_record(entry, self.counter + batch.len() as u64, false));
+            }
+        }
+        for entry in self.entry_keys(&record.key) {
+            batch.push(entry_record(entry, self.counter + batch.len() as u64, true));
+        }
+
+        self.write_records(&batch)
+    }
+
+    // Indexes a record applied from a replication leader. Its entries are only kept in memory: the
+    // timestamps they would be written under are the leader's to hand out, and the keys they are
+    // missing from get their entries written once the index is registered again.
+    pub(crate) fn index_applied(&mut self, record: &Record) {
+        if self.indexes.is_empty() || namespace::is_namespaced(&record.key) || is_index_key(&record.key) {
+            return;
+        }
+
+        let mut entries: Vec<(String, bool)> = self
+            .entry_keys(&record.key)
+            .into_iter()
+            .map(|entry| (entry, true))
+            .collect();
+        if record.tombstone == 0 {
+            for (name, index) in self.indexes.iter() {
+                for index_key in index.index_keys(&record.value) {
+                    entries.push((entry_key(name, &index_key, record.timestamp, &record.key), false));
+                }
+            }
+        }
+        for (entry, removed) in entries {
+            self.track_index_entry(&entry, removed);
+        }
+    }
+
+    // Keeps the registered indexes in step with an entry just written to the log or removed from it.
+    pub(crate) fn track_index_entry(&mut self, key: &str, removed: bool) {
+        let (name, entry) = match key.strip_prefix(INDEX_PREFIX).and_then(|key| key.split_once(SEPARATOR)) {
+            Some(parts) => parts,
+            None => return,
+        };
+
+        if let Some(index) = self.indexes.get_mut(name) {
+            if removed {
+                index.remove(entry);
+            } else {
+                index.insert(entry);
+            }
+        }
+    }
+
+    // The keys in the log of every entry of `primary`, over all the registered indexes.
+    fn entry_keys(&self, primary: &str) -> Vec<String> {
+        let mut keys = Vec::new();
+        for (name, index) in self.indexes.iter() {
+            if let Some(entries) = index.by_primary.get(primary) {
+                for entry in entries {
+                    keys.push(format!("{}{}{}{}", INDEX_PREFIX, name, SEPARATOR, entry));
+                }
+            }
+        }
+        keys
+    }
+
+    fn is_current(&self, primary: &str, timestamp: u64) -> bool {
+        self.keydir
+            .get(primary)
+            .is_some_and(|keyinfo| keyinfo.timestamp == timestamp)
+    }
+}
+
+/// Whether `key` holds an index entry rather than a value.
+pub(crate) fn is_index_key(key: &str) -> bool {
+    key.starts_with(INDEX_PREFIX)
+}
+
+fn entry_prefix(name: &str) -> Result<String> {
+    if name.is_empty() || name.contains(SEPARATOR) {
+        return Err(KvsError::InvalidIndex(name.to_string()));
+    }
+
+    Ok(format!("{}{}{}", INDEX_PREFIX, name, SEPARATOR))
+}
+
+fn entry_key(name: &str, index_key: &str, timestamp: u64, primary: &str) -> String {
+    format!(
+        "{}{}{sep}{}{sep}{}{sep}{}",
+        INDEX_PREFIX,
+        name,
+        index_key,
+        timestamp,
+        primary,
+        sep = SEPARATOR
+    )
+}
+
+// Splits an entry into its index key, the timestamp of the record it was made from and its primary
+// key, which may itself contain NUL characters.
+fn split_entry(entry: &str) -> Option<(&str, u64, &str)> {
+    let mut parts = entry.splitn(3, SEPARATOR);
+    let index_key = parts.next()?;
+    let timestamp = parts.next()?.parse().ok()?;
+    let primary = parts.next()?;
+    Some((index_key, timestamp, primary))
+}
+
+fn entry_record(key: String, timestamp: u64, tombstone: bool) -> Record {
+    Record {
+        timestamp,
+        tombstone: if tombstone { 1 } else { 0 },
+        key,
+        value: String::new(),
+        value_pointer: None,
+        written_at: 0,
+        expires_at: 0,
+    }
+}
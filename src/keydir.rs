@@ -0,0 +1,128 @@
+use crate::index;
+use std::collections::hash_map::{self, HashMap};
+use std::mem;
+
+/// Segments are numbered by the sequence number in their file name.
+pub(crate) type SegmentId = u32;
+
+pub(crate) fn segment_file_name(segment_id: SegmentId) -> String {
+    format!("{:08}.bcd", segment_id)
+}
+
+/// Where the live record of a key sits in the log, and how long its value is.
+///
+/// Kept to 32 bytes, so records are limited to `u32::MAX` bytes.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct KeyInfo {
+    pub(crate) record_pos: u64,
+    pub(crate) timestamp: u64,
+    pub(crate) segment_id: SegmentId,
+    /// Includes the length prefix, so reading this many bytes from `record_pos` gets the record.
+    pub(crate) record_len: u32,
+    pub(crate) value_len: u32,
+    /// Set when the value is kept in the value log rather than in the record.
+    pub(crate) in_value_log: bool,
+}
+
+impl KeyInfo {
+    // The bytes the key takes in the segments and in the value log.
+    fn live_bytes(&self) -> u64 {
+        let value_log_bytes = if self.in_value_log { self.value_len } else { 0 };
+        u64::from(self.record_len) + u64::from(value_log_bytes)
+    }
+}
+
+/// The in-memory index from every live key to its `KeyInfo`.
+///
+/// Each key costs `ENTRY_BYTES` (49) bytes in the table, plus its own length in a separate
+/// allocation that the allocator rounds up. The table keeps up to an eighth of its slots free and
+/// doubles when full, so expect between 56 and 112 bytes per key on top of the keys themselves.
+#[derive(Debug, Default)]
+pub(crate) struct KeyDir {
+    entries: HashMap<Box<str>, KeyInfo>,
+    key_bytes: usize,
+    // index entries are left out of these two
+    record_bytes: u64,
+    index_entries: usize,
+}
+
+/// The table slot of a key: the boxed key, its `KeyInfo` and one control byte.
+pub(crate) const ENTRY_BYTES: usize = mem::size_of::<(Box<str>, KeyInfo)>() + 1;
+
+pub(crate) type Iter<'a> = hash_map::Iter<'a, Box<str>, KeyInfo>;
+
+impl KeyDir {
+    pub(crate) fn get(&self, key: &str) -> Option<&KeyInfo> {
+        self.entries.get(key)
+    }
+
+    pub(crate) fn contains_key(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Points `key` at `keyinfo`, returning what it pointed at before. The key is only copied if it
+    /// is new.
+    pub(crate) fn insert(&mut self, key: &str, keyinfo: KeyInfo) -> Option<KeyInfo> {
+        let is_entry = index::is_index_key(key);
+        if !is_entry {
+            self.record_bytes += keyinfo.live_bytes();
+        }
+        match self.entries.get_mut(key) {
+            Some(slot) => {
+                let replaced = mem::replace(slot, keyinfo);
+                if !is_entry {
+                    self.record_bytes -= replaced.live_bytes();
+                }
+                Some(replaced)
+            }
+            None => {
+                self.key_bytes += key.len();
+                self.index_entries += is_entry as usize;
+                self.entries.insert(key.into(), keyinfo);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &str) -> Option<KeyInfo> {
+        let keyinfo = self.entries.remove(key)?;
+        self.key_bytes -= key.len();
+        if index::is_index_key(key) {
+            self.index_entries -= 1;
+        } else {
+            self.record_bytes -= keyinfo.live_bytes();
+        }
+        Some(keyinfo)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.key_bytes = 0;
+        self.record_bytes = 0;
+        self.index_entries = 0;
+    }
+
+    /// Bytes taken in the log and the value log by the live record of every key, index entries
+    /// aside.
+    pub(crate) fn record_bytes(&self) -> u64 {
+        self.record_bytes
+    }
+
+    /// The number of live keys, index entries aside.
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len() - self.index_entries
+    }
+
+    pub(crate) fn iter(&self) -> Iter<'_> {
+        self.entries.iter()
+    }
+
+    /// An estimate of the heap memory held by the index, as if its table were as full as it gets
+    /// and ignoring allocator overhead.
+    ///
+    /// The real capacity of the table depends on where removed keys happened to hash to, so it is not
+    /// used here, which keeps the estimate the same for the same keys.
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.entries.len() * ENTRY_BYTES * 8 / 7 + self.key_bytes
+    }
+}
@@ -1,20 +1,72 @@
+// `failure_derive` places its generated impls inside an anonymous const.
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate failure;
 
+#[cfg(feature = "async")]
+mod async_store;
+mod cache;
+mod checkpoint;
+mod compaction;
+pub mod dump;
+mod eviction;
+mod expiry;
+mod format;
+mod history;
+mod index;
+mod key_encoding;
+mod keydir;
+mod migrate;
+mod namespace;
+mod options;
+mod rate_limiter;
 mod reader;
 mod record;
+pub mod replication;
+mod stats;
+mod table;
+mod transfer;
+mod value_log;
+mod value_reader;
+pub mod verify;
+mod watch;
 mod writer;
 
-use crate::record::Record;
-use byteorder::{BigEndian, WriteBytesExt};
-use rmp_serde::{Deserializer, Serializer};
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "async")]
+pub use crate::async_store::{AsyncKvStore, KvsFuture};
+pub use crate::checkpoint::RestorePoint;
+pub use crate::compaction::{CompactOptions, CompactProgress, CompactionPolicy, GarbageRatioPolicy};
+pub use crate::eviction::EvictionPolicy;
+pub use crate::format::{SegmentHeader, FORMAT_VERSION, MIN_READABLE_VERSION};
+pub use crate::history::{Retention, Version};
+pub use crate::namespace::{Namespace, NamespaceStats};
+pub use crate::options::Options;
+pub use crate::rate_limiter::RateLimiter;
+pub use crate::stats::{CompactionStats, SegmentStats, Stats};
+pub use crate::table::{Codec, Table};
+pub use crate::transfer::{ExportEncoding, ExportFormat};
+pub use crate::value_reader::ValueReader;
+pub use crate::watch::{Tail, WatchEvent};
+
+use crate::cache::ValueCache;
+use crate::compaction::CompactionJob;
+use crate::eviction::EvictionQueue;
+use crate::expiry::Expiries;
+use crate::history::{History, PastVersion};
+use crate::index::SecondaryIndex;
+use crate::keydir::{segment_file_name, KeyDir, KeyInfo, SegmentId};
+use crate::record::{Record, RecordRef, ValuePointer};
+use crate::stats::SegmentUsage;
+use crate::value_log::ValueLog;
+use memmap2::Mmap;
 use std::collections::hash_map::HashMap;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 use std::result;
+use std::sync::mpsc;
 
 pub type Result<T> = result::Result<T, KvsError>;
 
@@ -24,6 +76,37 @@ pub enum KvsError {
     Io(#[cause] io::Error),
     #[fail(display = "Key not found")]
     KeyNotFound,
+    #[fail(display = "replication protocol error: {}", _0)]
+    Protocol(String),
+    #[fail(display = "changes up to timestamp {} have been compacted away", _0)]
+    Compacted(u64),
+    #[fail(display = "invalid checkpoint: {}", _0)]
+    InvalidCheckpoint(String),
+    #[fail(display = "invalid import data: {}", _0)]
+    InvalidImport(String),
+    #[fail(display = "no segment named {}", _0)]
+    UnknownSegment(String),
+    #[fail(display = "record of {} bytes is too large", _0)]
+    RecordTooLarge(u64),
+    #[fail(
+        display = "segment {} has unsupported format version {}; older stores can be upgraded with `kvs migrate`",
+        file_name, version
+    )]
+    UnsupportedFormat { file_name: String, version: u16 },
+    #[fail(display = "invalid key {:?}: keys starting with a NUL character are reserved", _0)]
+    InvalidKey(String),
+    #[fail(display = "invalid namespace name {:?}", _0)]
+    InvalidNamespace(String),
+    #[fail(display = "invalid index name {:?}", _0)]
+    InvalidIndex(String),
+    #[fail(display = "no index named {:?} is registered", _0)]
+    UnknownIndex(String),
+    #[fail(display = "cannot encode: {}", _0)]
+    Encode(String),
+    #[fail(display = "cannot decode: {}", _0)]
+    Decode(String),
+    #[fail(display = "store worker has stopped")]
+    WorkerStopped,
     #[fail(display = "unknown error")]
     Unknown,
 }
@@ -34,33 +117,55 @@ impl From<io::Error> for KvsError {
     }
 }
 
-type KeyDir = HashMap<Key, KeyInfo>;
-type Key = String;
-
-#[derive(Debug)]
-struct KeyInfo {
-    file_id: String,
-    record_pos: u64,
-    timestamp: u64,
-}
+// Segments are sealed once they grow past this many bytes.
+const SEGMENT_SIZE_LIMIT: u64 = 1000;
+const HORIZON_FILE_NAME: &str = "HORIZON";
 
 pub struct KvStore {
     counter: u64,
     keydir: KeyDir,
-    file_handles: HashMap<String, fs::File>,
-    file_names: Vec<String>,
+    file_handles: HashMap<SegmentId, fs::File>,
+    // format version of each segment; only the ones in an older format are read differently
+    segment_versions: HashMap<SegmentId, u16>,
+    segments: Vec<SegmentId>,
     path: PathBuf,
-    largest_segment_seq: u64,
+    largest_segment_seq: SegmentId,
+    horizon: u64,
+    watchers: Vec<(String, mpsc::Sender<WatchEvent>)>,
+    replicas: Vec<mpsc::SyncSender<Record>>,
+    // bumped whenever records move within the log or leave it, invalidating their positions
+    rewrites: u64,
+    segment_usage: HashMap<SegmentId, SegmentUsage>,
+    last_compaction: Option<CompactionStats>,
+    compaction_policy: Box<dyn CompactionPolicy>,
+    compaction_rate_limiter: RateLimiter,
+    // the compaction the policy started, while writes carry it on
+    compaction_job: Option<CompactionJob>,
+    options: Options,
+    maps: HashMap<SegmentId, Mmap>,
+    cache: Option<ValueCache>,
+    value_log: ValueLog,
+    indexes: HashMap<String, SecondaryIndex>,
+    history: History,
+    expiries: Expiries,
+    // only kept when the store has a size limit
+    eviction_queue: Option<EvictionQueue>,
+    evictions: u64,
 }
 
 impl KvStore {
     pub fn open(path: &Path) -> Result<Self> {
+        KvStore::open_with_options(path, Options::default())
+    }
+
+    pub fn open_with_options(path: &Path, options: Options) -> Result<Self> {
         if !path.exists() {
             fs::create_dir(path)?;
         }
 
-        let mut list_of_files: Vec<(String, fs::File)> = Vec::new();
-        let mut largest_segment_seq: u64 = 0;
+        let value_log = ValueLog::open(path)?;
+        let mut list_of_files: Vec<(SegmentId, fs::File, u16)> = Vec::new();
+        let mut largest_segment_seq: SegmentId = 0;
         for entry in fs::read_dir(path)? {
             let entry = entry?;
 
@@ -70,317 +175,1026 @@ impl KvStore {
                 let file_path = path.join(&file_name);
                 let file_path = file_path.as_path();
 
-                let segment_seq: u64 = file_path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+                let segment_seq: SegmentId = file_path.file_stem().unwrap().to_str().unwrap().parse().unwrap();
                 largest_segment_seq = std::cmp::max(largest_segment_seq, segment_seq);
 
-                let f = fs::OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .append(true)
-                    .open(&file_path)?;
+                let mut f = fs::OpenOptions::new().read(true).append(true).open(file_path)?;
+                let version = check_segment_format(&file_name, &mut f)?;
 
-                list_of_files.push((file_name, f));
+                list_of_files.push((segment_seq, f, version));
             }
         }
 
-        list_of_files.sort_by(|f1, f2| f1.0.cmp(&f2.0));
+        list_of_files.sort_by_key(|(segment_id, _, _)| *segment_id);
 
-        let mut keydir: KeyDir = HashMap::new();
+        let mut keydir = KeyDir::default();
         let mut file_handles = HashMap::new();
-        let mut file_names = Vec::new();
+        let mut segment_versions = HashMap::new();
+        let mut segments = Vec::new();
         let mut largest_timestamp: u64 = 0;
+        let mut timestamps: Vec<u64> = Vec::new();
+        let mut segment_usage: HashMap<SegmentId, SegmentUsage> = HashMap::new();
+        let mut deleted: HashMap<String, u64> = HashMap::new();
+        // every record read, to find the past versions of each key in once the live ones are known
+        let mut records: Vec<(String, PastVersion)> = Vec::new();
+        // every record with a TTL, which only applies if it is the live one
+        let mut expiring: Vec<(String, u64, u64)> = Vec::new();
 
         if list_of_files.is_empty() {
-            let file_name = format!("{:08}.bcd", 0);
-            let file_path = path.join(&file_name);
-            let file_path = file_path.as_path();
-
-            let f = fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(&file_path)?;
-            file_names.push(file_name.clone());
-            file_handles.insert(file_name, f);
+            let f = create_segment_file(&path.join(segment_file_name(0)))?;
+            segments.push(0);
+            segment_usage.insert(0, SegmentUsage::default());
+            segment_versions.insert(0, FORMAT_VERSION);
+            file_handles.insert(0, f);
         } else {
             //restore the keydir
-            for (file_name, file_to_read) in list_of_files.into_iter() {
+            let active = list_of_files.len() - 1;
+            for (i, (segment_id, mut file_to_read, version)) in list_of_files.into_iter().enumerate() {
+                file_to_read.seek(io::SeekFrom::Start(SegmentHeader::LEN))?;
                 let buf_reader = io::BufReader::with_capacity(1024, &file_to_read);
-                let mut reader = reader::Reader::new(buf_reader);
-                let mut record = Record::new();
+                let mut reader = reader::Reader::new(buf_reader, version);
 
-                let mut curr_offset = 0;
+                let mut curr_offset = SegmentHeader::LEN;
                 let mut next_offset = 0;
-                while reader.read_record(io::SeekFrom::Current(0), &mut record, &mut next_offset)? != false {
+                segment_usage.insert(segment_id, SegmentUsage::default());
+                loop {
+                    let record = match reader.read_record_ref(io::SeekFrom::Current(0), &mut next_offset) {
+                        Ok(Some(record)) => record,
+                        Ok(None) => break,
+                        // A value streamed to the segment is checksummed once all of it is written, so a
+                        // crash can leave the last record whole but for its checksum. It was never
+                        // acknowledged, so it is cut off.
+                        Err(ref e)
+                            if e.kind() == io::ErrorKind::InvalidData
+                                && i == active
+                                && version >= 2
+                                && ends_file(&file_to_read, curr_offset)? =>
+                        {
+                            file_to_read.set_len(curr_offset)?;
+                            break;
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
                     largest_timestamp = std::cmp::max(largest_timestamp, record.timestamp);
-
+                    timestamps.push(record.timestamp);
+
+                    let record_len = next_offset - curr_offset;
+                    if record.value_pointer.is_some_and(|pointer| !value_log.contains(pointer)) {
+                        // garbage collection deleted the value, after moving it if it was live or retained
+                        let usage = segment_usage.get_mut(&segment_id).unwrap();
+                        usage.add(record, record_len);
+                        usage.live_bytes -= record_len;
+                        curr_offset = next_offset;
+                        continue;
+                    }
                     let keyinfo = KeyInfo {
-                        file_id: file_name.clone(),
+                        segment_id,
                         record_pos: curr_offset,
+                        record_len: record_len as u32,
+                        value_len: record.value_len() as u32,
                         timestamp: record.timestamp,
+                        in_value_log: record.value_pointer.is_some(),
                     };
 
-                    if record.tombstone == 1 {
-                        keydir.remove(&record.key);
+                    if options.retention != Retention::Latest && !index::is_index_key(record.key) {
+                        let version = PastVersion {
+                            keyinfo,
+                            tombstone: record.tombstone == 1,
+                        };
+                        records.push((record.key.to_string(), version));
+                    }
+
+                    if record.expires_at != 0 {
+                        expiring.push((record.key.to_string(), record.timestamp, record.expires_at));
+                    }
+
+                    let usage = segment_usage.get_mut(&segment_id).unwrap();
+                    usage.add(record, record_len);
+
+                    // Compaction moves records into the files of older segments, so a key's latest
+                    // record is the one with the highest timestamp rather than the last one read.
+                    let newest = match keydir.get(record.key) {
+                        Some(keyinfo) => Some(keyinfo.timestamp),
+                        None => deleted.get(record.key).copied(),
+                    };
+                    if newest.is_some_and(|newest| newest > record.timestamp) {
+                        if record.tombstone == 0 {
+                            usage.live_bytes -= record_len;
+                        }
                     } else {
-                        keydir.insert(record.key.clone(), keyinfo);
+                        let replaced = if record.tombstone == 1 {
+                            deleted.insert(record.key.to_string(), record.timestamp);
+                            keydir.remove(record.key)
+                        } else {
+                            deleted.remove(record.key);
+                            keydir.insert(record.key, keyinfo)
+                        };
+                        if let Some(replaced) = replaced {
+                            segment_usage.get_mut(&replaced.segment_id).unwrap().live_bytes -=
+                                u64::from(replaced.record_len);
+                        }
                     }
 
                     curr_offset = next_offset;
                 }
 
-                file_names.push(file_name.clone());
-                file_handles.insert(file_name, file_to_read);
+                segments.push(segment_id);
+                segment_versions.insert(segment_id, version);
+                file_handles.insert(segment_id, file_to_read);
             }
         }
 
-        let store = KvStore {
-            counter: largest_timestamp + 1,
-            keydir: keydir,
-            file_handles: file_handles,
-            file_names: file_names,
+        let horizon = match read_horizon(path)? {
+            Some(horizon) => horizon,
+            None => {
+                // Stores from before the horizon was saved: timestamps are handed out consecutively, so
+                // the last one missing from the log marks at most how far compaction has discarded
+                // history.
+                timestamps.sort_unstable();
+                let mut horizon = 0;
+                let mut expected = 1;
+                for timestamp in timestamps {
+                    if timestamp > expected {
+                        horizon = timestamp - 1;
+                    }
+                    expected = timestamp + 1;
+                }
+                write_horizon(path, horizon)?;
+                horizon
+            }
+        };
+
+        let mut expiries = Expiries::default();
+        for (key, timestamp, expires_at) in expiring {
+            if keydir.get(&key).map(|keyinfo| keyinfo.timestamp) == Some(timestamp) {
+                expiries.set(&key, expires_at);
+            }
+        }
+        // keys are taken to have been used in the order they were last written
+        let eviction_queue = options.max_live_bytes.map(|_| {
+            let mut keys: Vec<(&str, u64)> = keydir
+                .iter()
+                .map(|(key, keyinfo)| (&key[..], keyinfo.timestamp))
+                .collect();
+            keys.sort_unstable_by_key(|&(_, timestamp)| timestamp);
+            let mut queue = EvictionQueue::new(options.eviction);
+            for (key, _) in keys {
+                queue.written(key);
+            }
+            queue
+        });
+
+        let mut history = History::default();
+        for (key, version) in records {
+            if keydir.get(&key).map(|keyinfo| keyinfo.timestamp) != Some(version.keyinfo.timestamp) {
+                history.push(&key, version);
+            }
+        }
+        // the versions the retention keeps count as live
+        for (key, versions) in history.iter() {
+            let live = keydir.get(key).map(|keyinfo| keyinfo.timestamp);
+            for version in versions {
+                if history.retains(options.retention, key, version.keyinfo.timestamp, live) {
+                    segment_usage.get_mut(&version.keyinfo.segment_id).unwrap().live_bytes +=
+                        u64::from(version.keyinfo.record_len);
+                }
+            }
+        }
+
+        let mut store = KvStore {
+            // a cleared store has a horizon past every record left
+            counter: std::cmp::max(largest_timestamp, horizon) + 1,
+            keydir,
+            file_handles,
+            segment_versions,
+            segments,
             path: PathBuf::from(path),
-            largest_segment_seq: largest_segment_seq,
+            largest_segment_seq,
+            horizon,
+            watchers: Vec::new(),
+            replicas: Vec::new(),
+            rewrites: 0,
+            segment_usage,
+            last_compaction: None,
+            compaction_policy: Box::new(GarbageRatioPolicy::default()),
+            compaction_rate_limiter: RateLimiter::unlimited(),
+            compaction_job: None,
+            cache: if options.cache_capacity > 0 {
+                Some(ValueCache::new(options.cache_capacity))
+            } else {
+                None
+            },
+            options,
+            maps: HashMap::new(),
+            value_log,
+            indexes: HashMap::new(),
+            history,
+            expiries,
+            eviction_queue,
+            evictions: 0,
         };
 
+        // records are only appended in the current format
+        let active = *store.segments.last().unwrap();
+        if store.segment_versions[&active] != FORMAT_VERSION {
+            store.create_segment()?;
+        }
+
+        let sealed = store.segments[..store.segments.len() - 1].to_vec();
+        for segment_id in sealed {
+            store.map_segment(segment_id)?;
+        }
+
         Ok(store)
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(keyinfo) = self.keydir.get(&key) {
-            let buf_reader = io::BufReader::with_capacity(1024, self.file_handles.get(&keyinfo.file_id).unwrap());
-            let mut reader = reader::Reader::new(buf_reader);
-            let mut record = Record::new();
-            let mut next_offset = 0;
+        let keyinfo = match self.keydir.get(&key) {
+            Some(_) if self.expiries.is_expired(&key) => return Ok(None),
+            Some(keyinfo) => keyinfo,
+            None => return Ok(None),
+        };
+        if let Some(queue) = self.eviction_queue.as_mut() {
+            queue.read(&key);
+        }
 
-            reader.read_record(io::SeekFrom::Start(keyinfo.record_pos), &mut record, &mut next_offset);
+        if let Some(cache) = self.cache.as_mut() {
+            if let Some(value) = cache.get(keyinfo.segment_id, keyinfo.record_pos) {
+                return Ok(Some(value));
+            }
+        }
 
-            Ok(Some(record.value.clone()))
-        } else {
-            Ok(None)
+        let record = self.read_record_at(keyinfo)?;
+        if let Some(cache) = self.cache.as_mut() {
+            cache.insert(keyinfo.segment_id, keyinfo.record_pos, record.value.clone());
         }
+        Ok(Some(record.value))
     }
 
-    fn should_write_to_new_file(&self, file_name: &String) -> io::Result<bool> {
-        let f = self.file_handles.get(file_name).unwrap();
+    /// Whether `key` has a value, answered from memory.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.keydir.contains_key(key) && !self.expiries.is_expired(key)
+    }
+
+    /// The length in bytes of the value of `key`, answered from memory.
+    pub fn get_value_len(&self, key: &str) -> Option<u64> {
+        if self.expiries.is_expired(key) {
+            return None;
+        }
+        self.keydir.get(key).map(|keyinfo| u64::from(keyinfo.value_len))
+    }
+
+    /// Streams the value of `key` from disk, so it never has to be held in memory whole.
+    ///
+    /// The reader keeps its own handle to the segment and reads the value as it was when this was
+    /// called, even if the key is changed or compacted in the meantime.
+    pub fn get_reader(&self, key: &str) -> Result<Option<ValueReader>> {
+        let keyinfo = match self.keydir.get(key) {
+            Some(_) if self.expiries.is_expired(key) => return Ok(None),
+            Some(keyinfo) => keyinfo,
+            None => return Ok(None),
+        };
+
+        if self.segment_versions[&keyinfo.segment_id] < 2 {
+            let record = self.read_record_at(keyinfo)?;
+            return Ok(Some(ValueReader::from_value(record.value)));
+        }
+
+        let file = &self.file_handles[&keyinfo.segment_id];
+        let mut header = [0; record::RECORD_HEADER_LEN];
+        reader::read_exact_at(file, &mut header, keyinfo.record_pos)?;
+        if record::has_value_pointer(&header) {
+            // a record pointing into the value log is small enough to read whole
+            let mut buf = vec![0; keyinfo.record_len as usize];
+            reader::read_exact_at(file, &mut buf, keyinfo.record_pos)?;
+            let pointer = record::decode(&buf)?.value_pointer.unwrap();
+            return Ok(Some(self.value_log.reader(key, pointer)?));
+        }
+
+        Ok(Some(ValueReader::open(
+            file.try_clone()?,
+            keyinfo.record_pos,
+            key.len(),
+        )?))
+    }
+
+    fn should_write_to_new_file(&self, segment_id: SegmentId) -> io::Result<bool> {
+        let f = self.file_handles.get(&segment_id).unwrap();
         let metadata = f.metadata()?;
-        Ok(metadata.len() > 1000)
+        Ok(metadata.len() > SEGMENT_SIZE_LIMIT)
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let (mut file_id, mut file_to_write) = if self.should_write_to_new_file(self.file_names.last().unwrap())? {
-            self.largest_segment_seq += 1;
-            let file_name = format!("{:08}.bcd", self.largest_segment_seq);
-            let file_path = self.path.join(&file_name);
-            let file_path = file_path.as_path();
-
-            let f = fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(&file_path)?;
-            self.file_handles.insert(file_name.clone(), f);
-            self.file_names.push(file_name.clone());
-            (file_name.clone(), self.file_handles.get(&file_name).unwrap())
+    fn active_file(&mut self) -> Result<SegmentId> {
+        let segment_id = *self.segments.last().unwrap();
+        if self.should_write_to_new_file(segment_id)? {
+            self.create_segment()?;
+            self.map_segment(segment_id)?;
+        }
+
+        Ok(*self.segments.last().unwrap())
+    }
+
+    // Maps a sealed segment if the store reads through memory maps.
+    fn map_segment(&mut self, segment_id: SegmentId) -> Result<()> {
+        if self.options.mmap {
+            // Safety: sealed segments are never written to again; compaction replaces them with new
+            // files instead of modifying them in place.
+            let map = unsafe { Mmap::map(&self.file_handles[&segment_id])? };
+            self.maps.insert(segment_id, map);
+        }
+
+        Ok(())
+    }
+
+    // Starts a new, empty segment after the existing ones.
+    fn create_segment(&mut self) -> Result<()> {
+        self.largest_segment_seq += 1;
+        let segment_id = self.largest_segment_seq;
+        let f = create_segment_file(&self.path.join(segment_file_name(segment_id)))?;
+
+        self.segments.push(segment_id);
+        self.segment_usage.insert(segment_id, SegmentUsage::default());
+        self.segment_versions.insert(segment_id, FORMAT_VERSION);
+        self.file_handles.insert(segment_id, f);
+
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &Record) -> Result<()> {
+        check_record_size(record)?;
+        let mut record_ref = record.as_record_ref();
+        record_ref.value_pointer = self.move_to_value_log(record)?;
+        // replicated and imported records are stamped with the time they reach this store
+        if record_ref.written_at == 0 {
+            record_ref.written_at = record::wall_clock();
+        }
+
+        let segment_id = self.active_file()?;
+        let mut file_to_write = self.file_handles.get(&segment_id).unwrap();
+        let file_offset = file_to_write.seek(io::SeekFrom::End(0))?;
+
+        let mut writer = writer::Writer::new(file_to_write);
+        let record_len = writer.write_record_ref(record_ref)?;
+
+        let in_value_log = record_ref.value_pointer.is_some();
+        self.index_record(record, in_value_log, segment_id, file_offset, record_len);
+
+        Ok(())
+    }
+
+    // Writes `record` together with the index entries it makes and removes.
+    fn write_indexed(&mut self, record: Record) -> Result<()> {
+        if self.indexes.is_empty() {
+            return self.write_record(&record);
+        }
+
+        let records = self.with_index_entries(vec![record]);
+        self.write_records(&records)
+    }
+
+    /// Appends `records` in order, buffering the writes to each segment.
+    fn write_records(&mut self, records: &[Record]) -> Result<()> {
+        let mut value_pointers = Vec::with_capacity(records.len());
+        for record in records {
+            check_record_size(record)?;
+            value_pointers.push(self.move_to_value_log(record)?);
+        }
+        let mut records = records.iter().zip(value_pointers).peekable();
+
+        while records.peek().is_some() {
+            let segment_id = self.active_file()?;
+            let mut written = Vec::new();
+            {
+                let mut file_to_write = self.file_handles.get(&segment_id).unwrap();
+                let mut file_offset = file_to_write.seek(io::SeekFrom::End(0))?;
+
+                let mut writer = writer::Writer::new(io::BufWriter::new(file_to_write));
+                while file_offset <= SEGMENT_SIZE_LIMIT {
+                    let (record, value_pointer) = match records.next() {
+                        Some(record) => record,
+                        None => break,
+                    };
+                    let mut record_ref = record.as_record_ref();
+                    record_ref.value_pointer = value_pointer;
+                    if record_ref.written_at == 0 {
+                        record_ref.written_at = record::wall_clock();
+                    }
+                    let record_len = writer.write_record_ref(record_ref)?;
+                    written.push((record, value_pointer.is_some(), file_offset, record_len));
+                    file_offset += record_len;
+                }
+                writer.flush()?;
+            }
+
+            for (record, in_value_log, record_pos, record_len) in written {
+                self.index_record(record, in_value_log, segment_id, record_pos, record_len);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes the value of `record` to the value log if it is large enough to go there.
+    fn move_to_value_log(&mut self, record: &Record) -> Result<Option<ValuePointer>> {
+        if record.tombstone == 1 || !self.goes_to_value_log(record.value.len() as u64) {
+            return Ok(None);
+        }
+
+        let value_len = record.value.len() as u32;
+        let pointer = self
+            .value_log
+            .append(record.timestamp, &record.key, &mut record.value.as_bytes(), value_len)?;
+        Ok(Some(pointer))
+    }
+
+    fn goes_to_value_log(&self, value_len: u64) -> bool {
+        self.options
+            .value_log_threshold
+            .is_some_and(|threshold| value_len > threshold as u64)
+    }
+
+    // Streams `value` into the value log and writes `record` to the segments pointing at it, without
+    // notifying watchers.
+    fn write_to_value_log(&mut self, record: RecordRef, value: &mut dyn Read, value_len: u32) -> Result<KeyInfo> {
+        let keyinfo = self.append_to_value_log(record, value, value_len)?;
+        self.index_keyinfo(record, keyinfo);
+        Ok(keyinfo)
+    }
+
+    // Streams `value` into the value log and writes `record` to the segments pointing at it, leaving
+    // the keydir as it is.
+    fn append_to_value_log(&mut self, record: RecordRef, value: &mut dyn Read, value_len: u32) -> Result<KeyInfo> {
+        let pointer = self.value_log.append(record.timestamp, record.key, value, value_len)?;
+        let record = RecordRef {
+            value_pointer: Some(pointer),
+            ..record
+        };
+
+        let segment_id = self.active_file()?;
+        let mut file_to_write = self.file_handles.get(&segment_id).unwrap();
+        let record_pos = file_to_write.seek(io::SeekFrom::End(0))?;
+        let record_len = writer::Writer::new(file_to_write).write_record_ref(record)?;
+
+        Ok(KeyInfo {
+            segment_id,
+            record_pos,
+            record_len: record_len as u32,
+            value_len,
+            timestamp: record.timestamp,
+            in_value_log: true,
+        })
+    }
+
+    // Flushes the segment being written to down to disk.
+    fn sync_active_segment(&self) -> io::Result<()> {
+        self.file_handles[self.segments.last().unwrap()].sync_data()
+    }
+
+    fn index_record(
+        &mut self,
+        record: &Record,
+        in_value_log: bool,
+        segment_id: SegmentId,
+        record_pos: u64,
+        record_len: u64,
+    ) {
+        let keyinfo = KeyInfo {
+            segment_id,
+            record_pos,
+            record_len: record_len as u32,
+            value_len: record.value.len() as u32,
+            timestamp: record.timestamp,
+            in_value_log,
+        };
+        self.index_keyinfo(record.as_record_ref(), keyinfo);
+        self.notify_watchers(record);
+    }
+
+    // Points the keydir at a record just written, given as `keyinfo`; the value of `record` is not
+    // looked at.
+    fn index_keyinfo(&mut self, record: RecordRef, keyinfo: KeyInfo) {
+        self.segment_usage
+            .entry(keyinfo.segment_id)
+            .or_default()
+            .add(record, u64::from(keyinfo.record_len));
+
+        let replaced = if record.tombstone == 1 {
+            self.keydir.remove(record.key)
         } else {
-            (
-                self.file_names.last().unwrap().clone(),
-                self.file_handles.get(self.file_names.last().unwrap()).unwrap(),
-            )
+            self.keydir.insert(record.key, keyinfo)
         };
+        if let Some(replaced) = replaced {
+            if let Some(usage) = self.segment_usage.get_mut(&replaced.segment_id) {
+                usage.live_bytes -= u64::from(replaced.record_len);
+            }
+        }
 
-        let file_offset = file_to_write.seek(io::SeekFrom::End(0))?;
+        // garbage collection moving a value rewrites its record as it was, which is not a new version
+        let moved = replaced.is_some_and(|replaced| replaced.timestamp == record.timestamp);
+        if !moved {
+            self.track_version(record, keyinfo, replaced);
+            if let Some(queue) = self.eviction_queue.as_mut() {
+                if record.tombstone == 1 {
+                    queue.removed(record.key);
+                } else {
+                    queue.written(record.key);
+                }
+            }
+        }
+        self.expiries.set(record.key, record.expires_at);
+
+        if index::is_index_key(record.key) {
+            self.track_index_entry(record.key, record.tombstone == 1);
+        }
+
+        self.counter = std::cmp::max(self.counter, record.timestamp + 1);
+    }
+
+    fn notify_watchers(&mut self, record: &Record) {
+        if index::is_index_key(&record.key) {
+            return;
+        }
+        self.notify_replicas(record);
+        self.watchers.retain(|(prefix, watcher)| {
+            !record.key.starts_with(prefix.as_str()) || watcher.send(WatchEvent::from(record.clone())).is_ok()
+        });
+    }
+
+    fn maybe_compact(&mut self) -> Result<()> {
+        self.evict()?;
+        self.compact_by_policy()
+    }
 
+    // Compacts the segments the compaction policy selects, if any.
+    /// Replaces the policy deciding which segments get compacted, `GarbageRatioPolicy` by default.
+    pub fn set_compaction_policy<P: CompactionPolicy + 'static>(&mut self, policy: P) {
+        self.compaction_policy = Box::new(policy);
+    }
+
+    /// The limiter `compact` draws its reads and writes from, unlimited by default.
+    ///
+    /// Compactions the policy starts are limited too: each write only carries them on as far as the
+    /// rate allows right away. Keep a clone to change the rate later, including from another thread
+    /// while a compaction runs.
+    pub fn compaction_rate_limiter(&self) -> RateLimiter {
+        self.compaction_rate_limiter.clone()
+    }
+
+    pub fn set_compaction_rate_limiter(&mut self, rate_limiter: RateLimiter) {
+        self.compaction_rate_limiter = rate_limiter;
+    }
+
+    /// Sets `key` to `value`.
+    ///
+    /// Keys starting with a NUL character are rejected, as those are kept for namespaces and indexes.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        namespace::check_key(&key)?;
+        self.set_unchecked(key, value)
+    }
+
+    // Like `set`, but also takes the keys of namespaces.
+    pub(crate) fn set_unchecked(&mut self, key: String, value: String) -> Result<()> {
         let new_record = Record {
             timestamp: self.counter,
             tombstone: 0,
-            key: key.clone(),
-            value: value,
+            key,
+            value,
+            value_pointer: None,
+            written_at: record::wall_clock(),
+            expires_at: 0,
         };
 
-        let mut writer = writer::Writer::new(file_to_write);
-        writer.write_record(&new_record);
-        let keyinfo = KeyInfo {
-            file_id: file_id.clone(),
-            record_pos: file_offset,
-            timestamp: self.counter,
-        };
+        self.write_indexed(new_record)?;
+        self.maybe_compact()
+    }
 
-        self.keydir.insert(key, keyinfo);
+    /// Sets `key` to the `len` bytes read from `value`, streaming them to disk instead of holding
+    /// them in memory.
+    ///
+    /// The value must be UTF-8 like any other. If `value` fails, runs out early or is not UTF-8,
+    /// nothing is stored and the partly written record is cut off the log.
+    pub fn set_reader<R: Read>(&mut self, key: String, value: R, len: u64) -> Result<()> {
+        namespace::check_key(&key)?;
+        self.set_reader_unchecked(key, value, len)
+    }
 
-        self.counter += 1;
+    // Like `set_reader`, but also takes the keys of namespaces.
+    pub(crate) fn set_reader_unchecked<R: Read>(&mut self, key: String, mut value: R, len: u64) -> Result<()> {
+        let payload = key.len() as u64 + len;
+        if payload > u64::from(u32::MAX) - 64 {
+            return Err(KvsError::RecordTooLarge(payload));
+        }
+
+        let keyinfo = if self.goes_to_value_log(len) {
+            let record = RecordRef {
+                timestamp: self.counter,
+                tombstone: 0,
+                key: &key,
+                value: "",
+                value_pointer: None,
+                written_at: record::wall_clock(),
+                expires_at: 0,
+            };
+            self.write_to_value_log(record, &mut value, len as u32)?
+        } else {
+            let segment_id = self.active_file()?;
+            let file_path = self.path.join(segment_file_name(segment_id));
+            let timestamp = self.counter;
+            let written_at = record::wall_clock();
+            let (record_pos, record_len) = writer::stream_record(
+                &self.file_handles[&segment_id],
+                &file_path,
+                timestamp,
+                &key,
+                &mut value,
+                len as u32,
+                written_at,
+            )?;
+
+            let keyinfo = KeyInfo {
+                segment_id,
+                record_pos,
+                record_len: record_len as u32,
+                value_len: len as u32,
+                timestamp,
+                in_value_log: false,
+            };
+            // the value is not in memory, so only the key goes into the accounting
+            let record = RecordRef {
+                timestamp,
+                tombstone: 0,
+                key: &key,
+                value: "",
+                value_pointer: None,
+                written_at,
+                expires_at: 0,
+            };
+            self.index_keyinfo(record, keyinfo);
+            keyinfo
+        };
 
-        if self.file_names.len() > 6 {
-            let range: Vec<usize> = (0..self.file_names.len()).collect();
-            self.compaction(&range)?;
+        let indexed = !self.indexes.is_empty() && !namespace::is_namespaced(&key);
+        let watched = self.watchers.iter().any(|(prefix, _)| key.starts_with(prefix.as_str()));
+        if indexed || watched || !self.replicas.is_empty() {
+            let record = self.read_record_at(&keyinfo)?;
+            if indexed {
+                self.index_streamed(&record)?;
+            }
+            self.notify_watchers(&record);
         }
 
-        Ok(())
+        self.maybe_compact()
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
-        if !self.keydir.contains_key(&key) {
+        namespace::check_key(&key)?;
+        self.remove_unchecked(key)
+    }
+
+    // Like `remove`, but also takes the keys of namespaces.
+    pub(crate) fn remove_unchecked(&mut self, key: String) -> Result<()> {
+        if !self.contains_key(&key) {
             return Err(KvsError::KeyNotFound);
         }
 
-        let (_, mut file_to_write) = if self.should_write_to_new_file(self.file_names.last().unwrap())? {
-            let file_name = format!("{:08}.bcd", self.largest_segment_seq + 1);
-            let file_path = self.path.join(&file_name);
-            let file_path = file_path.as_path();
-
-            let f = fs::OpenOptions::new()
-                .read(true)
-                .write(true)
-                .append(true)
-                .create(true)
-                .open(&file_path)?;
-            self.file_handles.insert(file_name.clone(), f);
-            self.file_names.push(file_name.clone());
-            (file_name.clone(), self.file_handles.get(&file_name).unwrap())
-        } else {
-            (
-                self.file_names.last().unwrap().clone(),
-                self.file_handles.get(self.file_names.last().unwrap()).unwrap(),
-            )
-        };
-
-        let file_offset = file_to_write.seek(io::SeekFrom::End(0))?;
+        self.write_tombstone(key)?;
+        self.maybe_compact()
+    }
 
+    // Removes `key`, without checking that it exists.
+    fn write_tombstone(&mut self, key: String) -> Result<()> {
         let new_record = Record {
             timestamp: self.counter,
             tombstone: 1,
-            key: key.clone(),
+            key,
             value: "".to_string(),
+            value_pointer: None,
+            written_at: record::wall_clock(),
+            expires_at: 0,
         };
 
-        let mut writer = writer::Writer::new(file_to_write);
-        writer.write_record(&new_record);
+        self.write_indexed(new_record)
+    }
 
-        self.keydir.remove(&key);
+    /// Appends a record received from a replication leader, keeping the leader's timestamp.
+    pub(crate) fn apply_record(&mut self, record: &Record) -> Result<()> {
+        self.write_record(record)?;
+        self.index_applied(record);
+        // the leader replicates the tombstones of the keys it evicts, and evicting others here would
+        // leave the follower with fewer keys than the leader
+        self.compact_by_policy()
+    }
 
-        self.counter += 1;
-        Ok(())
+    /// Timestamp of the most recent change made to the store.
+    pub fn last_timestamp(&self) -> u64 {
+        self.counter - 1
     }
 
-    fn compaction(&mut self, to_be_compacted: &[usize]) -> Result<()> {
-        let mut list_of_merged_path: Vec<PathBuf> = Vec::new();
-        let mut list_of_merge_file_names: Vec<String> = Vec::new();
+    /// Records with a timestamp at or below the horizon may have been compacted away, so history
+    /// is only complete for records after it.
+    pub(crate) fn horizon(&self) -> u64 {
+        self.horizon
+    }
 
-        for &i in to_be_compacted.iter() {
-            let file_name = &self.file_names[i];
-            let file_path = self.path.join(file_name);
-            let file_path = file_path.as_path();
+    /// Returns a channel receiving every change made from now on to keys starting with `prefix`.
+    ///
+    /// The watch ends when the receiver is dropped.
+    pub fn watch(&mut self, prefix: &str) -> mpsc::Receiver<WatchEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.watchers.push((prefix.to_string(), tx));
+        rx
+    }
 
-            let mut segment_seq_str: String = file_path.file_stem().unwrap().to_str().unwrap().to_string();
-            segment_seq_str.push('1');
-            segment_seq_str.push_str(".merge");
-            let merged_file_path: PathBuf = self.path.join(&segment_seq_str);
-            list_of_merged_path.push(merged_file_path);
-            list_of_merge_file_names.push(segment_seq_str);
+    /// Replays the changes recorded in the log after `timestamp`, oldest first.
+    ///
+    /// Fails with `KvsError::Compacted` if compaction has already discarded some of them.
+    pub fn changes_since(&self, timestamp: u64) -> Result<Vec<WatchEvent>> {
+        if timestamp < self.horizon {
+            return Err(KvsError::Compacted(self.horizon));
         }
 
-        let mut curr_idx: usize = 0;
-        let mut merged_file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&list_of_merged_path[curr_idx])?;
+        let records = self.records_since(timestamp)?;
+        Ok(records.into_iter().map(WatchEvent::from).collect())
+    }
 
-        let mut list_of_merge_files = Vec::new();
+    // The keydir knows the exact extent of the record, so it takes a single read.
+    fn read_record_at(&self, keyinfo: &KeyInfo) -> Result<Record> {
+        let mut record = self.read_raw_record_at(keyinfo)?;
+        if let Some(pointer) = record.value_pointer.take() {
+            record.value = self.value_log.read(&record.key, pointer)?;
+        }
+        Ok(record)
+    }
 
-        for &i in to_be_compacted.iter() {
-            let source_file_name = &self.file_names[i];
-            let mut rdr = self.file_handles.get(source_file_name).unwrap();
-            rdr.seek(io::SeekFrom::Start(0));
+    // Like `read_record_at`, but leaves values in the value log where they are.
+    fn read_raw_record_at(&self, keyinfo: &KeyInfo) -> Result<Record> {
+        let version = self.segment_versions[&keyinfo.segment_id];
+        let record = match self.maps.get(&keyinfo.segment_id) {
+            Some(map) => reader::decode_record_at(map, keyinfo.record_pos, version)?.0,
+            None => {
+                let mut buf = vec![0; keyinfo.record_len as usize];
+                reader::read_exact_at(&self.file_handles[&keyinfo.segment_id], &mut buf, keyinfo.record_pos)?;
+                reader::decode_record_at(&buf, 0, version)?.0
+            }
+        };
+        Ok(record)
+    }
 
-            let buf_reader = io::BufReader::with_capacity(1024, rdr);
-            let mut reader = reader::Reader::new(buf_reader);
-            let mut record = Record::new();
+    /// Every record still in the log with a timestamp after `timestamp`, oldest first, index entries
+    /// aside.
+    pub(crate) fn records_since(&self, timestamp: u64) -> Result<Vec<Record>> {
+        let positions = self.positions_since(timestamp)?;
+        self.read_records_at(&positions)
+    }
 
-            let mut curr_offset = 0;
-            let mut next_offset = 0;
-            while reader.read_record(io::SeekFrom::Current(0), &mut record, &mut next_offset)? != false {
-                if let Some(keyinfo) = self.keydir.get(&record.key) {
-                    if keyinfo.timestamp == record.timestamp {
-                        let file_offset = merged_file.seek(io::SeekFrom::End(0))?;
-                        let mut writer = writer::Writer::new(&merged_file);
-                        writer.write_record(&record)?;
-
-                        let new_key_info = KeyInfo {
-                            file_id: list_of_merge_file_names[curr_idx].clone(),
-                            record_pos: file_offset,
-                            timestamp: keyinfo.timestamp,
-                        };
+    /// Where each record still in the log with a timestamp after `timestamp` is, oldest first.
+    ///
+    /// Index entries are left out, as they are from watches: only the changes made to keys leave
+    /// the store, and a follower indexes the records it applies itself. The positions hold until
+    /// `rewrites` changes.
+    pub(crate) fn positions_since(&self, timestamp: u64) -> Result<Vec<KeyInfo>> {
+        let mut positions = Vec::new();
+
+        for segment_id in self.segments.iter() {
+            if self.segment_usage[segment_id].max_timestamp <= timestamp {
+                continue;
+            }
 
-                        self.keydir.insert(record.key.clone(), new_key_info);
-
-                        if curr_idx < list_of_merged_path.len()-1 {
-                            let metadata = merged_file.metadata()?;
-                            if metadata.len() > 1000 {
-                                list_of_merge_files.push(merged_file);
-                                curr_idx += 1;
-
-                                merged_file = fs::OpenOptions::new()
-                                    .read(true)
-                                    .write(true)
-                                    .append(true)
-                                    .create(true)
-                                    .open(&list_of_merged_path[curr_idx])?;
-                            }
-                        }
-                    }
-                }
+            let mut file_to_read = self.file_handles.get(segment_id).unwrap();
+            file_to_read.seek(io::SeekFrom::Start(SegmentHeader::LEN))?;
 
+            let buf_reader = io::BufReader::with_capacity(1024, file_to_read);
+            let mut reader = reader::Reader::new(buf_reader, self.segment_versions[segment_id]);
+            let mut curr_offset = SegmentHeader::LEN;
+            let mut next_offset = 0;
+            while let Some(record) = reader.read_record_ref(io::SeekFrom::Current(0), &mut next_offset)? {
+                if record.timestamp > timestamp && !index::is_index_key(record.key) {
+                    positions.push(KeyInfo {
+                        segment_id: *segment_id,
+                        record_pos: curr_offset,
+                        record_len: (next_offset - curr_offset) as u32,
+                        value_len: record.value_len() as u32,
+                        timestamp: record.timestamp,
+                        in_value_log: record.value_pointer.is_some(),
+                    });
+                }
                 curr_offset = next_offset;
             }
         }
 
-        list_of_merge_files.push(merged_file);
-        curr_idx += 1;
-
-        let mut j = 0;
-        for (k, merged_file) in list_of_merge_files.into_iter().enumerate() {
-            let file_name = &self.file_names[to_be_compacted[j]];
-            let file_path = self.path.join(file_name);
-            let file_path = file_path.as_path();
-
-            fs::remove_file(file_path)?;
-            fs::rename(&list_of_merged_path[k], file_path)?;
+        positions.sort_by_key(|keyinfo| keyinfo.timestamp);
+        Ok(positions)
+    }
 
-            self.file_handles.insert(file_name.clone(), merged_file);
-            j += 1;
+    /// Reads the records at `positions`, as found by `positions_since`.
+    pub(crate) fn read_records_at(&self, positions: &[KeyInfo]) -> Result<Vec<Record>> {
+        let mut records = Vec::with_capacity(positions.len());
+
+        for keyinfo in positions {
+            let mut record = self.read_raw_record_at(keyinfo)?;
+            if let Some(pointer) = record.value_pointer.take() {
+                // garbage collection drops the values of overwritten records the retention does not
+                // keep, which like compaction leaves the later record to catch up from, and moves the
+                // others to a new record under the same timestamp
+                if !self.value_log.contains(pointer) {
+                    continue;
+                }
+                record.value = self.value_log.read(&record.key, pointer)?;
+            }
+            records.push(record);
         }
 
-        let jj = j;
-        while j < to_be_compacted.len() {
-            let idx = to_be_compacted[j];
+        Ok(records)
+    }
 
-            let file_name = &self.file_names[idx];
-            let file_path = self.path.join(file_name);
-            let file_path = file_path.as_path();
+    /// Deletes every key.
+    ///
+    /// Watchers are not notified, and changes made before the call can no longer be replayed.
+    /// Followers are sent a snapshot of the empty store.
+    pub fn clear(&mut self) -> Result<()> {
+        self.wipe()?;
+        // the clear takes a timestamp of its own, so that every follower is behind the horizon
+        self.counter += 1;
+        self.set_horizon(self.last_timestamp())?;
+        // followers subscribed to the records written from now on catch up again from the snapshot
+        self.replicas.clear();
+        Ok(())
+    }
 
-            self.file_handles.remove(file_name);
-            fs::remove_file(file_path)?;
+    /// Deletes every segment and starts over with an empty log whose history begins after `horizon`.
+    pub(crate) fn reset(&mut self, horizon: u64) -> Result<()> {
+        self.wipe()?;
+        self.counter = 1;
+        self.set_horizon(horizon)
+    }
+
+    fn set_horizon(&mut self, horizon: u64) -> Result<()> {
+        write_horizon(&self.path, horizon)?;
+        self.horizon = horizon;
+        Ok(())
+    }
 
-            j += 1;
+    fn wipe(&mut self) -> Result<()> {
+        self.cancel_compaction();
+        self.rewrites += 1;
+        self.keydir.clear();
+        self.maps.clear();
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
         }
+        self.file_handles.clear();
+        self.segment_usage.clear();
+        self.segment_versions.clear();
+        for segment_id in self.segments.drain(..) {
+            fs::remove_file(self.path.join(segment_file_name(segment_id)))?;
+        }
+        self.value_log.clear()?;
+        for index in self.indexes.values_mut() {
+            index.clear();
+        }
+        self.history.clear();
+        self.expiries.clear();
+        if let Some(queue) = self.eviction_queue.as_mut() {
+            queue.clear();
+        }
+
+        self.create_segment()
+    }
+
+    /// Iterates over every live key and its value, in no particular order. Keys in namespaces are
+    /// left out.
+    pub fn iter(&self) -> Iter<'_> {
+        self.iter_prefix(Some(""))
+    }
 
-        if jj < to_be_compacted.len() {
-            let drain_start = to_be_compacted[jj];
-            self.file_names.drain(drain_start..);
+    // Iterates over the keys starting with the namespace prefix `prefix`, with it stripped, or over
+    // the keys outside any namespace if it is empty. With no prefix, every key but the index
+    // entries is returned as stored.
+    fn iter_prefix<'a>(&'a self, prefix: Option<&'a str>) -> Iter<'a> {
+        Iter {
+            store: self,
+            keys: self.keydir.iter(),
+            prefix,
         }
+    }
+}
 
-        Ok(())
+// The horizon is kept in a file of its own: once compaction has dropped a tombstone, nothing left in
+// the log tells how far history is gone.
+pub(crate) fn read_horizon(path: &Path) -> Result<Option<u64>> {
+    match fs::read_to_string(path.join(HORIZON_FILE_NAME)) {
+        Ok(horizon) => horizon.trim().parse().map(Some).map_err(|_| {
+            let message = format!("invalid {} file {:?}", HORIZON_FILE_NAME, horizon);
+            io::Error::new(io::ErrorKind::InvalidData, message).into()
+        }),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Replaces the horizon file in one rename, so that it is never seen half written.
+fn write_horizon(path: &Path, horizon: u64) -> Result<()> {
+    let tmp_path = path.join(format!("{}.tmp", HORIZON_FILE_NAME));
+    let mut f = fs::File::create(&tmp_path)?;
+    writeln!(f, "{}", horizon)?;
+    f.sync_all()?;
+    fs::rename(&tmp_path, path.join(HORIZON_FILE_NAME))?;
+    Ok(())
+}
+
+// Opens the segment at `file_path` for reading and appending, creating it with a header if it does
+// not exist yet.
+// Whether the record at `pos` in `file` is the last one in it.
+fn ends_file(file: &fs::File, pos: u64) -> io::Result<bool> {
+    let mut header = [0; record::RECORD_HEADER_LEN];
+    reader::read_exact_at(file, &mut header, pos)?;
+    Ok(pos + record::RECORD_HEADER_LEN as u64 + record::body_len(&header) == file.metadata()?.len())
+}
+
+fn create_segment_file(file_path: &Path) -> Result<fs::File> {
+    let mut f = fs::OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(file_path)?;
+    if f.metadata()?.len() == 0 {
+        f.write_all(&SegmentHeader::new().encode())?;
+    }
+
+    Ok(f)
+}
+
+// Returns the format version of the segment in `f`, failing unless the store can read it. An empty
+// segment, left behind by a crash right after it was created, is given its header.
+fn check_segment_format(file_name: &str, f: &mut fs::File) -> Result<u16> {
+    if f.metadata()?.len() == 0 {
+        f.write_all(&SegmentHeader::new().encode())?;
+        return Ok(FORMAT_VERSION);
+    }
+
+    let header = SegmentHeader::read(f)?;
+    let version = SegmentHeader::version_of(header.as_ref());
+    if !(MIN_READABLE_VERSION..=FORMAT_VERSION).contains(&version) {
+        return Err(KvsError::UnsupportedFormat {
+            file_name: file_name.to_string(),
+            version,
+        });
+    }
+
+    Ok(version)
+}
+
+// The keydir stores record lengths in 32 bits.
+fn check_record_size(record: &Record) -> Result<()> {
+    let payload = (record.key.len() + record.value.len()) as u64;
+    // leaves room for the length prefix and the rest of the encoding
+    if payload > u64::from(u32::MAX) - 64 {
+        return Err(KvsError::RecordTooLarge(payload));
+    }
+
+    Ok(())
+}
+
+pub struct Iter<'a> {
+    store: &'a KvStore,
+    keys: keydir::Iter<'a>,
+    prefix: Option<&'a str>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, keyinfo) = self.keys.next()?;
+            if self.store.expiries.is_expired(key) {
+                continue;
+            }
+            let key = match self.prefix {
+                None if index::is_index_key(key) => continue,
+                None => key,
+                Some("") if namespace::is_namespaced(key) => continue,
+                Some("") => key,
+                Some(prefix) => match key.strip_prefix(prefix) {
+                    Some(key) => key,
+                    None => continue,
+                },
+            };
+
+            return Some(
+                self.store
+                    .read_record_at(keyinfo)
+                    .map(|record| (key.to_string(), record.value)),
+            );
+        }
     }
 }
 
 impl Drop for KvStore {
     fn drop(&mut self) {
-        for f in self.file_handles.iter() {
-            f.1.sync_data();
+        self.cancel_compaction();
+        for f in self.file_handles.values() {
+            let _ = f.sync_data();
         }
     }
 }
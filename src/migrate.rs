@@ -0,0 +1,70 @@
+use crate::format::{SegmentHeader, FORMAT_VERSION};
+use crate::reader::Reader;
+use crate::writer::Writer;
+use crate::{KvStore, KvsError, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+impl KvStore {
+    /// Rewrites every segment in `path` that is in an older format into the current one, returning
+    /// how many were rewritten.
+    ///
+    /// The store must not be open. Each segment is replaced in one rename, so an interrupted migration
+    /// leaves a store that can simply be migrated again.
+    pub fn migrate(path: &Path) -> Result<u64> {
+        let mut file_names = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.path().extension() == Some(OsStr::new("bcd")) {
+                file_names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        file_names.sort();
+
+        let mut migrated = 0;
+        for file_name in file_names.iter() {
+            let file_path = path.join(file_name);
+            let f = fs::File::open(&file_path)?;
+            let header = SegmentHeader::read(&f)?;
+            let version = SegmentHeader::version_of(header.as_ref());
+            if version == FORMAT_VERSION {
+                continue;
+            }
+            if version > FORMAT_VERSION {
+                return Err(KvsError::UnsupportedFormat {
+                    file_name: file_name.clone(),
+                    version,
+                });
+            }
+
+            let migrated_path = file_path.with_extension("migrate");
+            rewrite_segment(f, header.as_ref(), &migrated_path)?;
+            fs::rename(&migrated_path, &file_path)?;
+            migrated += 1;
+        }
+
+        Ok(migrated)
+    }
+}
+
+// Copies the records of the segment in `src` into a new segment at `dest` in the current format.
+fn rewrite_segment(mut src: fs::File, header: Option<&SegmentHeader>, dest: &Path) -> Result<()> {
+    src.seek(io::SeekFrom::Start(SegmentHeader::records_start(header)))?;
+    let mut reader = Reader::new(io::BufReader::new(src), SegmentHeader::version_of(header));
+    let mut next_offset = 0;
+
+    let mut wtr = io::BufWriter::new(fs::File::create(dest)?);
+    wtr.write_all(&SegmentHeader::new().encode())?;
+    let mut writer = Writer::new(&mut wtr);
+    while let Some(record) = reader.read_record_ref(io::SeekFrom::Current(0), &mut next_offset)? {
+        writer.write_record_ref(record)?;
+    }
+
+    wtr.flush()?;
+    wtr.get_ref().sync_all()?;
+
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use crate::record::Record;
+use crate::{Iter, KvStore, KvsError, Result, ValueReader};
+use std::collections::BTreeSet;
+use std::io::Read;
+
+// Namespaced keys are stored as `\0name\0key`, which keeps them apart from the keys of the default
+// namespace since those may not start with a NUL character.
+const SEPARATOR: char = '\0';
+
+/// A named key space within a store, returned by `KvStore::namespace`.
+///
+/// Namespaces share the segments, value log and compaction of the store, but their keys are
+/// independent of each other and of the keys set on the store itself. Watches and `changes_since`
+/// see the keys of every namespace, prefixed with `\0name\0`.
+pub struct Namespace<'a> {
+    store: &'a mut KvStore,
+    prefix: String,
+}
+
+/// Space used by one namespace, as reported by `Namespace::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceStats {
+    pub live_keys: u64,
+    /// Bytes taken in the segments by the latest record of each key.
+    pub live_bytes: u64,
+    /// Total length of the latest value of each key, including values kept in the value log.
+    pub value_bytes: u64,
+}
+
+impl KvStore {
+    /// The namespace called `name`, which needs no creating: it exists as long as it has keys.
+    ///
+    /// Names must not be empty or contain NUL characters.
+    pub fn namespace(&mut self, name: &str) -> Result<Namespace<'_>> {
+        Ok(Namespace {
+            prefix: namespace_prefix(name)?,
+            store: self,
+        })
+    }
+
+    /// The names of the namespaces holding any keys, in order.
+    pub fn namespaces(&self) -> Vec<String> {
+        let names: BTreeSet<&str> = self
+            .keydir
+            .iter()
+            .filter_map(|(key, _)| key.strip_prefix(SEPARATOR))
+            .filter_map(|key| key.split(SEPARATOR).next())
+            .filter(|name| !name.is_empty())
+            .collect();
+        names.into_iter().map(str::to_string).collect()
+    }
+
+    /// Removes every key of the namespace `name`, returning how many there were.
+    pub fn drop_namespace(&mut self, name: &str) -> Result<u64> {
+        let prefix = namespace_prefix(name)?;
+        let timestamp = self.counter;
+        let tombstones: Vec<Record> = self
+            .keydir
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix.as_str()))
+            .enumerate()
+            .map(|(i, (key, _))| Record {
+                timestamp: timestamp + i as u64,
+                tombstone: 1,
+                key: key.to_string(),
+                value: String::new(),
+                value_pointer: None,
+                written_at: 0,
+                expires_at: 0,
+            })
+            .collect();
+
+        self.write_records(&tombstones)?;
+        self.maybe_compact()?;
+        Ok(tombstones.len() as u64)
+    }
+}
+
+impl<'a> Namespace<'a> {
+    pub fn name(&self) -> &str {
+        self.prefix.trim_matches(SEPARATOR)
+    }
+
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let key = self.key(&key);
+        self.store.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let key = self.key(&key);
+        self.store.set_unchecked(key, value)
+    }
+
+    /// Like `KvStore::set_reader`.
+    pub fn set_reader<R: Read>(&mut self, key: String, value: R, len: u64) -> Result<()> {
+        let key = self.key(&key);
+        self.store.set_reader_unchecked(key, value, len)
+    }
+
+    /// Like `KvStore::get_reader`.
+    pub fn get_reader(&self, key: &str) -> Result<Option<ValueReader>> {
+        self.store.get_reader(&self.key(key))
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        let key = self.key(&key);
+        self.store.remove_unchecked(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.store.contains_key(&self.key(key))
+    }
+
+    pub fn get_value_len(&self, key: &str) -> Option<u64> {
+        self.store.get_value_len(&self.key(key))
+    }
+
+    /// Iterates over every key of the namespace and its value, in no particular order.
+    pub fn iter(&self) -> Iter<'_> {
+        self.store.iter_prefix(Some(&self.prefix))
+    }
+
+    /// Reports how much of the store the namespace takes. This does not touch the disk.
+    pub fn stats(&self) -> NamespaceStats {
+        let mut stats = NamespaceStats {
+            live_keys: 0,
+            live_bytes: 0,
+            value_bytes: 0,
+        };
+        for (_, keyinfo) in self
+            .store
+            .keydir
+            .iter()
+            .filter(|(key, _)| key.starts_with(self.prefix.as_str()))
+        {
+            stats.live_keys += 1;
+            stats.live_bytes += u64::from(keyinfo.record_len);
+            stats.value_bytes += u64::from(keyinfo.value_len);
+        }
+        stats
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+pub(crate) fn namespace_prefix(name: &str) -> Result<String> {
+    if name.is_empty() || name.contains(SEPARATOR) {
+        return Err(KvsError::InvalidNamespace(name.to_string()));
+    }
+
+    Ok(format!("{}{}{}", SEPARATOR, name, SEPARATOR))
+}
+
+// Keeps the keys set on the store itself from reaching into namespaces or index entries.
+pub(crate) fn check_key(key: &str) -> Result<()> {
+    if is_namespaced(key) {
+        return Err(KvsError::InvalidKey(key.to_string()));
+    }
+    Ok(())
+}
+
+// Keys read back from an export keep the prefix of their namespace, but must not be anything else
+// the store keeps internally, such as index entries.
+pub(crate) fn check_exported_key(key: &str) -> Result<()> {
+    let rest = match key.strip_prefix(SEPARATOR) {
+        Some(rest) => rest,
+        None => return Ok(()),
+    };
+    // index entries are kept under an empty namespace name, which `namespace` does not allow
+    match rest.split_once(SEPARATOR) {
+        Some((name, _)) if !name.is_empty() => Ok(()),
+        _ => Err(KvsError::InvalidKey(key.to_string())),
+    }
+}
+
+/// Whether `key` belongs to a namespace rather than to the store itself.
+pub(crate) fn is_namespaced(key: &str) -> bool {
+    key.starts_with(SEPARATOR)
+}
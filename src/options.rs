@@ -0,0 +1,26 @@
+use crate::{EvictionPolicy, Retention};
+
+/// Settings for `KvStore::open_with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Memory-map sealed segments and decode records straight from the map, instead of reading
+    /// them through the file. The segment being written to is always read through the file.
+    ///
+    /// Other processes must not modify the segments while the store is open.
+    pub mmap: bool,
+    /// Keep up to this many bytes of recently read values in memory, 0 to disable the cache.
+    pub cache_capacity: usize,
+    /// Write values longer than this many bytes to a separate value log, leaving only a pointer to
+    /// them in the segments, so compaction does not copy them around. The value log has its own
+    /// garbage collection, `KvStore::collect_value_log`. `None` keeps every value in the segments.
+    pub value_log_threshold: Option<usize>,
+    /// Which past versions of each key compaction keeps, only the live one by default.
+    pub retention: Retention,
+    /// Keep `Stats::live_data_bytes` under this many by evicting keys after each write, writing
+    /// tombstones for them that compaction later reclaims. Unlike `Stats::live_bytes`, this leaves
+    /// out the tombstones and past versions compaction keeps, and counts the values in the value log
+    /// along with the records pointing at them. `None` never evicts anything.
+    pub max_live_bytes: Option<u64>,
+    /// Which keys go first when evicting, least recently used by default.
+    pub eviction: EvictionPolicy,
+}
@@ -0,0 +1,107 @@
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Longest a caller sleeps before looking at the bucket again, so rate changes apply promptly.
+const MAX_WAIT: Duration = Duration::from_millis(50);
+
+// Fraction of a second's worth of I/O that can be done in one burst after the limiter sat idle.
+const BURST: f64 = 0.1;
+
+/// Token bucket limiting I/O to a number of bytes per second.
+///
+/// Clones share the same bucket, so a clone kept by another thread can change the rate of a
+/// compaction that is already running. A rate of 0 means unlimited.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    bytes_per_sec: u64,
+    // goes negative when a caller takes more than is available, making the next caller wait
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = f64::min(
+            self.tokens + elapsed * self.bytes_per_sec as f64,
+            BURST * self.bytes_per_sec as f64,
+        );
+        self.refilled = now;
+    }
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            bucket: Arc::new(Mutex::new(Bucket {
+                bytes_per_sec,
+                tokens: 0.0,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        RateLimiter::new(0)
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().bytes_per_sec
+    }
+
+    /// Changes the rate, taking effect for callers already waiting too.
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.bytes_per_sec = bytes_per_sec;
+        bucket.tokens = f64::min(bucket.tokens, BURST * bytes_per_sec as f64);
+    }
+
+    /// Blocks until `bytes` of I/O fit within the rate.
+    pub fn acquire(&self, bytes: u64) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                if bucket.bytes_per_sec == 0 {
+                    return;
+                }
+
+                bucket.refill();
+                if bucket.tokens >= 0.0 {
+                    bucket.tokens -= bytes as f64;
+                    return;
+                }
+                Duration::from_secs_f64(-bucket.tokens / bucket.bytes_per_sec as f64)
+            };
+
+            thread::sleep(cmp::min(wait, MAX_WAIT));
+        }
+    }
+
+    /// Takes `bytes` without waiting, even if that goes over the rate, and returns whether any more
+    /// I/O fits within it right now.
+    pub(crate) fn try_acquire(&self, bytes: u64) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.bytes_per_sec == 0 {
+            return true;
+        }
+
+        bucket.refill();
+        bucket.tokens -= bytes as f64;
+        bucket.tokens >= 0.0
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::unlimited()
+    }
+}
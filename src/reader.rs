@@ -1,21 +1,103 @@
-use crate::record::Record;
-use byteorder::{BigEndian, ReadBytesExt};
-use rmp_serde::{Deserializer, Serializer};
-use serde::{Deserialize, Serialize};
+use crate::record::{self, Record, RecordRef, RECORD_HEADER_LEN};
+use byteorder::{BigEndian, ByteOrder};
+use rmp_serde::Deserializer;
+use serde::Deserialize;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
-use std::io::BufRead;
 use std::io::Cursor;
 
+/// Decodes the msgpack payload of a format version 1 record, which must span all of `buf`.
+fn decode_msgpack(buf: &[u8]) -> io::Result<Record> {
+    let mut de = Deserializer::new(Cursor::new(buf));
+    let record: Record =
+        Deserialize::deserialize(&mut de).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    if de.position() != buf.len() as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "trailing bytes after record",
+        ));
+    }
+
+    Ok(record)
+}
+
+/// Decodes the record at `pos` in `buf`, laid out as in format `version`, returning it with its
+/// length.
+pub(crate) fn decode_record_at(buf: &[u8], pos: u64, version: u16) -> io::Result<(Record, u64)> {
+    let pos = pos as usize;
+    if version < 2 {
+        let header = buf
+            .get(pos..pos + 8)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "record length cut short"))?;
+        let len = BigEndian::read_u64(header);
+        if len > (buf.len() - pos - 8) as u64 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record cut short"));
+        }
+
+        let record = decode_msgpack(&buf[pos + 8..pos + 8 + len as usize])?;
+        return Ok((record, 8 + len));
+    }
+
+    let header = buf
+        .get(pos..pos + RECORD_HEADER_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "record header cut short"))?;
+    let len = RECORD_HEADER_LEN as u64 + record::body_len(header);
+    if len > (buf.len() - pos) as u64 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record cut short"));
+    }
+
+    let record = record::decode(&buf[pos..pos + len as usize])?;
+    Ok((record.to_record(), len))
+}
+
+/// Fills `buf` from `file` starting at `pos`, without moving the file's cursor.
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &fs::File, buf: &mut [u8], pos: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buf, pos)
+}
+
+/// Fills `buf` from `file` starting at `pos`. This moves the file's cursor, which writes and scans
+/// do not rely on since they seek first.
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &fs::File, mut buf: &mut [u8], mut pos: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, pos) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "record cut short")),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                pos += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the records of a segment in format `version` one after the other.
 #[derive(Debug)]
 pub(crate) struct Reader<R> {
     rdr: io::BufReader<R>,
+    version: u16,
+    buf: Vec<u8>,
+    // the last record read from a version 1 segment, which `read_record_ref` lends out
+    legacy: Record,
 }
 
 impl<R: io::Read + io::Seek> Reader<R> {
-    pub(crate) fn new(rdr: R) -> Reader<R> {
+    pub(crate) fn new(rdr: R, version: u16) -> Reader<R> {
         Reader {
             rdr: io::BufReader::with_capacity(100, rdr),
+            version,
+            buf: Vec::new(),
+            legacy: Record::new(),
         }
     }
 
@@ -25,35 +107,57 @@ impl<R: io::Read + io::Seek> Reader<R> {
         record: &mut Record,
         next_offset: &mut u64,
     ) -> io::Result<bool> {
-        self.rdr.seek(seek_from)?;
-
-        let mut buf: [u8; 8] = [0; 8];
-        let num_of_bytes = self.rdr.read(&mut buf)?;
-        if num_of_bytes == 0 {
-            return Ok(false);
+        match self.read_record_ref(seek_from, next_offset)? {
+            Some(record_ref) => {
+                *record = record_ref.to_record();
+                Ok(true)
+            }
+            None => Ok(false),
         }
+    }
 
-        let mut cursor = Cursor::new(&buf);
-        let record_size: usize = cursor.read_u64::<BigEndian>().unwrap() as usize;
-
-        let mut buf = Vec::new();
-        buf.resize(record_size, 0);
+    /// Reads the next record like `read_record`, but lends it out instead of copying its key and
+    /// value, so callers can look at a record before deciding to keep it.
+    pub(crate) fn read_record_ref(
+        &mut self,
+        seek_from: io::SeekFrom,
+        next_offset: &mut u64,
+    ) -> io::Result<Option<RecordRef<'_>>> {
+        self.rdr.seek(seek_from)?;
 
-        let num_of_bytes = self.rdr.read(&mut buf)?;
-        if num_of_bytes == 0 {
-            return Ok(false);
+        let header_len = if self.version < 2 { 8 } else { RECORD_HEADER_LEN };
+        self.buf.resize(header_len, 0);
+        if !read_full(&mut self.rdr, &mut self.buf)? {
+            return Ok(None);
         }
 
-        let mut de = Deserializer::new(&buf[..]);
-        let deseralized_record: Record = Deserialize::deserialize(&mut de).unwrap();
+        let body_len = if self.version < 2 {
+            BigEndian::read_u64(&self.buf)
+        } else {
+            record::body_len(&self.buf)
+        };
+        // read incrementally, so a garbage length does not allocate more than the file holds
+        let read = (&mut self.rdr).take(body_len).read_to_end(&mut self.buf)?;
+        if (read as u64) < body_len {
+            return Ok(None);
+        }
 
-        record.timestamp = deseralized_record.timestamp;
-        record.tombstone = deseralized_record.tombstone;
-        record.key = deseralized_record.key;
-        record.value = deseralized_record.value;
+        *next_offset = self.rdr.stream_position()?;
 
-        *next_offset = self.rdr.seek(io::SeekFrom::Current(0))?;
+        if self.version < 2 {
+            self.legacy = decode_msgpack(&self.buf[8..])?;
+            return Ok(Some(self.legacy.as_record_ref()));
+        }
+        record::decode(&self.buf).map(Some)
+    }
+}
 
-        Ok(true)
+// A record cut short by the end of the file is one that is still being written (or was torn by
+// a crash), so it is treated the same as reaching the end of the segment.
+fn read_full<R: io::Read>(rdr: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match rdr.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
     }
 }
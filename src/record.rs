@@ -1,12 +1,26 @@
+use byteorder::{BigEndian, ByteOrder};
 use serde::{Deserialize, Serialize};
+use std::io;
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Serialize, Deserialize, Debug)]
-// TODO: add CRC32
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct Record {
     pub(crate) timestamp: u64,
     pub(crate) tombstone: u8,
     pub(crate) key: String,
     pub(crate) value: String,
+    /// Set when the value is kept in the value log, in which case `value` may not hold it.
+    #[serde(skip)]
+    pub(crate) value_pointer: Option<ValuePointer>,
+    /// Wall-clock time the record was written at, in milliseconds since the Unix epoch, or 0 if
+    /// unknown because the record predates format version 3.
+    #[serde(skip)]
+    pub(crate) written_at: u64,
+    /// Wall-clock time the key expires at, in milliseconds since the Unix epoch, or 0 if it never
+    /// does.
+    #[serde(skip)]
+    pub(crate) expires_at: u64,
 }
 
 impl Record {
@@ -16,6 +30,247 @@ impl Record {
             tombstone: 0,
             key: "".to_string(),
             value: "".to_string(),
+            value_pointer: None,
+            written_at: 0,
+            expires_at: 0,
         }
     }
+
+    pub(crate) fn as_record_ref(&self) -> RecordRef<'_> {
+        RecordRef {
+            timestamp: self.timestamp,
+            tombstone: self.tombstone,
+            key: &self.key,
+            value: &self.value,
+            value_pointer: self.value_pointer,
+            written_at: self.written_at,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+/// A record borrowing its key and value from the buffer it was decoded from.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RecordRef<'a> {
+    pub(crate) timestamp: u64,
+    pub(crate) tombstone: u8,
+    pub(crate) key: &'a str,
+    pub(crate) value: &'a str,
+    /// Set when the value is kept in the value log, in which case `value` is empty.
+    pub(crate) value_pointer: Option<ValuePointer>,
+    pub(crate) written_at: u64,
+    pub(crate) expires_at: u64,
+}
+
+impl<'a> RecordRef<'a> {
+    pub(crate) fn to_record(self) -> Record {
+        Record {
+            timestamp: self.timestamp,
+            tombstone: self.tombstone,
+            key: self.key.to_string(),
+            value: self.value.to_string(),
+            value_pointer: self.value_pointer,
+            written_at: self.written_at,
+            expires_at: self.expires_at,
+        }
+    }
+
+    /// The length of the value, wherever it is kept.
+    pub(crate) fn value_len(&self) -> u64 {
+        match self.value_pointer {
+            Some(pointer) => u64::from(pointer.value_len),
+            None => self.value.len() as u64,
+        }
+    }
+}
+
+/// Where a value moved to the value log is kept: the record at `record_pos` in value log file
+/// `file_id`, which repeats the key and timestamp of the record pointing to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ValuePointer {
+    pub(crate) file_id: u32,
+    pub(crate) record_pos: u64,
+    pub(crate) value_len: u32,
+}
+
+impl ValuePointer {
+    const LEN: usize = 4 + 8 + 4;
+
+    /// The length of the record the pointer points to, whose key is `key_len` bytes long.
+    pub(crate) fn record_len(&self, key_len: usize) -> u64 {
+        (RECORD_HEADER_LEN + key_len) as u64 + u64::from(self.value_len)
+    }
+
+    fn encode(&self) -> [u8; ValuePointer::LEN] {
+        let mut buf = [0; ValuePointer::LEN];
+        BigEndian::write_u32(&mut buf[..4], self.file_id);
+        BigEndian::write_u64(&mut buf[4..12], self.record_pos);
+        BigEndian::write_u32(&mut buf[12..], self.value_len);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> io::Result<ValuePointer> {
+        if buf.len() != ValuePointer::LEN {
+            return Err(invalid_data("value pointer of the wrong length"));
+        }
+
+        Ok(ValuePointer {
+            file_id: BigEndian::read_u32(&buf[..4]),
+            record_pos: BigEndian::read_u64(&buf[4..12]),
+            value_len: BigEndian::read_u32(&buf[12..]),
+        })
+    }
+}
+
+// Since format version 2 a record is laid out as follows, with integers in big-endian:
+//
+//   crc         u32  CRC-32 of every byte after it
+//   timestamp   u64
+//   flags       u8   bit 0 is set for tombstones, bit 1 when the value is a pointer into the value
+//                    log, bit 2 when the record has a `written_at`, bit 3 when it has an `expires_at`
+//   key_len     u32
+//   value_len   u32
+//   key         key_len bytes of UTF-8
+//   value       value_len bytes of UTF-8, or a `ValuePointer`
+//   written_at  u64  milliseconds since the Unix epoch, only written since format version 3
+//   expires_at  u64  milliseconds since the Unix epoch, only written since format version 4
+//
+// Before that, a record was a u64 length followed by the msgpack encoding of `Record`.
+
+/// Length of the fixed part of a record, ahead of its key and value.
+pub(crate) const RECORD_HEADER_LEN: usize = 4 + 8 + 1 + 4 + 4;
+
+const TOMBSTONE: u8 = 1;
+const VALUE_POINTER: u8 = 2;
+const WALL_CLOCK: u8 = 4;
+const EXPIRES: u8 = 8;
+
+/// The current wall-clock time, as stored in `written_at`.
+pub(crate) fn wall_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Appends the encoding of `record` to `buf`.
+pub(crate) fn encode(record: RecordRef, buf: &mut Vec<u8>) {
+    let pointer = record.value_pointer.map(|pointer| pointer.encode());
+    let value = match pointer {
+        Some(ref pointer) => &pointer[..],
+        None => record.value.as_bytes(),
+    };
+
+    let start = buf.len();
+    buf.resize(start + RECORD_HEADER_LEN, 0);
+    encode_header(
+        record.timestamp,
+        record.tombstone,
+        record.key.len() as u32,
+        value.len() as u32,
+        record.written_at,
+        &mut buf[start..],
+    );
+    if pointer.is_some() {
+        buf[start + 12] |= VALUE_POINTER;
+    }
+    if record.expires_at != 0 {
+        buf[start + 12] |= EXPIRES;
+    }
+    buf.extend_from_slice(record.key.as_bytes());
+    buf.extend_from_slice(value);
+    if record.written_at != 0 {
+        buf.extend_from_slice(&record.written_at.to_be_bytes());
+    }
+    if record.expires_at != 0 {
+        buf.extend_from_slice(&record.expires_at.to_be_bytes());
+    }
+
+    let crc = crc32fast::hash(&buf[start + 4..]);
+    BigEndian::write_u32(&mut buf[start..start + 4], crc);
+}
+
+/// Fills in the fixed part of a record in `header`, except for its checksum. A `written_at` of 0
+/// is left out of the record.
+pub(crate) fn encode_header(
+    timestamp: u64,
+    tombstone: u8,
+    key_len: u32,
+    value_len: u32,
+    written_at: u64,
+    header: &mut [u8],
+) {
+    BigEndian::write_u64(&mut header[4..12], timestamp);
+    header[12] = if tombstone == 1 { TOMBSTONE } else { 0 };
+    if written_at != 0 {
+        header[12] |= WALL_CLOCK;
+    }
+    BigEndian::write_u32(&mut header[13..17], key_len);
+    BigEndian::write_u32(&mut header[17..21], value_len);
+}
+
+/// Whether the record starting with `header` points into the value log for its value.
+pub(crate) fn has_value_pointer(header: &[u8]) -> bool {
+    header[12] & VALUE_POINTER != 0
+}
+
+/// The length of the value, or value pointer, of the record that starts with `header`.
+pub(crate) fn value_len(header: &[u8]) -> u32 {
+    BigEndian::read_u32(&header[17..21])
+}
+
+/// The number of bytes following the fixed part of the record that starts with `header`.
+pub(crate) fn body_len(header: &[u8]) -> u64 {
+    let mut trailer_len = 0;
+    if header[12] & WALL_CLOCK != 0 {
+        trailer_len += 8;
+    }
+    if header[12] & EXPIRES != 0 {
+        trailer_len += 8;
+    }
+    u64::from(BigEndian::read_u32(&header[13..17])) + u64::from(value_len(header)) + trailer_len
+}
+
+/// Decodes a record, which must span all of `buf`, without copying its key or value.
+pub(crate) fn decode(buf: &[u8]) -> io::Result<RecordRef<'_>> {
+    if buf.len() < RECORD_HEADER_LEN || buf.len() as u64 != RECORD_HEADER_LEN as u64 + body_len(buf) {
+        return Err(invalid_data("record length does not match its header"));
+    }
+    if BigEndian::read_u32(&buf[..4]) != crc32fast::hash(&buf[4..]) {
+        return Err(invalid_data("record checksum mismatch"));
+    }
+
+    let key_len = BigEndian::read_u32(&buf[13..17]) as usize;
+    let (key, rest) = buf[RECORD_HEADER_LEN..].split_at(key_len);
+    let (value, mut trailer) = rest.split_at(value_len(buf) as usize);
+    let mut read_trailer = |flag: u8| {
+        if buf[12] & flag == 0 {
+            return 0;
+        }
+        let (field, rest) = trailer.split_at(8);
+        trailer = rest;
+        BigEndian::read_u64(field)
+    };
+    let written_at = read_trailer(WALL_CLOCK);
+    let expires_at = read_trailer(EXPIRES);
+    let (value, value_pointer) = if buf[12] & VALUE_POINTER != 0 {
+        ("", Some(ValuePointer::decode(value)?))
+    } else {
+        (str::from_utf8(value).map_err(invalid_data)?, None)
+    };
+    Ok(RecordRef {
+        timestamp: BigEndian::read_u64(&buf[4..12]),
+        tombstone: buf[12] & TOMBSTONE,
+        key: str::from_utf8(key).map_err(invalid_data)?,
+        value,
+        value_pointer,
+        written_at,
+        expires_at,
+    })
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
 }
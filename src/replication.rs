@@ -0,0 +1,473 @@
+//! Leader/follower replication.
+//!
+//! A follower connects to a leader and announces the timestamp of the last record it has applied.
+//! The leader answers with every record written after that point and then keeps streaming new
+//! records as they are appended. Followers write the records with the leader's timestamps, so the
+//! position to resume from after a restart is simply the follower's own latest timestamp. A
+//! follower that falls too far behind the live stream, or whose leader is cleared, catches up again
+//! the way it did when it connected.
+//!
+//! If compaction on the leader has discarded history the follower still needs, the leader sends a
+//! snapshot of all live records instead and the follower rebuilds its store from it.
+
+use crate::index;
+use crate::keydir::KeyInfo;
+use crate::record::Record;
+use crate::{KvStore, KvsError, Result};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rmp_serde::{Deserializer, Serializer};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// records read from the store at a time while a follower catches up
+const CHUNK_LEN: usize = 256;
+// records a follower can fall behind the leader before it has to catch up again
+const REPLICA_BUFFER_LEN: usize = 4096;
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Message {
+    /// follower -> leader: the timestamp of the last record the follower has applied
+    Hello { position: u64 },
+    /// leader -> follower: discard everything, the records that follow are a full copy
+    Snapshot { position: u64 },
+    /// leader -> follower: a record, with the times the serialized record leaves out
+    Record {
+        record: Record,
+        written_at: u64,
+        expires_at: u64,
+    },
+    /// leader -> follower: everything up to `position` has been sent
+    Synced { position: u64 },
+    /// follower -> leader
+    Ack { position: u64 },
+}
+
+fn send<W: Write>(wtr: &mut W, message: &Message) -> Result<()> {
+    let mut buf = Vec::new();
+    message
+        .serialize(&mut Serializer::new(&mut buf))
+        .map_err(|e| KvsError::Protocol(e.to_string()))?;
+
+    wtr.write_u64::<BigEndian>(buf.len() as u64)?;
+    wtr.write_all(&buf)?;
+
+    Ok(())
+}
+
+fn record_message(record: Record) -> Message {
+    Message::Record {
+        written_at: record.written_at,
+        expires_at: record.expires_at,
+        record,
+    }
+}
+
+fn receive<R: Read>(rdr: &mut R) -> Result<Option<Message>> {
+    let len = match rdr.read_u64::<BigEndian>() {
+        Ok(len) => len,
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut buf = vec![0; len as usize];
+    rdr.read_exact(&mut buf)?;
+
+    let mut de = Deserializer::new(&buf[..]);
+    let message = Deserialize::deserialize(&mut de).map_err(|e| KvsError::Protocol(e.to_string()))?;
+
+    Ok(Some(message))
+}
+
+/// Serves the records of a store to any number of followers.
+pub struct Leader {
+    local_addr: SocketAddr,
+    followers: Arc<Mutex<HashMap<SocketAddr, u64>>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Leader {
+    pub fn bind<A: ToSocketAddrs>(addr: A, store: Arc<Mutex<KvStore>>) -> Result<Leader> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let followers = Arc::new(Mutex::new(HashMap::new()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let leader = Leader {
+            local_addr,
+            followers: followers.clone(),
+            shutdown: shutdown.clone(),
+        };
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+
+                let store = store.clone();
+                let followers = followers.clone();
+                let shutdown = shutdown.clone();
+                thread::spawn(move || {
+                    let peer = stream.peer_addr().ok();
+                    let _ = serve_follower(stream, &store, &followers, &shutdown);
+                    if let Some(peer) = peer {
+                        followers.lock().unwrap().remove(&peer);
+                    }
+                });
+            }
+        });
+
+        Ok(leader)
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The connected followers and the position each one last acknowledged.
+    pub fn followers(&self) -> Vec<(SocketAddr, u64)> {
+        let followers = self.followers.lock().unwrap();
+        followers.iter().map(|(addr, position)| (*addr, *position)).collect()
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake up the accept loop so it notices the shutdown
+        let _ = TcpStream::connect(self.local_addr);
+    }
+}
+
+fn serve_follower(
+    stream: TcpStream,
+    store: &Mutex<KvStore>,
+    followers: &Arc<Mutex<HashMap<SocketAddr, u64>>>,
+    shutdown: &AtomicBool,
+) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let mut rdr = io::BufReader::new(stream.try_clone()?);
+    let mut wtr = io::BufWriter::new(stream);
+
+    let position = match receive(&mut rdr)? {
+        Some(Message::Hello { position }) => position,
+        Some(message) => return Err(KvsError::Protocol(format!("expected hello, got {:?}", message))),
+        None => return Ok(()),
+    };
+
+    followers.lock().unwrap().insert(peer, position);
+    let acks = followers.clone();
+    thread::spawn(move || {
+        while let Ok(Some(Message::Ack { position })) = receive(&mut rdr) {
+            acks.lock().unwrap().insert(peer, position);
+        }
+    });
+
+    let (mut rx, mut sent) = catch_up(&mut wtr, store, position)?;
+    send(&mut wtr, &Message::Synced { position: sent })?;
+    wtr.flush()?;
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let record = match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(record) => record,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            // the store drops the sender of a follower too far behind, and every sender when it is
+            // cleared, after which the follower is behind the horizon and gets a snapshot
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let (resubscribed, caught_up) = catch_up(&mut wtr, store, sent)?;
+                rx = resubscribed;
+                sent = caught_up;
+                wtr.flush()?;
+                continue;
+            }
+        };
+
+        if record.timestamp > sent {
+            sent = record.timestamp;
+            send(&mut wtr, &record_message(record))?;
+        }
+
+        while let Ok(record) = rx.try_recv() {
+            if record.timestamp > sent {
+                sent = record.timestamp;
+                send(&mut wtr, &record_message(record))?;
+            }
+        }
+        wtr.flush()?;
+    }
+
+    Ok(())
+}
+
+// Sends a follower at `position` everything it is missing, a chunk at a time so that writers are only
+// held up while one chunk is read, and subscribes to new records once the rest fits in one chunk.
+// Returns the subscription and the timestamp it picks up after.
+fn catch_up<W: Write>(wtr: &mut W, store: &Mutex<KvStore>, mut position: u64) -> Result<(mpsc::Receiver<Record>, u64)> {
+    let mut pending: Vec<KeyInfo> = Vec::new();
+    let mut next = 0;
+    let mut rewrites = None;
+
+    loop {
+        let mut guard = store.lock().unwrap();
+
+        let last = guard.last_timestamp();
+        if position < guard.horizon() || position > last {
+            let keys = guard.snapshot_keys();
+            drop(guard);
+
+            send(wtr, &Message::Snapshot { position: last })?;
+            for chunk in keys.chunks(CHUNK_LEN) {
+                let records = store.lock().unwrap().snapshot_records(chunk, last)?;
+                for record in records {
+                    send(wtr, &record_message(record))?;
+                }
+            }
+
+            // whatever changed while the snapshot was sent comes from the log
+            position = last;
+            pending.clear();
+            next = 0;
+            continue;
+        }
+
+        if next == pending.len() || rewrites != Some(guard.rewrites) {
+            pending = guard.positions_since(position)?;
+            next = 0;
+            rewrites = Some(guard.rewrites);
+
+            if pending.len() <= CHUNK_LEN {
+                // reading the rest and subscribing under the same lock guarantees that every record
+                // ends up either in the backlog or in the channel
+                let records = guard.read_records_at(&pending)?;
+                let rx = guard.replicate();
+                drop(guard);
+
+                for record in records {
+                    send(wtr, &record_message(record))?;
+                }
+                return Ok((rx, last));
+            }
+        }
+
+        let chunk = &pending[next..std::cmp::min(next + CHUNK_LEN, pending.len())];
+        let records = guard.read_records_at(chunk)?;
+        drop(guard);
+
+        next += chunk.len();
+        position = chunk.last().unwrap().timestamp;
+        for record in records {
+            send(wtr, &record_message(record))?;
+        }
+    }
+}
+
+impl KvStore {
+    /// Returns a channel receiving every record written from now on.
+    ///
+    /// Unlike a watch, the channel only holds so many records: once a receiver falls that far behind
+    /// it is disconnected rather than left to use up memory. `clear` disconnects every receiver.
+    pub(crate) fn replicate(&mut self) -> mpsc::Receiver<Record> {
+        let (tx, rx) = mpsc::sync_channel(REPLICA_BUFFER_LEN);
+        self.replicas.push(tx);
+        rx
+    }
+
+    pub(crate) fn notify_replicas(&mut self, record: &Record) {
+        if !self.replicas.is_empty() {
+            self.replicas.retain(|replica| replica.try_send(record.clone()).is_ok());
+        }
+    }
+
+    // The live keys, in the order of their records, index entries aside.
+    fn snapshot_keys(&self) -> Vec<String> {
+        let mut keys: Vec<(&str, u64)> = self
+            .keydir
+            .iter()
+            .filter(|(key, _)| !index::is_index_key(key))
+            .map(|(key, keyinfo)| (&key[..], keyinfo.timestamp))
+            .collect();
+        keys.sort_unstable_by_key(|&(_, timestamp)| timestamp);
+        keys.into_iter().map(|(key, _)| key.to_string()).collect()
+    }
+
+    // The live records of `keys` up to `last`; keys written or removed since come from the log.
+    fn snapshot_records(&self, keys: &[String], last: u64) -> Result<Vec<Record>> {
+        let mut records = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(keyinfo) = self.keydir.get(key) {
+                if keyinfo.timestamp <= last {
+                    records.push(self.read_record_at(keyinfo)?);
+                }
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[derive(Default)]
+struct FollowerState {
+    position: u64,
+    synced: bool,
+    finished: bool,
+}
+
+/// Applies the records streamed by a leader to a local store.
+///
+/// The store should not be written to while it is following; call `promote` to stop replication
+/// and take it over.
+pub struct Follower {
+    store: Arc<Mutex<KvStore>>,
+    stream: TcpStream,
+    state: Arc<(Mutex<FollowerState>, Condvar)>,
+    stopping: Arc<AtomicBool>,
+    handle: thread::JoinHandle<Result<()>>,
+}
+
+impl Follower {
+    pub fn connect<A: ToSocketAddrs>(addr: A, store: Arc<Mutex<KvStore>>) -> Result<Follower> {
+        let stream = TcpStream::connect(addr)?;
+        let position = store.lock().unwrap().last_timestamp();
+
+        let state = Arc::new((
+            Mutex::new(FollowerState {
+                position,
+                ..Default::default()
+            }),
+            Condvar::new(),
+        ));
+        let stopping = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let stream = stream.try_clone()?;
+            let store = store.clone();
+            let state = state.clone();
+            let stopping = stopping.clone();
+            thread::spawn(move || {
+                let result = follow(stream, &store, &state);
+
+                let (lock, cvar) = &*state;
+                lock.lock().unwrap().finished = true;
+                cvar.notify_all();
+
+                if stopping.load(Ordering::SeqCst) {
+                    Ok(())
+                } else {
+                    result
+                }
+            })
+        };
+
+        Ok(Follower {
+            store,
+            stream,
+            state,
+            stopping,
+            handle,
+        })
+    }
+
+    /// Timestamp of the last record applied from the leader.
+    pub fn position(&self) -> u64 {
+        self.state.0.lock().unwrap().position
+    }
+
+    pub fn store(&self) -> Arc<Mutex<KvStore>> {
+        self.store.clone()
+    }
+
+    /// Blocks until the backlog the leader had at connection time has been applied. Returns `false`
+    /// if the connection ended first.
+    pub fn wait_synced(&self) -> bool {
+        let (lock, cvar) = &*self.state;
+        let state = cvar
+            .wait_while(lock.lock().unwrap(), |state| !state.synced && !state.finished)
+            .unwrap();
+        state.synced
+    }
+
+    /// Blocks until the follower has applied `position`, giving up after `timeout`.
+    pub fn wait_for(&self, position: u64, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (lock, cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+
+        while state.position < position && !state.finished {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = cvar.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        state.position >= position
+    }
+
+    /// Blocks until the leader closes the connection.
+    pub fn join(self) -> Result<Arc<Mutex<KvStore>>> {
+        self.handle.join().expect("replication thread panicked")?;
+        Ok(self.store)
+    }
+
+    /// Stops replicating and hands the store over for reads and writes.
+    pub fn promote(self) -> Result<Arc<Mutex<KvStore>>> {
+        self.stopping.store(true, Ordering::SeqCst);
+        let _ = self.stream.shutdown(Shutdown::Both);
+        self.join()
+    }
+}
+
+fn follow(stream: TcpStream, store: &Mutex<KvStore>, state: &(Mutex<FollowerState>, Condvar)) -> Result<()> {
+    let mut rdr = io::BufReader::new(stream.try_clone()?);
+    let mut wtr = stream;
+    let (lock, cvar) = state;
+
+    let position = lock.lock().unwrap().position;
+    send(&mut wtr, &Message::Hello { position })?;
+
+    while let Some(message) = receive(&mut rdr)? {
+        let position = match message {
+            Message::Snapshot { position } => {
+                store.lock().unwrap().reset(position)?;
+                continue;
+            }
+            Message::Record {
+                mut record,
+                written_at,
+                expires_at,
+            } => {
+                record.written_at = written_at;
+                record.expires_at = expires_at;
+                let mut store = store.lock().unwrap();
+                store.apply_record(&record)?;
+                store.last_timestamp()
+            }
+            Message::Synced { position } => {
+                lock.lock().unwrap().synced = true;
+                position
+            }
+            message => return Err(KvsError::Protocol(format!("unexpected message {:?}", message))),
+        };
+
+        lock.lock().unwrap().position = position;
+        cvar.notify_all();
+
+        // acknowledge once the records received so far have all been applied
+        if rdr.buffer().is_empty() {
+            send(&mut wtr, &Message::Ack { position })?;
+        }
+    }
+
+    Ok(())
+}
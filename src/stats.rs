@@ -0,0 +1,145 @@
+use crate::keydir::segment_file_name;
+use crate::record::RecordRef;
+use crate::KvStore;
+use std::time::{Duration, SystemTime};
+
+/// Space accounting for one segment, kept up to date as records are written and compacted.
+///
+/// Tombstones count as dead, except those compaction had to keep because an older record of their
+/// key may survive in another segment.
+#[derive(Debug, Clone)]
+pub(crate) struct SegmentUsage {
+    pub(crate) records: u64,
+    pub(crate) tombstones: u64,
+    pub(crate) total_bytes: u64,
+    pub(crate) live_bytes: u64,
+    pub(crate) min_timestamp: u64,
+    pub(crate) max_timestamp: u64,
+}
+
+impl Default for SegmentUsage {
+    fn default() -> Self {
+        SegmentUsage {
+            records: 0,
+            tombstones: 0,
+            total_bytes: 0,
+            live_bytes: 0,
+            min_timestamp: u64::MAX,
+            max_timestamp: 0,
+        }
+    }
+}
+
+impl SegmentUsage {
+    pub(crate) fn add(&mut self, record: RecordRef, record_len: u64) {
+        self.records += 1;
+        self.total_bytes += record_len;
+        self.min_timestamp = std::cmp::min(self.min_timestamp, record.timestamp);
+        self.max_timestamp = std::cmp::max(self.max_timestamp, record.timestamp);
+        if record.tombstone == 1 {
+            self.tombstones += 1;
+        } else {
+            self.live_bytes += record_len;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentStats {
+    pub file_name: String,
+    pub records: u64,
+    pub tombstones: u64,
+    pub total_bytes: u64,
+    /// Bytes taken by the latest value of each key.
+    pub live_bytes: u64,
+    /// Bytes taken by overwritten values and tombstones, which compaction can reclaim.
+    pub dead_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub finished_at: SystemTime,
+    pub duration: Duration,
+    pub bytes_reclaimed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub live_keys: u64,
+    /// Oldest segment first; the last one is the segment being written to.
+    pub segments: Vec<SegmentStats>,
+    /// The last compaction since the store was opened.
+    pub last_compaction: Option<CompactionStats>,
+    /// An estimate of the memory taken by the in-memory index of keys.
+    pub keydir_bytes: u64,
+    /// Lookups served by the value cache since the store was opened, 0 without a cache.
+    pub cache_hits: u64,
+    /// Lookups the value cache could not serve since the store was opened.
+    pub cache_misses: u64,
+    /// Bytes of values held by the value cache.
+    pub cache_bytes: u64,
+    /// Bytes in value log files, including values that garbage collection has yet to reclaim.
+    pub value_log_bytes: u64,
+    /// Bytes taken by the live record of each key and its value in the value log, which
+    /// `Options::max_live_bytes` bounds.
+    pub live_data_bytes: u64,
+    /// Keys removed since the store was opened to keep it under `Options::max_live_bytes`.
+    pub evictions: u64,
+}
+
+impl Stats {
+    pub fn total_bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.total_bytes).sum()
+    }
+
+    pub fn live_bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.live_bytes).sum()
+    }
+
+    pub fn dead_bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.dead_bytes).sum()
+    }
+
+    pub fn tombstones(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.tombstones).sum()
+    }
+}
+
+impl KvStore {
+    /// Reports how the store uses its segments. This does not touch the disk.
+    pub fn stats(&self) -> Stats {
+        let segments = self
+            .segments
+            .iter()
+            .map(|segment_id| {
+                let usage = self.segment_usage.get(segment_id).cloned().unwrap_or_default();
+                SegmentStats {
+                    file_name: segment_file_name(*segment_id),
+                    records: usage.records,
+                    tombstones: usage.tombstones,
+                    total_bytes: usage.total_bytes,
+                    live_bytes: usage.live_bytes,
+                    dead_bytes: usage.total_bytes - usage.live_bytes,
+                }
+            })
+            .collect();
+
+        let (cache_hits, cache_misses, cache_bytes) = match self.cache.as_ref() {
+            Some(cache) => (cache.hits, cache.misses, cache.bytes() as u64),
+            None => (0, 0, 0),
+        };
+
+        Stats {
+            live_keys: self.keydir.len() as u64,
+            keydir_bytes: self.keydir.memory_usage() as u64,
+            segments,
+            last_compaction: self.last_compaction.clone(),
+            cache_hits,
+            cache_misses,
+            cache_bytes,
+            value_log_bytes: self.value_log.bytes(),
+            live_data_bytes: self.keydir.record_bytes(),
+            evictions: self.evictions,
+        }
+    }
+}
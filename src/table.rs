@@ -0,0 +1,147 @@
+//! Typed tables of serde keys and values, stored in a namespace of their own.
+
+use crate::key_encoding;
+use crate::namespace::namespace_prefix;
+use crate::{KvStore, KvsError, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/// How the values of a `Table` are serialized.
+///
+/// Binary codecs are stored base64 encoded, since values are strings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    MsgPack,
+    Bincode,
+    Json,
+}
+
+impl Codec {
+    fn encode<T: Serialize>(self, value: &T) -> Result<String> {
+        let bytes = match self {
+            Codec::MsgPack => rmp_serde::to_vec(value).map_err(|e| KvsError::Encode(e.to_string()))?,
+            Codec::Bincode => bincode::serialize(value).map_err(|e| KvsError::Encode(e.to_string()))?,
+            Codec::Json => return serde_json::to_string(value).map_err(|e| KvsError::Encode(e.to_string())),
+        };
+        Ok(STANDARD.encode(bytes))
+    }
+
+    fn decode<T: DeserializeOwned>(self, value: &str) -> Result<T> {
+        let bytes = match self {
+            Codec::Json => return serde_json::from_str(value).map_err(|e| KvsError::Decode(e.to_string())),
+            _ => STANDARD.decode(value).map_err(|e| KvsError::Decode(e.to_string()))?,
+        };
+        match self {
+            Codec::MsgPack => rmp_serde::from_slice(&bytes).map_err(|e| KvsError::Decode(e.to_string())),
+            _ => bincode::deserialize(&bytes).map_err(|e| KvsError::Decode(e.to_string())),
+        }
+    }
+}
+
+/// A map from `K` to `V` kept in the namespace of the same name, returned by `KvStore::table`.
+///
+/// Keys are encoded so that they sort like the keys themselves, which lets `range` return them in
+/// order. Each value is stored along with its key, so keys never have to be decoded. Values that
+/// fail to decode, for instance because the table was written with another codec or type, are
+/// reported as `KvsError::Decode`.
+pub struct Table<'a, K, V> {
+    store: &'a mut KvStore,
+    prefix: String,
+    codec: Codec,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl KvStore {
+    /// The table called `name`, whose values are serialized with `codec`.
+    ///
+    /// Tables live in the namespace `name`, which should not be used for anything else.
+    pub fn table<K, V>(&mut self, name: &str, codec: Codec) -> Result<Table<'_, K, V>>
+    where
+        K: Serialize + DeserializeOwned,
+        V: Serialize + DeserializeOwned,
+    {
+        Ok(Table {
+            prefix: namespace_prefix(name)?,
+            store: self,
+            codec,
+            types: PhantomData,
+        })
+    }
+}
+
+impl<'a, K, V> Table<'a, K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn get(&mut self, key: &K) -> Result<Option<V>> {
+        let key = self.key(key)?;
+        match self.store.get(key)? {
+            Some(value) => Ok(Some(self.codec.decode::<(K, V)>(&value)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&mut self, key: &K, value: &V) -> Result<()> {
+        let value = self.codec.encode(&(key, value))?;
+        let key = self.key(key)?;
+        self.store.set_unchecked(key, value)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Result<()> {
+        let key = self.key(key)?;
+        self.store.remove_unchecked(key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        Ok(self.store.contains_key(&self.key(key)?))
+    }
+
+    /// The keys within `range` and their values, in key order.
+    ///
+    /// The keydir is not ordered, so every call goes through every key of the store, not only those
+    /// of the table, and sorts the ones in range: it takes time in proportion to the size of the
+    /// whole store even for a narrow range. Only the values in range are read.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, V)>> {
+        let start = self.bound(range.start_bound())?;
+        let end = self.bound(range.end_bound())?;
+
+        let mut keys: Vec<(&str, _)> = self
+            .store
+            .keydir
+            .iter()
+            .filter_map(|(key, keyinfo)| Some((key.strip_prefix(self.prefix.as_str())?, keyinfo)))
+            .filter(|(key, _)| (start.as_ref().map(String::as_str), end.as_ref().map(String::as_str)).contains(*key))
+            .collect();
+        keys.sort_unstable_by_key(|(key, _)| *key);
+
+        keys.into_iter()
+            .map(|(_, keyinfo)| self.codec.decode(&self.store.read_record_at(keyinfo)?.value))
+            .collect()
+    }
+
+    /// Every key of the table and its value, in key order.
+    pub fn iter(&self) -> Result<Vec<(K, V)>> {
+        self.range(..)
+    }
+
+    fn key(&self, key: &K) -> Result<String> {
+        Ok(format!("{}{}", self.prefix, encode_key(key)?))
+    }
+
+    fn bound(&self, bound: Bound<&K>) -> Result<Bound<String>> {
+        Ok(match bound {
+            Bound::Included(key) => Bound::Included(encode_key(key)?),
+            Bound::Excluded(key) => Bound::Excluded(encode_key(key)?),
+            Bound::Unbounded => Bound::Unbounded,
+        })
+    }
+}
+
+fn encode_key<K: Serialize>(key: &K) -> Result<String> {
+    key_encoding::encode(key).map_err(|e| KvsError::Encode(e.to_string()))
+}
@@ -0,0 +1,202 @@
+//! Logical export and import of the live keys of a store.
+//!
+//! Both formats carry one key per row. With `ExportEncoding::Text` the fields are written as-is
+//! (`key`/`value`); with `ExportEncoding::Base64` they are base64 encoded (`key_base64`/`value_base64`)
+//! so the output survives tools that mangle arbitrary bytes. Imports accept either form.
+
+use crate::namespace;
+use crate::record::Record;
+use crate::{KvStore, KvsError, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::prelude::*;
+
+// Number of imported records appended to the log in one go.
+const IMPORT_BATCH_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per line.
+    Jsonl,
+    /// Comma separated values with a header row.
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportEncoding {
+    Text,
+    Base64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct JsonRow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_base64: Option<String>,
+}
+
+impl KvStore {
+    /// Writes every live key to `wtr`, returning the number of keys written. Keys in namespaces are
+    /// written with their namespace prefix, so importing them puts them back in their namespaces.
+    /// Index entries are left out; registering the indexes on the importing store rebuilds them.
+    pub fn export<W: Write>(&self, wtr: W, format: ExportFormat, encoding: ExportEncoding) -> Result<u64> {
+        let mut count = 0;
+
+        match format {
+            ExportFormat::Jsonl => {
+                let mut wtr = io::BufWriter::new(wtr);
+                for entry in self.iter_prefix(None) {
+                    let (key, value) = entry?;
+                    let row = match encoding {
+                        ExportEncoding::Text => JsonRow {
+                            key: Some(key),
+                            value: Some(value),
+                            ..Default::default()
+                        },
+                        ExportEncoding::Base64 => JsonRow {
+                            key_base64: Some(STANDARD.encode(key)),
+                            value_base64: Some(STANDARD.encode(value)),
+                            ..Default::default()
+                        },
+                    };
+
+                    serde_json::to_writer(&mut wtr, &row).map_err(io::Error::from)?;
+                    wtr.write_all(b"\n")?;
+                    count += 1;
+                }
+                wtr.flush()?;
+            }
+            ExportFormat::Csv => {
+                let mut wtr = csv::Writer::from_writer(wtr);
+                match encoding {
+                    ExportEncoding::Text => wtr.write_record(["key", "value"]).map_err(csv_error)?,
+                    ExportEncoding::Base64 => wtr.write_record(["key_base64", "value_base64"]).map_err(csv_error)?,
+                }
+
+                for entry in self.iter_prefix(None) {
+                    let (key, value) = entry?;
+                    match encoding {
+                        ExportEncoding::Text => wtr.write_record([key, value]).map_err(csv_error)?,
+                        ExportEncoding::Base64 => wtr
+                            .write_record([STANDARD.encode(key), STANDARD.encode(value)])
+                            .map_err(csv_error)?,
+                    }
+                    count += 1;
+                }
+                wtr.flush()?;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Sets every key read from `rdr`, returning the number of keys imported.
+    ///
+    /// Rows are appended to the log in batches; call `clear` first to replace the contents of the
+    /// store rather than merge into it.
+    pub fn import<R: Read>(&mut self, rdr: R, format: ExportFormat) -> Result<u64> {
+        let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+        let mut count = 0;
+
+        match format {
+            ExportFormat::Jsonl => {
+                for (i, line) in io::BufReader::new(rdr).lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+
+                    let row: JsonRow = serde_json::from_str(&line)
+                        .map_err(|e| KvsError::InvalidImport(format!("line {}: {}", i + 1, e)))?;
+                    let key = decode_field(row.key, row.key_base64, "key", i + 1)?;
+                    let value = decode_field(row.value, row.value_base64, "value", i + 1)?;
+
+                    self.push_import(&mut batch, key, value)?;
+                    count += 1;
+                }
+            }
+            ExportFormat::Csv => {
+                let mut rdr = csv::Reader::from_reader(rdr);
+                let base64 = match rdr.headers().map_err(csv_error)?.iter().collect::<Vec<_>>().as_slice() {
+                    ["key", "value"] => false,
+                    ["key_base64", "value_base64"] => true,
+                    headers => return Err(KvsError::InvalidImport(format!("unexpected CSV header {:?}", headers))),
+                };
+
+                for (i, row) in rdr.records().enumerate() {
+                    let row = row.map_err(csv_error)?;
+                    let line = i + 2;
+                    let (key, value) = match (row.get(0), row.get(1)) {
+                        (Some(key), Some(value)) if base64 => (
+                            decode_field(None, Some(key.to_string()), "key", line)?,
+                            decode_field(None, Some(value.to_string()), "value", line)?,
+                        ),
+                        (Some(key), Some(value)) => (key.to_string(), value.to_string()),
+                        _ => return Err(KvsError::InvalidImport(format!("line {}: expected 2 fields", line))),
+                    };
+
+                    self.push_import(&mut batch, key, value)?;
+                    count += 1;
+                }
+            }
+        }
+
+        self.flush_import(&mut batch)?;
+
+        Ok(count)
+    }
+
+    fn push_import(&mut self, batch: &mut Vec<Record>, key: String, value: String) -> Result<()> {
+        namespace::check_exported_key(&key)?;
+        batch.push(Record {
+            timestamp: self.counter + batch.len() as u64,
+            tombstone: 0,
+            key,
+            value,
+            value_pointer: None,
+            written_at: 0,
+            expires_at: 0,
+        });
+
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            self.flush_import(batch)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush_import(&mut self, batch: &mut Vec<Record>) -> Result<()> {
+        let records = self.with_index_entries(std::mem::take(batch));
+        self.write_records(&records)?;
+        self.maybe_compact()
+    }
+}
+
+fn decode_field(text: Option<String>, base64: Option<String>, name: &str, line: usize) -> Result<String> {
+    match (text, base64) {
+        (Some(text), _) => Ok(text),
+        (None, Some(base64)) => {
+            let bytes = STANDARD
+                .decode(base64)
+                .map_err(|e| KvsError::InvalidImport(format!("line {}: {}: {}", line, name, e)))?;
+            String::from_utf8(bytes)
+                .map_err(|_| KvsError::InvalidImport(format!("line {}: {} is not valid UTF-8", line, name)))
+        }
+        (None, None) => Err(KvsError::InvalidImport(format!("line {}: missing {}", line, name))),
+    }
+}
+
+fn csv_error(err: csv::Error) -> KvsError {
+    let message = err.to_string();
+    match err.into_kind() {
+        csv::ErrorKind::Io(err) => KvsError::Io(err),
+        _ => KvsError::InvalidImport(message),
+    }
+}
@@ -0,0 +1,303 @@
+use crate::format::{SegmentHeader, FORMAT_VERSION};
+use crate::history::Retention;
+use crate::reader;
+use crate::record::{self, ValuePointer, RECORD_HEADER_LEN};
+use crate::value_reader::ValueReader;
+use crate::writer;
+use crate::{create_segment_file, KvStore, KvsError, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Value log files are sealed once they grow past this many bytes.
+const VALUE_LOG_SIZE_LIMIT: u64 = 64 * 1024;
+
+pub(crate) fn value_log_file_name(file_id: u32) -> String {
+    format!("{:08}.vlog", file_id)
+}
+
+/// Large values, kept apart from the segments so compaction does not have to rewrite them.
+///
+/// Value log files are laid out like segments, with each value written as a record repeating the key
+/// and timestamp of the record pointing to it. That lets garbage collection tell from the keydir
+/// alone whether a value is still live.
+#[derive(Debug)]
+pub(crate) struct ValueLog {
+    path: PathBuf,
+    // the last file is the one being written to
+    files: BTreeMap<u32, ValueLogFile>,
+}
+
+#[derive(Debug)]
+struct ValueLogFile {
+    file: fs::File,
+    len: u64,
+}
+
+// A value found by scanning a value log file, without reading the value itself.
+struct ScannedValue {
+    key: String,
+    timestamp: u64,
+    pointer: ValuePointer,
+}
+
+impl ValueLog {
+    pub(crate) fn open(path: &Path) -> Result<ValueLog> {
+        let mut files = BTreeMap::new();
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.path().extension() != Some(OsStr::new("vlog")) {
+                continue;
+            }
+
+            // skip anything the store did not write
+            let file_name = match entry.file_name().into_string() {
+                Ok(file_name) => file_name,
+                Err(_) => continue,
+            };
+            let file_id: u32 = match file_name.trim_end_matches(".vlog").parse() {
+                Ok(file_id) => file_id,
+                Err(_) => continue,
+            };
+            let file = create_segment_file(&entry.path())?;
+            let header = SegmentHeader::read(&file)?;
+            let version = SegmentHeader::version_of(header.as_ref());
+            // value log records never carry a wall-clock time, so they are the same since version 2
+            if !(2..=FORMAT_VERSION).contains(&version) {
+                return Err(KvsError::UnsupportedFormat { file_name, version });
+            }
+
+            let len = file.metadata()?.len();
+            files.insert(file_id, ValueLogFile { file, len });
+        }
+
+        Ok(ValueLog {
+            path: path.to_path_buf(),
+            files,
+        })
+    }
+
+    /// Appends a value of `value_len` bytes read from `value` for the record of `key` written at
+    /// `timestamp`, returning where it went.
+    pub(crate) fn append(
+        &mut self,
+        timestamp: u64,
+        key: &str,
+        value: &mut dyn io::Read,
+        value_len: u32,
+    ) -> Result<ValuePointer> {
+        let file_id = match self.files.iter().next_back() {
+            Some((&file_id, active)) if active.len <= VALUE_LOG_SIZE_LIMIT => file_id,
+            Some((&file_id, _)) => self.create_file(file_id + 1)?,
+            None => self.create_file(0)?,
+        };
+
+        let active = self.files.get_mut(&file_id).unwrap();
+        let file_path = self.path.join(value_log_file_name(file_id));
+        let (record_pos, record_len) =
+            writer::stream_record(&active.file, &file_path, timestamp, key, value, value_len, 0)?;
+        active.len = record_pos + record_len;
+
+        Ok(ValuePointer {
+            file_id,
+            record_pos,
+            value_len,
+        })
+    }
+
+    fn create_file(&mut self, file_id: u32) -> Result<u32> {
+        let file = create_segment_file(&self.path.join(value_log_file_name(file_id)))?;
+        let len = file.metadata()?.len();
+        self.files.insert(file_id, ValueLogFile { file, len });
+        Ok(file_id)
+    }
+
+    /// Whether the file `pointer` points into still exists; garbage collection deletes files once
+    /// none of their values are live.
+    pub(crate) fn contains(&self, pointer: ValuePointer) -> bool {
+        self.files.contains_key(&pointer.file_id)
+    }
+
+    /// Reads the value of `key` that `pointer` points to.
+    pub(crate) fn read(&self, key: &str, pointer: ValuePointer) -> Result<String> {
+        read_value(self.file(pointer)?, key, pointer)
+    }
+
+    /// Streams the value of `key` that `pointer` points to.
+    pub(crate) fn reader(&self, key: &str, pointer: ValuePointer) -> Result<ValueReader> {
+        let file = self.file(pointer)?.try_clone()?;
+        Ok(ValueReader::open(file, pointer.record_pos, key.len())?)
+    }
+
+    fn file(&self, pointer: ValuePointer) -> Result<&fs::File> {
+        match self.files.get(&pointer.file_id) {
+            Some(value_log_file) => Ok(&value_log_file.file),
+            None => {
+                let message = format!("missing value log file {}", value_log_file_name(pointer.file_id));
+                Err(io::Error::new(io::ErrorKind::NotFound, message).into())
+            }
+        }
+    }
+
+    /// Every value log file with its length, oldest first.
+    pub(crate) fn files(&self) -> impl Iterator<Item = (String, u64)> + '_ {
+        self.files
+            .iter()
+            .map(|(&file_id, value_log_file)| (value_log_file_name(file_id), value_log_file.len))
+    }
+
+    /// Bytes in value log files, live or not.
+    pub(crate) fn bytes(&self) -> u64 {
+        self.files.values().map(|value_log_file| value_log_file.len).sum()
+    }
+
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        for value_log_file in self.files.values() {
+            value_log_file.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // Reads the key and timestamp of every value in a file, skipping over the values themselves.
+    fn scan(&self, file_id: u32) -> Result<Vec<ScannedValue>> {
+        let value_log_file = &self.files[&file_id];
+        let mut values = Vec::new();
+        let mut pos = SegmentHeader::LEN;
+        let mut header = [0; RECORD_HEADER_LEN];
+        while pos + RECORD_HEADER_LEN as u64 <= value_log_file.len {
+            reader::read_exact_at(&value_log_file.file, &mut header, pos)?;
+            let key_len = BigEndian::read_u32(&header[13..17]) as usize;
+            let value_len = record::value_len(&header);
+            let pointer = ValuePointer {
+                file_id,
+                record_pos: pos,
+                value_len,
+            };
+            if pos + pointer.record_len(key_len) > value_log_file.len {
+                break;
+            }
+
+            let mut key = vec![0; key_len];
+            reader::read_exact_at(&value_log_file.file, &mut key, pos + RECORD_HEADER_LEN as u64)?;
+            let key = String::from_utf8(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            values.push(ScannedValue {
+                key,
+                timestamp: BigEndian::read_u64(&header[4..12]),
+                pointer,
+            });
+            pos += pointer.record_len(key_len);
+        }
+
+        Ok(values)
+    }
+
+    fn remove(&mut self, file_id: u32) -> Result<()> {
+        self.files.remove(&file_id);
+        fs::remove_file(self.path.join(value_log_file_name(file_id)))?;
+        Ok(())
+    }
+
+    /// Deletes every value log file.
+    pub(crate) fn clear(&mut self) -> Result<()> {
+        let file_ids: Vec<u32> = self.files.keys().copied().collect();
+        for file_id in file_ids {
+            self.remove(file_id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the value of `key` that `pointer` points to in `file`, the value log file it names.
+pub(crate) fn read_value(file: &fs::File, key: &str, pointer: ValuePointer) -> Result<String> {
+    let mut buf = vec![0; pointer.record_len(key.len()) as usize];
+    reader::read_exact_at(file, &mut buf, pointer.record_pos)?;
+
+    let record = record::decode(&buf)?;
+    if record.key != key || record.value_pointer.is_some() {
+        let message = "value log record does not match its pointer";
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+    }
+    Ok(record.value.to_string())
+}
+
+impl KvStore {
+    /// Reclaims the space taken by overwritten and removed values in the value log, returning the
+    /// number of bytes reclaimed.
+    ///
+    /// Each sealed value log file with more than `max_garbage_ratio` of its bytes dead has its live
+    /// values, and those of the past versions the retention keeps, moved to the end of the log, then
+    /// is deleted. Moving a value rewrites the record of its key as it was, with the same timestamp and
+    /// write time, only pointing at the new copy; it is not a change, so neither watchers nor
+    /// `changes_since` see it. This is independent of compaction,
+    /// which only ever copies the pointers to values in the value log.
+    pub fn collect_value_log(&mut self, max_garbage_ratio: f64) -> Result<u64> {
+        let sealed: Vec<u32> = self.value_log.files.keys().rev().skip(1).copied().collect();
+
+        let mut bytes_reclaimed = 0;
+        for file_id in sealed.into_iter().rev() {
+            let live: Vec<ScannedValue> = self
+                .value_log
+                .scan(file_id)?
+                .into_iter()
+                .filter(|value| {
+                    let live = self.keydir.get(&value.key).map(|keyinfo| keyinfo.timestamp);
+                    live == Some(value.timestamp)
+                        || self.options.retention != Retention::Latest
+                            && self
+                                .history
+                                .retains(self.options.retention, &value.key, value.timestamp, live)
+                })
+                .collect();
+
+            let total_bytes = self.value_log.files[&file_id].len;
+            let live_bytes: u64 = live.iter().map(|value| value.pointer.record_len(value.key.len())).sum();
+            let dead_bytes = total_bytes - SegmentHeader::LEN - live_bytes;
+            if dead_bytes == 0 || dead_bytes as f64 <= max_garbage_ratio * (total_bytes - SegmentHeader::LEN) as f64 {
+                continue;
+            }
+
+            // the moved records are appended under timestamps already handed out
+            self.rewrites += 1;
+            for value in live {
+                let mut rdr = self.value_log.reader(&value.key, value.pointer)?;
+                match self.keydir.get(&value.key).copied() {
+                    Some(keyinfo) if keyinfo.timestamp == value.timestamp => {
+                        let record = self.read_raw_record_at(&keyinfo)?;
+                        self.write_to_value_log(record.as_record_ref(), &mut rdr, value.pointer.value_len)?;
+                    }
+                    _ => {
+                        // a past version the retention keeps goes on being one
+                        let old = self.history.version(&value.key, value.timestamp).unwrap().keyinfo;
+                        let record = self.read_raw_record_at(&old)?;
+                        let keyinfo =
+                            self.append_to_value_log(record.as_record_ref(), &mut rdr, value.pointer.value_len)?;
+                        let usage = self.segment_usage.entry(keyinfo.segment_id).or_default();
+                        usage.add(record.as_record_ref(), u64::from(keyinfo.record_len));
+                        if let Some(usage) = self.segment_usage.get_mut(&old.segment_id) {
+                            usage.live_bytes -= u64::from(old.record_len);
+                        }
+                        self.history.moved(
+                            &value.key,
+                            value.timestamp,
+                            keyinfo.segment_id,
+                            keyinfo.record_pos,
+                            keyinfo.record_len,
+                        );
+                    }
+                }
+            }
+
+            // the moved values must be on disk before the only other copy goes
+            self.value_log.sync()?;
+            self.sync_active_segment()?;
+            self.value_log.remove(file_id)?;
+            bytes_reclaimed += total_bytes - live_bytes;
+        }
+
+        self.maybe_compact()?;
+        Ok(bytes_reclaimed)
+    }
+}
@@ -0,0 +1,96 @@
+use crate::reader;
+use crate::record::{self, RECORD_HEADER_LEN};
+use byteorder::{BigEndian, ByteOrder};
+use std::fs;
+use std::io;
+
+/// Reads a value straight from its segment, returned by `KvStore::get_reader`.
+///
+/// The record's checksum is checked once the whole value has been read, so a corrupt value fails
+/// the last read rather than the first.
+#[derive(Debug)]
+pub struct ValueReader {
+    inner: Inner,
+}
+
+#[derive(Debug)]
+enum Inner {
+    Segment {
+        file: fs::File,
+        pos: u64,
+        remaining: u64,
+        // bytes following the value, which only go into the checksum
+        trailer_len: u64,
+        hasher: crc32fast::Hasher,
+        crc: u32,
+    },
+    // values in older formats are decoded whole
+    Memory(io::Cursor<Vec<u8>>),
+}
+
+impl ValueReader {
+    /// Starts reading the value of the record at `record_pos` in `file`, whose key is `key_len`
+    /// bytes long.
+    pub(crate) fn open(file: fs::File, record_pos: u64, key_len: usize) -> io::Result<ValueReader> {
+        let mut head = vec![0; RECORD_HEADER_LEN + key_len];
+        reader::read_exact_at(&file, &mut head, record_pos)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&head[4..]);
+        let value_len = u64::from(record::value_len(&head));
+        Ok(ValueReader {
+            inner: Inner::Segment {
+                pos: record_pos + head.len() as u64,
+                remaining: value_len,
+                trailer_len: record::body_len(&head) - key_len as u64 - value_len,
+                crc: BigEndian::read_u32(&head[..4]),
+                file,
+                hasher,
+            },
+        })
+    }
+
+    pub(crate) fn from_value(value: String) -> ValueReader {
+        ValueReader {
+            inner: Inner::Memory(io::Cursor::new(value.into_bytes())),
+        }
+    }
+}
+
+impl io::Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (file, pos, remaining, trailer_len, hasher, crc) = match self.inner {
+            Inner::Segment {
+                ref file,
+                ref mut pos,
+                ref mut remaining,
+                trailer_len,
+                ref mut hasher,
+                crc,
+            } => (file, pos, remaining, trailer_len, hasher, crc),
+            Inner::Memory(ref mut cursor) => return cursor.read(buf),
+        };
+        if *remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = std::cmp::min(*remaining, buf.len() as u64) as usize;
+        reader::read_exact_at(file, &mut buf[..n], *pos)?;
+        hasher.update(&buf[..n]);
+        *pos += n as u64;
+        *remaining -= n as u64;
+
+        if *remaining == 0 {
+            let mut hasher = hasher.clone();
+            if trailer_len > 0 {
+                let mut trailer = vec![0; trailer_len as usize];
+                reader::read_exact_at(file, &mut trailer, *pos)?;
+                hasher.update(&trailer);
+            }
+            if hasher.finalize() != crc {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "record checksum mismatch"));
+            }
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,414 @@
+//! Offline consistency checks for a store directory.
+//!
+//! Nothing here opens the store, so it works on directories `KvStore::open` would reject.
+
+use crate::checkpoint::ensure_no_store;
+use crate::format::{SegmentHeader, FORMAT_VERSION, MIN_READABLE_VERSION};
+use crate::reader::decode_record_at;
+use crate::record::{self, Record, RECORD_HEADER_LEN};
+use crate::value_log::{read_value, value_log_file_name};
+use crate::{KvStore, KvsError, Result};
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// The segment ends in the middle of a record.
+    Truncated,
+    /// The bytes do not decode as a record.
+    Undecodable,
+}
+
+/// A range of bytes in a segment that could not be read as records.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corruption {
+    pub offset: u64,
+    pub len: u64,
+    pub kind: CorruptionKind,
+}
+
+#[derive(Debug, Default)]
+pub struct SegmentReport {
+    pub file_name: String,
+    /// Format version of the segment, 0 if it predates headers.
+    pub version: u16,
+    pub records: u64,
+    pub tombstones: u64,
+    /// Bytes taken by the latest record of each live key.
+    pub live_bytes: u64,
+    /// Bytes taken by overwritten records and tombstones.
+    pub stale_bytes: u64,
+    pub corruptions: Vec<Corruption>,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub segments: Vec<SegmentReport>,
+    /// Leftovers of a compaction that did not finish.
+    pub orphan_merge_files: Vec<PathBuf>,
+    /// Timestamps carried by more than one record.
+    pub duplicate_timestamps: Vec<u64>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.orphan_merge_files.is_empty()
+            && self.duplicate_timestamps.is_empty()
+            && self.segments.iter().all(|segment| segment.corruptions.is_empty())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for segment in self.segments.iter() {
+            writeln!(
+                f,
+                "{}: {} records ({} tombstones), {} bytes live, {} bytes stale",
+                segment.file_name, segment.records, segment.tombstones, segment.live_bytes, segment.stale_bytes
+            )?;
+            if segment.version < MIN_READABLE_VERSION {
+                writeln!(
+                    f,
+                    "{}: format version {}, run `kvs migrate` before opening the store",
+                    segment.file_name, segment.version
+                )?;
+            }
+            for corruption in segment.corruptions.iter() {
+                let kind = match corruption.kind {
+                    CorruptionKind::Truncated => "truncated record",
+                    CorruptionKind::Undecodable => "undecodable bytes",
+                };
+                writeln!(
+                    f,
+                    "{}: {} at offset {} ({} bytes)",
+                    segment.file_name, kind, corruption.offset, corruption.len
+                )?;
+            }
+        }
+
+        for path in self.orphan_merge_files.iter() {
+            writeln!(f, "orphan merge file {}", path.display())?;
+        }
+        for timestamp in self.duplicate_timestamps.iter() {
+            writeln!(f, "duplicate timestamp {}", timestamp)?;
+        }
+
+        if self.is_ok() {
+            write!(f, "no problems found")
+        } else {
+            write!(f, "problems found")
+        }
+    }
+}
+
+struct ScannedSegment {
+    file_name: String,
+    version: u16,
+    records: Vec<ScannedRecord>,
+    corruptions: Vec<Corruption>,
+}
+
+struct ScannedRecord {
+    offset: u64,
+    len: u64,
+    record: Record,
+    // points into a value log file garbage collection has deleted, after moving the value to a new
+    // record under the same timestamp if it was live or retained
+    orphaned: bool,
+}
+
+// How far past a corrupt record the next valid one is looked for at each offset. Checking a
+// candidate costs as much as the record it claims to be, so bounding it keeps the search linear in
+// the length of the damaged region; a longer record right after the damage is counted as part of it.
+const RESYNC_WINDOW: u64 = 64 * 1024;
+
+// The bytes of a segment being scanned front to back, read in as the record at hand needs them and
+// dropped once they are behind it.
+struct Window<R> {
+    rdr: R,
+    version: u16,
+    // length of the segment
+    len: u64,
+    // offset in the segment of the first byte in `buf`
+    start: u64,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> Window<R> {
+    // Makes the window reach `end`, or the end of the segment if that comes first.
+    fn fill_to(&mut self, end: u64) -> io::Result<()> {
+        let end = std::cmp::min(end, self.len);
+        let have = self.start + self.buf.len() as u64;
+        if end > have {
+            (&mut self.rdr).take(end - have).read_to_end(&mut self.buf)?;
+        }
+        Ok(())
+    }
+
+    // Lets go of the bytes before `offset`, a window's worth at a time.
+    fn advance_to(&mut self, offset: u64) {
+        let behind = std::cmp::min(offset - self.start, self.buf.len() as u64);
+        if behind >= RESYNC_WINDOW {
+            self.buf.drain(..behind as usize);
+            self.start += behind;
+        }
+    }
+
+    // The length the record at `offset` claims to have, `None` if the segment ends within its header.
+    fn claimed_len(&mut self, offset: u64) -> io::Result<Option<u64>> {
+        let header_len = if self.version < 2 { 8 } else { RECORD_HEADER_LEN as u64 };
+        self.fill_to(offset + header_len)?;
+
+        let pos = (offset - self.start) as usize;
+        let header = match self.buf.get(pos..pos + header_len as usize) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        Ok(Some(if self.version < 2 {
+            header_len + BigEndian::read_u64(header)
+        } else {
+            header_len + record::body_len(header)
+        }))
+    }
+
+    // Decodes the record at `offset` if it is valid and no longer than `max_len`.
+    fn record_at(&mut self, offset: u64, max_len: u64) -> io::Result<Option<(Record, u64)>> {
+        let len = match self.claimed_len(offset)? {
+            Some(len) if len <= max_len && len <= self.len - offset => len,
+            _ => return Ok(None),
+        };
+        self.fill_to(offset + len)?;
+
+        let pos = offset - self.start;
+        Ok(decode_record_at(&self.buf[..(pos + len) as usize], pos, self.version).ok())
+    }
+}
+
+fn scan_segment<R: Read>(
+    rdr: R,
+    len: u64,
+    start: u64,
+    version: u16,
+) -> io::Result<(Vec<ScannedRecord>, Vec<Corruption>)> {
+    let mut records = Vec::new();
+    let mut corruptions = Vec::new();
+    let mut window = Window {
+        rdr,
+        version,
+        len,
+        start,
+        buf: Vec::new(),
+    };
+    let mut offset = start;
+
+    while offset < len {
+        if let Some((record, record_len)) = window.record_at(offset, len - offset)? {
+            records.push(ScannedRecord {
+                offset,
+                len: record_len,
+                record,
+                orphaned: false,
+            });
+            offset += record_len;
+            window.advance_to(offset);
+            continue;
+        }
+
+        let claims_past_end = window
+            .claimed_len(offset)?
+            .is_none_or(|claimed_len| claimed_len > len - offset);
+
+        // resynchronize on the next offset holding a valid record
+        let mut next = offset + 1;
+        while next < len && window.record_at(next, RESYNC_WINDOW)?.is_none() {
+            next += 1;
+            window.advance_to(next);
+        }
+
+        let kind = if next == len && claims_past_end {
+            CorruptionKind::Truncated
+        } else {
+            CorruptionKind::Undecodable
+        };
+        corruptions.push(Corruption {
+            offset,
+            len: next - offset,
+            kind,
+        });
+
+        offset = next;
+    }
+
+    Ok((records, corruptions))
+}
+
+fn scan_dir(path: &Path) -> Result<(Vec<ScannedSegment>, Vec<PathBuf>)> {
+    let mut segments = Vec::new();
+    let mut orphan_merge_files = Vec::new();
+    let mut value_log_files = HashSet::new();
+
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+
+        if entry_path.extension() == Some(OsStr::new("bcd")) {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let mut file = fs::File::open(&entry_path)?;
+            let len = file.metadata()?.len();
+            let (version, records, corruptions) = match SegmentHeader::read(&file) {
+                Ok(header) => {
+                    let start = SegmentHeader::records_start(header.as_ref());
+                    let version = SegmentHeader::version_of(header.as_ref());
+                    file.seek(io::SeekFrom::Start(start))?;
+                    let (records, corruptions) = scan_segment(io::BufReader::new(file), len, start, version)?;
+                    (version, records, corruptions)
+                }
+                Err(_) => {
+                    let corruption = Corruption {
+                        offset: 0,
+                        len,
+                        kind: CorruptionKind::Truncated,
+                    };
+                    (FORMAT_VERSION, Vec::new(), vec![corruption])
+                }
+            };
+            segments.push(ScannedSegment {
+                file_name,
+                version,
+                records,
+                corruptions,
+            });
+        } else if entry_path.extension() == Some(OsStr::new("merge")) {
+            orphan_merge_files.push(entry_path);
+        } else if entry_path.extension() == Some(OsStr::new("vlog")) {
+            value_log_files.insert(entry.file_name());
+        }
+    }
+
+    for scanned in segments.iter_mut().flat_map(|segment| segment.records.iter_mut()) {
+        if let Some(pointer) = scanned.record.value_pointer {
+            scanned.orphaned = !value_log_files.contains(OsStr::new(&value_log_file_name(pointer.file_id)));
+        }
+    }
+
+    segments.sort_by(|s1, s2| s1.file_name.cmp(&s2.file_name));
+    orphan_merge_files.sort();
+
+    Ok((segments, orphan_merge_files))
+}
+
+/// Reads every segment in `path` record by record and reports what is wrong with them.
+pub fn verify(path: &Path) -> Result<Report> {
+    let (segments, orphan_merge_files) = scan_dir(path)?;
+
+    // the latest record of each key decides what is live
+    let mut latest: HashMap<&str, (u64, usize, u64)> = HashMap::new();
+    let mut seen: HashMap<u64, u64> = HashMap::new();
+
+    for (i, scanned_segment) in segments.iter().enumerate() {
+        for scanned in scanned_segment.records.iter().filter(|scanned| !scanned.orphaned) {
+            let record = &scanned.record;
+
+            let newer = latest
+                .get(record.key.as_str())
+                .is_none_or(|&(timestamp, _, _)| record.timestamp > timestamp);
+            if newer {
+                latest.insert(&record.key, (record.timestamp, i, scanned.offset));
+            }
+
+            *seen.entry(record.timestamp).or_insert(0) += 1;
+        }
+    }
+
+    let mut report = Report {
+        orphan_merge_files,
+        ..Default::default()
+    };
+
+    report.duplicate_timestamps = seen
+        .into_iter()
+        .filter(|&(_, count)| count > 1)
+        .map(|(timestamp, _)| timestamp)
+        .collect();
+    report.duplicate_timestamps.sort_unstable();
+
+    for (i, scanned_segment) in segments.iter().enumerate() {
+        let mut segment = SegmentReport {
+            file_name: scanned_segment.file_name.clone(),
+            version: scanned_segment.version,
+            corruptions: scanned_segment.corruptions.clone(),
+            ..Default::default()
+        };
+
+        for scanned in scanned_segment.records.iter() {
+            let record = &scanned.record;
+            segment.records += 1;
+
+            if record.tombstone == 1 {
+                segment.tombstones += 1;
+                segment.stale_bytes += scanned.len;
+            } else if latest.get(record.key.as_str()) == Some(&(record.timestamp, i, scanned.offset)) {
+                segment.live_bytes += scanned.len;
+            } else {
+                segment.stale_bytes += scanned.len;
+            }
+        }
+
+        report.segments.push(segment);
+    }
+
+    Ok(report)
+}
+
+/// Writes the live keys among every record that can still be decoded in `path` into a new store at
+/// `dest`, returning the number of keys salvaged.
+pub fn repair(path: &Path, dest: &Path) -> Result<u64> {
+    let (segments, _) = scan_dir(path)?;
+
+    let mut latest: HashMap<String, Record> = HashMap::new();
+    for scanned_segment in segments {
+        for scanned in scanned_segment.records.into_iter().filter(|scanned| !scanned.orphaned) {
+            let record = scanned.record;
+            let newer = latest
+                .get(&record.key)
+                .is_none_or(|current| record.timestamp > current.timestamp);
+            if newer {
+                latest.insert(record.key.clone(), record);
+            }
+        }
+    }
+
+    let mut live: Vec<Record> = latest.into_values().filter(|record| record.tombstone == 0).collect();
+    // values in the value log are salvaged along with their keys, unless they are damaged too
+    live.retain_mut(|record| match record.value_pointer.take() {
+        Some(pointer) => {
+            let value = fs::File::open(path.join(value_log_file_name(pointer.file_id)))
+                .map_err(KvsError::from)
+                .and_then(|file| read_value(&file, &record.key, pointer));
+            match value {
+                Ok(value) => {
+                    record.value = value;
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+        None => true,
+    });
+    live.sort_by_key(|record| record.timestamp);
+
+    fs::create_dir_all(dest)?;
+    ensure_no_store(dest)?;
+
+    let mut store = KvStore::open(dest)?;
+    store.write_records(&live)?;
+    store.maybe_compact()?;
+
+    Ok(live.len() as u64)
+}
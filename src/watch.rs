@@ -0,0 +1,165 @@
+use crate::format::{SegmentHeader, FORMAT_VERSION};
+use crate::index;
+use crate::reader;
+use crate::record::Record;
+use crate::value_log::{self, value_log_file_name};
+use crate::{KvsError, Result};
+use std::collections::hash_map::{Entry, HashMap};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A change made to the store by a `set` (`value` is `Some`) or a `remove` (`value` is `None`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    pub key: String,
+    pub value: Option<String>,
+    pub timestamp: u64,
+}
+
+impl From<Record> for WatchEvent {
+    fn from(record: Record) -> Self {
+        WatchEvent {
+            value: if record.tombstone == 1 {
+                None
+            } else {
+                Some(record.value)
+            },
+            key: record.key,
+            timestamp: record.timestamp,
+        }
+    }
+}
+
+impl From<WatchEvent> for Record {
+    fn from(event: WatchEvent) -> Self {
+        Record {
+            timestamp: event.timestamp,
+            tombstone: event.value.is_none() as u8,
+            key: event.key,
+            value: event.value.unwrap_or_default(),
+            value_pointer: None,
+            written_at: 0,
+            expires_at: 0,
+        }
+    }
+}
+
+/// Follows the changes another process makes to the store in a directory, by reading its segment
+/// and value log files as they are written.
+///
+/// Unlike opening the store, tailing it never creates or writes anything in the directory, and does
+/// not build a keydir.
+#[derive(Debug)]
+pub struct Tail {
+    path: PathBuf,
+    position: u64,
+}
+
+impl Tail {
+    /// Starts after the latest change already in the log.
+    pub fn new(path: &Path) -> Result<Tail> {
+        let mut tail = Tail::since(path, 0);
+        tail.position = tail.scan(false)?.1;
+        Ok(tail)
+    }
+
+    /// Starts after the change at `timestamp`.
+    pub fn since(path: &Path, timestamp: u64) -> Tail {
+        Tail {
+            path: path.to_path_buf(),
+            position: timestamp,
+        }
+    }
+
+    /// The timestamp of the last change read.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Returns the changes made since the last call, oldest first.
+    ///
+    /// Fails with `KvsError::Compacted` if compaction has already discarded some of them, in which
+    /// case the next call picks up after what was lost.
+    pub fn poll(&mut self) -> Result<Vec<WatchEvent>> {
+        let horizon = crate::read_horizon(&self.path)?.unwrap_or(0);
+        if self.position < horizon {
+            self.position = horizon;
+            return Err(KvsError::Compacted(horizon));
+        }
+
+        let (records, last) = self.scan(true)?;
+
+        let mut value_logs: HashMap<u32, fs::File> = HashMap::new();
+        let mut events = Vec::with_capacity(records.len());
+        for mut record in records {
+            // garbage collection moves a value by rewriting its record under the same timestamp, and
+            // a compaction running meanwhile may have moved a record into a file read later
+            if events
+                .last()
+                .is_some_and(|event: &WatchEvent| event.timestamp == record.timestamp)
+            {
+                continue;
+            }
+            if let Some(pointer) = record.value_pointer.take() {
+                let file = match value_logs.entry(pointer.file_id) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        match fs::File::open(self.path.join(value_log_file_name(pointer.file_id))) {
+                            Ok(file) => entry.insert(file),
+                            // garbage collected, leaving a later record or the moved one
+                            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                };
+                record.value = value_log::read_value(file, &record.key, pointer)?;
+            }
+            events.push(WatchEvent::from(record));
+        }
+
+        self.position = last;
+        Ok(events)
+    }
+
+    // Returns the timestamp of the last record in the log and, if `keep` is set, every record after the
+    // position, oldest first and index entries aside.
+    fn scan(&self, keep: bool) -> Result<(Vec<Record>, u64)> {
+        let mut records = Vec::new();
+        let mut last = self.position;
+
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let file_path = entry.path();
+            if file_path.extension() != Some(OsStr::new("bcd")) {
+                continue;
+            }
+
+            let file = fs::File::open(&file_path)?;
+            let header = SegmentHeader::read(&file)?;
+            let version = SegmentHeader::version_of(header.as_ref());
+            if version > FORMAT_VERSION {
+                return Err(KvsError::UnsupportedFormat {
+                    file_name: entry.file_name().to_string_lossy().into_owned(),
+                    version,
+                });
+            }
+
+            let mut reader = reader::Reader::new(file, version);
+            let mut next_offset = SegmentHeader::records_start(header.as_ref());
+            while let Some(record) = reader.read_record_ref(io::SeekFrom::Start(next_offset), &mut next_offset)? {
+                if record.timestamp <= self.position {
+                    continue;
+                }
+                last = std::cmp::max(last, record.timestamp);
+                if keep && !index::is_index_key(record.key) {
+                    records.push(record.to_record());
+                }
+            }
+        }
+
+        records.sort_by_key(|record| record.timestamp);
+        Ok((records, last))
+    }
+}
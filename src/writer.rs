@@ -1,31 +1,157 @@
-use crate::record::Record;
-use byteorder::{BigEndian, WriteBytesExt};
-use rmp_serde::{Deserializer, Serializer};
-use serde::{Deserialize, Serialize};
+use crate::record::{self, RecordRef, RECORD_HEADER_LEN};
+use byteorder::{BigEndian, ByteOrder};
+use std::fs;
 use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::str;
 
+/// Writes records in the current format.
 #[derive(Debug)]
 pub(crate) struct Writer<W: io::Write> {
     wtr: W,
+    buf: Vec<u8>,
 }
 
 impl<W: io::Write> Writer<W> {
     pub fn new(wtr: W) -> Writer<W> {
-        Writer { wtr }
+        Writer { wtr, buf: Vec::new() }
     }
 
-    pub fn write_record(&mut self, record: &Record) -> io::Result<()> {
-        let mut buf_record = Vec::new();
+    /// Returns the number of bytes written.
+    pub fn write_record_ref(&mut self, record: RecordRef) -> io::Result<u64> {
+        self.buf.clear();
+        record::encode(record, &mut self.buf);
+        self.wtr.write_all(&self.buf)?;
 
-        record.serialize(&mut Serializer::new(&mut buf_record)).unwrap();
+        Ok(self.buf.len() as u64)
+    }
+
+    /// Writes a record whose value of `value_len` bytes is read from `value` a chunk at a time,
+    /// failing if it runs out early or is not UTF-8. Returns the number of bytes written and the
+    /// record's checksum. A `written_at` of 0 is left out of the record.
+    ///
+    /// The checksum leads the record but covers the value, so it is written as zeros for the caller
+    /// to fill in once the rest of the record is in place.
+    pub fn write_streamed_record(
+        &mut self,
+        timestamp: u64,
+        key: &str,
+        value: &mut dyn io::Read,
+        value_len: u32,
+        written_at: u64,
+    ) -> io::Result<(u64, u32)> {
+        let mut header = [0; RECORD_HEADER_LEN];
+        record::encode_header(timestamp, 0, key.len() as u32, value_len, written_at, &mut header);
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header[4..]);
+        hasher.update(key.as_bytes());
+        self.wtr.write_all(&header)?;
+        self.wtr.write_all(key.as_bytes())?;
+
+        self.buf.resize(64 * 1024, 0);
+        let mut remaining = u64::from(value_len);
+        // the start of a character split across two chunks, carried over to the next one
+        let mut pending = 0;
+        while remaining > 0 {
+            let limit = std::cmp::min(remaining, (self.buf.len() - pending) as u64) as usize;
+            let n = match value.read(&mut self.buf[pending..pending + limit]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "value shorter than its length",
+                    ))
+                }
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            remaining -= n as u64;
+
+            let chunk = &self.buf[..pending + n];
+            let valid = match str::from_utf8(chunk) {
+                Ok(_) => chunk.len(),
+                Err(e) if e.error_len().is_none() && remaining > 0 => e.valid_up_to(),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            };
+            hasher.update(&chunk[..valid]);
+            self.wtr.write_all(&chunk[..valid])?;
+
+            pending = chunk.len() - valid;
+            self.buf.copy_within(valid..valid + pending, 0);
+        }
+
+        let mut record_len = (RECORD_HEADER_LEN + key.len()) as u64 + u64::from(value_len);
+        if written_at != 0 {
+            hasher.update(&written_at.to_be_bytes());
+            self.wtr.write_all(&written_at.to_be_bytes())?;
+            record_len += 8;
+        }
+        Ok((record_len, hasher.finalize()))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.wtr.flush()
+    }
+}
 
-        let record_len: u64 = buf_record.len() as u64;
-        let mut buf = Vec::new();
-        buf.write_u64::<BigEndian>(record_len).unwrap();
+/// Streams a record into the end of `file`, the segment at `file_path`, and returns its length
+/// and position. A record that fails part way is cut off again, and `KvStore::open` cuts off one
+/// that a crash left without its checksum.
+///
+/// The checksum is filled in through a separate handle because writes through `file` always append.
+pub(crate) fn stream_record(
+    file: &fs::File,
+    file_path: &Path,
+    timestamp: u64,
+    key: &str,
+    value: &mut dyn io::Read,
+    value_len: u32,
+    written_at: u64,
+) -> io::Result<(u64, u64)> {
+    let record_pos = (&*file).seek(io::SeekFrom::End(0))?;
+    let written = (|| -> io::Result<u64> {
+        let mut writer = Writer::new(io::BufWriter::new(file));
+        let (record_len, crc) = writer.write_streamed_record(timestamp, key, value, value_len, written_at)?;
+        writer.flush()?;
 
-        self.wtr.write(&buf)?;
-        self.wtr.write(&buf_record)?;
+        let mut buf = [0; 4];
+        BigEndian::write_u32(&mut buf, crc);
+        write_all_at(&fs::OpenOptions::new().write(true).open(file_path)?, &buf, record_pos)?;
+        Ok(record_len)
+    })();
 
-        Ok(())
+    match written {
+        Ok(record_len) => Ok((record_pos, record_len)),
+        Err(e) => {
+            file.set_len(record_pos)?;
+            Err(e)
+        }
     }
 }
+
+#[cfg(unix)]
+fn write_all_at(file: &fs::File, buf: &[u8], pos: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.write_all_at(buf, pos)
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &fs::File, mut buf: &[u8], mut pos: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_write(buf, pos) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write checksum")),
+            Ok(n) => {
+                buf = &buf[n..];
+                pos += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
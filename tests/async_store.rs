@@ -0,0 +1,70 @@
+#![cfg(feature = "async")]
+
+use kvs::{AsyncKvStore, KvStore, KvsError, Result};
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn async_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::open(temp_dir.path()).await?;
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+
+    store.remove("key1".to_owned()).await?;
+    assert_eq!(store.get("key1".to_owned()).await?, None);
+
+    match store.remove("key1".to_owned()).await {
+        Err(KvsError::KeyNotFound) => {}
+        other => panic!("expected KeyNotFound, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// A write is submitted when the method is called, even if its future is never polled.
+#[tokio::test]
+async fn async_dropped_write_is_applied() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::open(temp_dir.path()).await?;
+
+    drop(store.set("key1".to_owned(), "value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned()).await?, Some("value1".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn async_concurrent_tasks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = AsyncKvStore::open(temp_dir.path()).await?;
+
+    let mut handles = Vec::new();
+    for task_id in 0..8 {
+        let store = store.clone();
+        handles.push(tokio::spawn(async move {
+            for i in 0..100 {
+                store.set(format!("key{}_{}", task_id, i), format!("{}", i)).await?;
+            }
+            Ok::<(), KvsError>(())
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap()?;
+    }
+
+    for task_id in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}_{}", task_id, i)).await?,
+                Some(format!("{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use kvs::{CompactOptions, KvStore, Options, Result};
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open(path: &Path, cache_capacity: usize) -> Result<KvStore> {
+    KvStore::open_with_options(
+        path,
+        Options {
+            cache_capacity,
+            ..Options::default()
+        },
+    )
+}
+
+#[test]
+fn cache_hits_and_misses() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), 1024)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    let stats = store.stats();
+    assert_eq!(stats.cache_hits, 1);
+    assert_eq!(stats.cache_misses, 1);
+    assert_eq!(stats.cache_bytes, 6);
+
+    Ok(())
+}
+
+#[test]
+fn cache_disabled_by_default() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let stats = store.stats();
+    assert_eq!(stats.cache_hits, 0);
+    assert_eq!(stats.cache_misses, 0);
+    assert_eq!(stats.cache_bytes, 0);
+
+    Ok(())
+}
+
+// The least recently read values are evicted to stay within the byte limit.
+#[test]
+fn cache_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), 20)?;
+
+    for key_id in 0..3 {
+        store.set(format!("key{}", key_id), format!("value{:0>4}", key_id))?;
+    }
+    store.get("key0".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key0".to_owned())?;
+    store.get("key2".to_owned())?;
+    assert_eq!(store.stats().cache_bytes, 18);
+
+    // key1 was the least recently read, so it is the one evicted
+    store.get("key0".to_owned())?;
+    store.get("key2".to_owned())?;
+    store.get("key1".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.cache_hits, 3);
+    assert_eq!(stats.cache_misses, 4);
+    assert!(stats.cache_bytes <= 20);
+
+    // values larger than the whole cache are never kept
+    store.set("big".to_owned(), "x".repeat(21))?;
+    store.get("big".to_owned())?;
+    store.get("big".to_owned())?;
+    assert_eq!(store.stats().cache_misses, 6);
+
+    Ok(())
+}
+
+#[test]
+fn cache_overwrite_and_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), 1024)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Compaction rewrites segments under their old names, so cached positions must not be trusted after it.
+#[test]
+fn cache_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), 1 << 20)?;
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}-{}", key_id, iter))?;
+            store.get(format!("key{}", key_id))?;
+        }
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}-9", key_id)));
+    }
+
+    store.compact(CompactOptions::new())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}-9", key_id)));
+    }
+
+    Ok(())
+}
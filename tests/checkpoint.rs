@@ -0,0 +1,287 @@
+use assert_cmd::prelude::*;
+use kvs::{CompactOptions, KvStore, KvsError, Options, RestorePoint, Result, Retention};
+use predicates::ord::eq;
+use predicates::prelude::*;
+use predicates::str::{contains, PredicateStrExt};
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+
+// A checkpoint should capture the store as of the call, unaffected by later writes and compactions.
+#[test]
+fn checkpoint_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_path = backup_dir.path().join("backup");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), "before".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+
+    store.checkpoint(&backup_path)?;
+
+    // keep writing until compaction has rewritten the segments the checkpoint links to
+    for iter in 0..10 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("after{}", iter))?;
+        }
+    }
+    drop(store);
+
+    KvStore::restore(&backup_path, restore_dir.path())?;
+    let mut store = KvStore::open(restore_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("before".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn restore_rejects_existing_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint(backup_dir.path())?;
+    drop(store);
+
+    assert!(KvStore::restore(backup_dir.path(), temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// The horizon goes along with a checkpoint, even once nothing left in the log tells how far it is,
+// and value log files are listed as such.
+#[test]
+fn checkpoint_keeps_horizon_and_value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let options = Options {
+        value_log_threshold: Some(10),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set("big".to_owned(), "x".repeat(100))?;
+    store.remove("big".to_owned())?;
+    store.compact(CompactOptions::new())?;
+    let horizon = store.last_timestamp();
+    store.checkpoint(backup_dir.path())?;
+    drop(store);
+
+    let manifest = fs::read_to_string(backup_dir.path().join("CHECKPOINT"))?;
+    assert!(manifest.contains("value_log 00000000.vlog "), "{}", manifest);
+    assert!(!manifest.contains("segment 00000000.vlog "), "{}", manifest);
+
+    KvStore::restore(backup_dir.path(), restore_dir.path())?;
+    assert!(restore_dir.path().join("00000000.vlog").exists());
+    let store = KvStore::open_with_options(restore_dir.path(), options)?;
+    assert_eq!(store.last_timestamp(), horizon);
+    assert!(matches!(store.changes_since(0), Err(KvsError::Compacted(h)) if h == horizon));
+
+    Ok(())
+}
+
+// A directory without a manifest is not a finished checkpoint.
+#[test]
+fn restore_rejects_incomplete_checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.checkpoint(backup_dir.path())?;
+    drop(store);
+
+    fs::remove_file(backup_dir.path().join("CHECKPOINT"))?;
+    match KvStore::restore(backup_dir.path(), restore_dir.path()) {
+        Err(KvsError::InvalidCheckpoint(_)) => {}
+        other => panic!("expected InvalidCheckpoint, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// `kvs backup <DEST>` followed by `kvs restore <SRC>` should reproduce the store.
+#[test]
+fn cli_backup_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_path = backup_dir.path().join("backup");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", backup_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", backup_path.to_str().unwrap()])
+        .current_dir(&restore_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&restore_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// A bad bulk write can be undone by restoring to the last change before it, as long as the
+// retention kept the versions it overwrote through compaction.
+#[test]
+fn restore_until_timestamp() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "good".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    let good = store.last_timestamp();
+    drop(store);
+
+    let options = Options {
+        retention: Retention::SinceTimestamp(good),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..5 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("bad{}", iter))?;
+        }
+    }
+    store.remove("key1".to_owned())?;
+    store.compact(CompactOptions::new())?;
+    store.checkpoint(backup_dir.path())?;
+    drop(store);
+
+    assert_eq!(
+        KvStore::restore_until(backup_dir.path(), restore_dir.path(), RestorePoint::Timestamp(good))?,
+        99
+    );
+    let mut store = KvStore::open(restore_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("good".to_owned()));
+    }
+    // the removal of key0 is not in the log, but the horizon keeps its timestamp from being reused
+    assert_eq!(store.last_timestamp(), good);
+    assert!(matches!(store.changes_since(0), Err(KvsError::Compacted(horizon)) if horizon == good));
+
+    Ok(())
+}
+
+#[test]
+fn restore_until_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "good".to_owned())?;
+    store.set("key2".to_owned(), "good".to_owned())?;
+    thread::sleep(Duration::from_millis(20));
+    let good = SystemTime::now();
+    thread::sleep(Duration::from_millis(20));
+    store.set("key1".to_owned(), "bad".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "bad".to_owned())?;
+    store.checkpoint(backup_dir.path())?;
+    drop(store);
+
+    KvStore::restore_until(backup_dir.path(), restore_dir.path(), RestorePoint::Time(good))?;
+    let mut store = KvStore::open(restore_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("good".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("good".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    // the restored records keep the time they were first written at
+    drop(store);
+    let again_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(restore_dir.path())?.checkpoint(&again_dir.path().join("backup"))?;
+    let restored = KvStore::restore_until(
+        &again_dir.path().join("backup"),
+        &again_dir.path().join("store"),
+        RestorePoint::Time(good),
+    )?;
+    assert_eq!(restored, 2);
+
+    Ok(())
+}
+
+#[test]
+fn cli_restore_until() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.last_timestamp();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.checkpoint(backup_dir.path())?;
+    drop(store);
+
+    let restore = |until: &str| {
+        let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+        let assert = Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["restore", backup_dir.path().to_str().unwrap(), "--until", until])
+            .current_dir(&restore_dir)
+            .assert();
+        (restore_dir, assert)
+    };
+
+    let (restore_dir, assert) = restore(&first.to_string());
+    assert.success().stdout(eq("restored 1 keys").trim());
+    assert_eq!(
+        KvStore::open(restore_dir.path())?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    let (restore_dir, assert) = restore("2999-01-01T00:00:00.5+02:00");
+    assert.success().stdout(eq("restored 1 keys").trim());
+    assert_eq!(
+        KvStore::open(restore_dir.path())?.get("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+
+    let (_, assert) = restore("1970-01-01T00:00:01Z");
+    assert.success().stdout(eq("restored 0 keys").trim());
+
+    let (_, assert) = restore("yesterday");
+    assert.failure();
+    // not a day of February, and a non-ASCII offset
+    for until in ["2026-02-31T00:00:00Z", "2026-01-01T00:00:00+0\u{e9}:0"] {
+        let (_, assert) = restore(until);
+        assert.failure().stderr(contains("panicked").not());
+    }
+
+    Ok(())
+}
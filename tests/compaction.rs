@@ -0,0 +1,311 @@
+use assert_cmd::prelude::*;
+use kvs::{CompactOptions, CompactionPolicy, GarbageRatioPolicy, KvStore, KvsError, Result, SegmentStats};
+use predicates::str::contains;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn segment_contents(path: &Path) -> HashMap<String, Vec<u8>> {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(OsStr::new("bcd")))
+        .map(|path| {
+            let contents = fs::read(&path).unwrap();
+            (path.file_name().unwrap().to_string_lossy().into_owned(), contents)
+        })
+        .collect()
+}
+
+// Overwriting a few hot keys should compact the segments they churn through and leave the segments
+// holding cold keys byte for byte as they were.
+#[test]
+fn cold_segments_untouched() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..200 {
+        store.set(format!("cold{}", key_id), "value".to_owned())?;
+    }
+    let cold = segment_contents(temp_dir.path());
+    let active = store.stats().segments.last().unwrap().file_name.clone();
+
+    for iter in 0..200 {
+        for key_id in 0..5 {
+            store.set(format!("hot{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    assert!(store.stats().last_compaction.is_some());
+
+    let after = segment_contents(temp_dir.path());
+    for (file_name, contents) in cold.iter().filter(|(file_name, _)| **file_name != active) {
+        assert_eq!(after.get(file_name), Some(contents), "{} was rewritten", file_name);
+    }
+    assert!(after.len() < cold.len() + 20);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("cold{}", key_id))?, Some("value".to_owned()));
+    }
+    for key_id in 0..5 {
+        assert_eq!(store.get(format!("hot{}", key_id))?, Some("value199".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn remove_compacts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let total_bytes = store.stats().total_bytes();
+
+    for key_id in 0..500 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let stats = store.stats();
+    assert!(stats.last_compaction.is_some());
+    assert!(stats.total_bytes() < total_bytes);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..500 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+
+    Ok(())
+}
+
+// A tombstone must outlive compaction while an older record of its key sits in a segment that was not
+// compacted, or the key comes back on reopen.
+#[test]
+fn tombstones_shadow_uncompacted_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("cold{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..3 {
+        store.remove(format!("cold{}", key_id))?;
+    }
+    for iter in 0..200 {
+        for key_id in 0..5 {
+            store.set(format!("hot{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    // the new value lands in a segment that is compacted while the old one stays put
+    store.set("cold50".to_owned(), "new".to_owned())?;
+    for iter in 0..100 {
+        store.set("hot0".to_owned(), format!("value{}", iter))?;
+    }
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..3 {
+        assert_eq!(store.get(format!("cold{}", key_id))?, None);
+    }
+    assert_eq!(store.get("cold3".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("cold50".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("hot0".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("hot1".to_owned())?, Some("value199".to_owned()));
+
+    Ok(())
+}
+
+struct NeverCompact;
+
+impl CompactionPolicy for NeverCompact {
+    fn select(&self, _segments: &[SegmentStats]) -> Vec<usize> {
+        Vec::new()
+    }
+}
+
+#[test]
+fn custom_policy() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_policy(NeverCompact);
+
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+
+    let stats = store.stats();
+    assert_eq!(stats.last_compaction, None);
+    assert_eq!(stats.segments.iter().map(|segment| segment.records).sum::<u64>(), 1000);
+
+    Ok(())
+}
+
+// Compacting the first and third segments moves the latest record of a key into the first segment's
+// file, ahead of the untouched second segment holding an older one.
+#[test]
+fn reopen_resolves_by_timestamp() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_policy(NeverCompact);
+
+    let fill = |store: &mut KvStore, prefix: &str, overwrite: bool| -> Result<()> {
+        let segments = store.stats().segments.len();
+        let mut i = 0;
+        while store.stats().segments.len() == segments {
+            let key = if overwrite {
+                prefix.to_owned()
+            } else {
+                format!("{}{}", prefix, i)
+            };
+            store.set(key, format!("value{}", i))?;
+            i += 1;
+        }
+        Ok(())
+    };
+
+    fill(&mut store, "hot", true)?;
+    store.set("key".to_owned(), "old".to_owned())?;
+    fill(&mut store, "cold", false)?;
+    store.set("key".to_owned(), "new".to_owned())?;
+    fill(&mut store, "hot", true)?;
+
+    store.set_compaction_policy(GarbageRatioPolicy::default());
+    store.set("trigger".to_owned(), "value".to_owned())?;
+    let stats = store.stats();
+    assert!(stats.last_compaction.is_some());
+    assert_eq!(stats.segments.len(), 3);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+
+    Ok(())
+}
+
+fn churn(store: &mut KvStore) -> Result<()> {
+    store.set_compaction_policy(NeverCompact);
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    churn(&mut store)?;
+
+    let before = store.stats();
+    assert_eq!(store.compact(CompactOptions::new().dry_run(true))?, before.dead_bytes());
+    assert_eq!(store.stats(), before);
+
+    let mut reports = Vec::new();
+    let reclaimed = store.compact(CompactOptions::new().progress(|progress| reports.push(progress.clone())))?;
+
+    let after = store.stats();
+    assert_eq!(reclaimed, before.total_bytes() - after.total_bytes());
+    assert_eq!(after.dead_bytes(), 0);
+    assert_eq!(reports.len(), before.segments.len());
+    let last = reports.last().unwrap();
+    assert_eq!(last.segments_done, last.segments_total);
+    assert_eq!(last.bytes_read, before.total_bytes());
+    assert_eq!(last.bytes_written, after.total_bytes());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value99".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn manual_compaction_of_selected_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    churn(&mut store)?;
+
+    let before = store.stats();
+    let selected = vec![
+        before.segments[1].file_name.clone(),
+        before.segments[3].file_name.clone(),
+    ];
+    let reclaimed = store.compact(CompactOptions::new().segments(selected))?;
+
+    // segments left out are untouched, the selected ones gone or free of garbage
+    let after = store.stats();
+    assert_eq!(reclaimed, before.total_bytes() - after.total_bytes());
+    for (i, segment) in before.segments.iter().enumerate() {
+        let compacted = after.segments.iter().find(|after| after.file_name == segment.file_name);
+        match i {
+            1 | 3 => assert!(compacted.is_none_or(|compacted| compacted.dead_bytes == 0)),
+            _ => assert_eq!(compacted, Some(segment)),
+        }
+    }
+
+    match store.compact(CompactOptions::new().segments(vec!["missing.bcd".to_owned()])) {
+        Err(KvsError::UnknownSegment(name)) => assert_eq!(name, "missing.bcd"),
+        other => panic!("expected UnknownSegment, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn manual_compaction_rate_limit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    churn(&mut store)?;
+
+    // at least every byte is read once, which should take a quarter of a second at this rate
+    let rate = store.stats().total_bytes() * 4;
+    let started = Instant::now();
+    store.compact(CompactOptions::new().rate_limit(rate))?;
+    assert!(started.elapsed() >= Duration::from_millis(240));
+
+    Ok(())
+}
+
+#[test]
+fn cli_compact() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    churn(&mut store)?;
+    let dead_bytes = store.stats().dead_bytes();
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact", "--dry-run"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(format!("would reclaim up to {} bytes", dead_bytes)));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["compact"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("reclaimed"))
+        .stderr(contains("segments"));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().dead_bytes(), 0);
+
+    Ok(())
+}
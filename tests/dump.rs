@@ -0,0 +1,116 @@
+use assert_cmd::prelude::*;
+use kvs::dump::SegmentDump;
+use kvs::{KvStore, Result};
+use predicates::ord::eq;
+use predicates::str::{contains, PredicateStrExt};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+fn segment(path: &Path) -> PathBuf {
+    fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some(OsStr::new("bcd")))
+        .unwrap()
+}
+
+#[test]
+fn dump_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let path = segment(temp_dir.path());
+    let records = SegmentDump::open(&path)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 3);
+    assert!(records[0].offset > 0);
+    assert_eq!(records[1].offset, records[0].offset + records[0].len);
+    assert_eq!(records[2].offset + records[2].len, fs::metadata(&path)?.len());
+
+    assert_eq!((records[1].key.as_str(), records[1].value.as_str()), ("key2", "value2"));
+    assert!(!records[1].tombstone);
+    assert_eq!(records[2].key, "key1");
+    assert!(records[2].tombstone);
+    assert!(records[0].timestamp < records[1].timestamp && records[1].timestamp < records[2].timestamp);
+
+    Ok(())
+}
+
+// Dumping stops at the first undecodable record, reporting where it is.
+#[test]
+fn dump_stops_at_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let path = segment(temp_dir.path());
+    let second = SegmentDump::open(&path)?.nth(1).unwrap()?.offset as usize;
+    let mut buf = fs::read(&path)?;
+    buf[second + 8] = 0xff;
+    fs::write(&path, &buf)?;
+
+    let mut dump = SegmentDump::open(&path)?;
+    assert_eq!(dump.next().unwrap()?.key, "key1");
+    assert!(dump.next().unwrap().is_err());
+    assert!(dump.next().is_none());
+    assert_eq!(dump.offset(), second as u64);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", path.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(contains(format!("undecodable record at offset {}", second)));
+
+    Ok(())
+}
+
+#[test]
+fn cli_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "a long value that will not fit".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let path = segment(temp_dir.path());
+    let records = SegmentDump::open(&path)?.collect::<Result<Vec<_>>>()?;
+
+    let expected = format!(
+        "{} {} {} set \"key1\" \"a long...\"",
+        records[0].offset, records[0].len, records[0].timestamp
+    );
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", path.to_str().unwrap(), "--key", "key1", "--width", "6"])
+        .assert()
+        .success()
+        .stdout(eq(expected.as_str()).trim());
+
+    let expected = format!(
+        concat!(
+            "{{\"offset\":{},\"len\":{},\"timestamp\":{},\"tombstone\":false,",
+            "\"key\":\"key2\",\"value\":\"value2\",\"written_at\":{}}}"
+        ),
+        records[1].offset,
+        records[1].len,
+        records[1].timestamp,
+        records[1].written_at.unwrap()
+    );
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", path.to_str().unwrap(), "--key", "key2", "--json"])
+        .assert()
+        .success()
+        .stdout(eq(expected.as_str()).trim());
+
+    Ok(())
+}
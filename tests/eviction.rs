@@ -0,0 +1,165 @@
+use kvs::{CompactOptions, EvictionPolicy, KvStore, Options, Result};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const MAX_LIVE_BYTES: u64 = 2000;
+
+fn open(path: &Path, eviction: EvictionPolicy) -> Result<KvStore> {
+    KvStore::open_with_options(
+        path,
+        Options {
+            max_live_bytes: Some(MAX_LIVE_BYTES),
+            eviction,
+            ..Options::default()
+        },
+    )
+}
+
+// Writes twice as many keys as fit, reading key0 after each write.
+fn fill(store: &mut KvStore) -> Result<()> {
+    for key_id in 0..100 {
+        store.set(format!("key{:02}", key_id), format!("value{:02}", key_id))?;
+        store.get("key00".to_owned())?;
+    }
+    Ok(())
+}
+
+fn live_keys(store: &KvStore) -> Result<Vec<String>> {
+    let mut keys = store
+        .iter()
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    keys.sort();
+    Ok(keys)
+}
+
+#[test]
+fn evict_least_recently_used() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), EvictionPolicy::LeastRecentlyUsed)?;
+    fill(&mut store)?;
+
+    let stats = store.stats();
+    assert!(stats.live_data_bytes <= MAX_LIVE_BYTES);
+    assert!(stats.evictions > 0);
+    assert_eq!(stats.evictions + stats.live_keys, 100);
+
+    // key0 was read all along, and the others go oldest first
+    let keys = live_keys(&store)?;
+    assert_eq!(keys[0], "key00");
+    assert_eq!(keys.last().unwrap(), "key99");
+    assert!(!store.contains_key("key01"));
+    let survivors = keys.len();
+    assert_eq!(keys[1], format!("key{:02}", 100 - survivors + 1));
+
+    // the tombstones and evicted values are garbage for compaction to reclaim
+    let total_bytes = store.stats().total_bytes();
+    store.compact(CompactOptions::new())?;
+    assert!(store.stats().total_bytes() < total_bytes);
+    assert_eq!(live_keys(&store)?, keys);
+
+    Ok(())
+}
+
+#[test]
+fn evict_oldest_written() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), EvictionPolicy::OldestWritten)?;
+    fill(&mut store)?;
+
+    let keys = live_keys(&store)?;
+    assert!(!store.contains_key("key00"));
+    assert_eq!(keys[0], format!("key{:02}", 100 - keys.len()));
+    assert!(store.stats().live_data_bytes <= MAX_LIVE_BYTES);
+
+    // the order carries over to a reopened store, which evicts on its next write
+    drop(store);
+    let mut store = open(temp_dir.path(), EvictionPolicy::OldestWritten)?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    assert!(!store.contains_key(&keys[0]));
+    assert!(store.contains_key(&keys[1]));
+
+    Ok(())
+}
+
+#[test]
+fn evict_ttl_first() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), EvictionPolicy::TtlFirst)?;
+    store.set("key00".to_owned(), "value00".to_owned())?;
+    store.set_with_ttl("late".to_owned(), "value".to_owned(), Duration::from_secs(3600))?;
+    store.set_with_ttl("soon".to_owned(), "value".to_owned(), Duration::from_secs(60))?;
+
+    // the keys with a TTL go first, soonest to expire first, then the oldest written
+    let mut key_id = 1;
+    for (evictions, evicted) in [(1, "soon"), (2, "late"), (3, "key00")] {
+        while store.stats().evictions < evictions {
+            store.set(format!("key{:02}", key_id), format!("value{:02}", key_id))?;
+            key_id += 1;
+        }
+        assert!(!store.contains_key(evicted));
+        assert!(store.contains_key("key01"));
+    }
+    assert!(store.stats().live_data_bytes <= MAX_LIVE_BYTES);
+
+    Ok(())
+}
+
+#[test]
+fn expired_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl("key1".to_owned(), "value1".to_owned(), Duration::from_millis(50))?;
+    store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::from_millis(50))?;
+    store.set_with_ttl("key3".to_owned(), "value3".to_owned(), Duration::from_secs(3600))?;
+    // setting a key again without a TTL makes it permanent
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // the TTLs are kept in the log
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(!store.contains_key("key1"));
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(live_keys(&store)?, vec!["key2", "key3"]);
+
+    assert_eq!(store.remove_expired()?, 1);
+    assert_eq!(store.remove_expired()?, 0);
+    assert_eq!(store.stats().live_keys, 2);
+    assert_eq!(store.stats().evictions, 0);
+
+    // a TTL too long to represent never runs out
+    store.set_with_ttl("key4".to_owned(), "value4".to_owned(), Duration::MAX)?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Values in the value log count towards the limit as much as the ones in the segments.
+#[test]
+fn evict_value_log_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(
+        temp_dir.path(),
+        Options {
+            max_live_bytes: Some(MAX_LIVE_BYTES),
+            value_log_threshold: Some(100),
+            ..Options::default()
+        },
+    )?;
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "x".repeat(500))?;
+    }
+    let stats = store.stats();
+    assert!(stats.live_data_bytes <= MAX_LIVE_BYTES);
+    assert!(stats.live_data_bytes >= 3 * 500);
+    assert_eq!(stats.live_keys, 3);
+    assert_eq!(stats.evictions, 7);
+
+    Ok(())
+}
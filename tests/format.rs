@@ -0,0 +1,235 @@
+use assert_cmd::prelude::*;
+use kvs::dump::SegmentDump;
+use kvs::{CompactOptions, KvStore, KvsError, Result, SegmentHeader, FORMAT_VERSION};
+use predicates::ord::eq;
+use predicates::str::{contains, PredicateStrExt};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+use tempfile::TempDir;
+
+fn segments(path: &Path) -> Vec<PathBuf> {
+    let mut segments: Vec<PathBuf> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(OsStr::new("bcd")))
+        .collect();
+    segments.sort();
+    segments
+}
+
+fn populate(path: &Path) -> Result<()> {
+    let mut store = KvStore::open(path)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    Ok(())
+}
+
+// Writes the same records as `populate` the way a store in an older format `version` laid them out,
+// in segments of 40 records: in version 0 and 1 as a length followed by the msgpack encoding of the
+// record, and since version 2 in the binary layout, without the optional fields at the end.
+fn populate_legacy(path: &Path, version: u16) -> Result<()> {
+    let mut records = Vec::new();
+    for key_id in 0..100 {
+        records.push((key_id + 1, 0u8, format!("key{}", key_id), format!("value{}", key_id)));
+    }
+    records.push((101, 1, "key0".to_owned(), "".to_owned()));
+
+    for (i, chunk) in records.chunks(40).enumerate() {
+        let mut buf = Vec::new();
+        if version > 0 {
+            let header = SegmentHeader {
+                version,
+                created_at: SystemTime::now(),
+                created_by: "0.1.0".to_owned(),
+            };
+            buf.extend_from_slice(&header.encode());
+        }
+        for record in chunk {
+            if version >= 2 {
+                let (timestamp, tombstone, key, value) = record;
+                let start = buf.len();
+                buf.extend_from_slice(&[0; 4]);
+                buf.extend_from_slice(&(*timestamp as u64).to_be_bytes());
+                buf.push(*tombstone);
+                buf.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+                buf.extend_from_slice(key.as_bytes());
+                buf.extend_from_slice(value.as_bytes());
+                let crc = crc32fast::hash(&buf[start + 4..]);
+                buf[start..start + 4].copy_from_slice(&crc.to_be_bytes());
+                continue;
+            }
+            let payload = rmp_serde::to_vec(record).unwrap();
+            buf.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+            buf.extend_from_slice(&payload);
+        }
+        fs::write(path.join(format!("{:08}.bcd", i)), &buf)?;
+    }
+    Ok(())
+}
+
+fn versions(path: &Path) -> Result<Vec<u16>> {
+    let mut versions = Vec::new();
+    for segment in segments(path) {
+        versions.push(SegmentDump::open(&segment)?.header().map_or(0, |header| header.version));
+    }
+    Ok(versions)
+}
+
+fn assert_populated(path: &Path) -> Result<()> {
+    let mut store = KvStore::open(path)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    Ok(())
+}
+
+#[test]
+fn segments_have_headers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let segments = segments(temp_dir.path());
+    assert!(segments.len() > 1);
+    for segment in segments {
+        let dump = SegmentDump::open(&segment)?;
+        let header = dump.header().expect("segment should have a header");
+        assert_eq!(header.version, FORMAT_VERSION);
+        assert_eq!(header.created_by, env!("CARGO_PKG_VERSION"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn open_rejects_old_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate_legacy(temp_dir.path(), 0)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat { version: 0, .. }) => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("old format should be rejected"),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn open_rejects_newer_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let segment = segments(temp_dir.path()).pop().unwrap();
+    let mut buf = fs::read(&segment)?;
+    buf[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+    fs::write(&segment, &buf)?;
+
+    let expected_version = FORMAT_VERSION + 1;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat { version, .. }) if version == expected_version => {}
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("newer format should be rejected"),
+    }
+    assert!(KvStore::migrate(temp_dir.path()).is_err());
+
+    Ok(())
+}
+
+// Segments in the msgpack format are read in place, and new records go to a segment of their own.
+#[test]
+fn open_msgpack_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate_legacy(temp_dir.path(), 1)?;
+    assert_populated(temp_dir.path())?;
+    assert_eq!(versions(temp_dir.path())?, vec![1, 1, 1, FORMAT_VERSION]);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.changes_since(0)?.len(), 102);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    store.compact(CompactOptions::new())?;
+    assert!(versions(temp_dir.path())?
+        .iter()
+        .all(|&version| version == FORMAT_VERSION));
+    assert_eq!(store.get("key1".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn migrate_old_format() -> Result<()> {
+    for version in 0..FORMAT_VERSION {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        populate_legacy(temp_dir.path(), version)?;
+
+        assert_eq!(KvStore::migrate(temp_dir.path())?, 3);
+        assert_eq!(versions(temp_dir.path())?, vec![FORMAT_VERSION; 3]);
+        assert_populated(temp_dir.path())?;
+
+        // only segments in an older format are rewritten
+        assert_eq!(KvStore::migrate(temp_dir.path())?, 0);
+    }
+
+    Ok(())
+}
+
+// A crash right after a segment was created leaves it without even a header.
+#[test]
+fn open_empty_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let next = segments(temp_dir.path()).len() + 100;
+    let empty = temp_dir.path().join(format!("{:08}.bcd", next));
+    fs::write(&empty, b"")?;
+
+    assert_populated(temp_dir.path())?;
+    assert_eq!(fs::metadata(&empty)?.len(), SegmentHeader::LEN);
+
+    Ok(())
+}
+
+#[test]
+fn cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate_legacy(temp_dir.path(), 0)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("UnsupportedFormat"));
+
+    let expected = "migrated 3 segments";
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(expected).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use kvs::{CompactOptions, KvStore, Options, Result, Retention, Version};
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open(path: &Path, retention: Retention) -> Result<KvStore> {
+    KvStore::open_with_options(
+        path,
+        Options {
+            retention,
+            ..Options::default()
+        },
+    )
+}
+
+fn version(timestamp: u64, value: Option<&str>) -> Version {
+    Version {
+        timestamp,
+        value: value.map(str::to_string),
+    }
+}
+
+#[test]
+fn history_and_get_at() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), Retention::Versions(10))?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.last_timestamp();
+    store.set("key2".to_owned(), "other".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let second = store.last_timestamp();
+    store.remove("key1".to_owned())?;
+    let removed = store.last_timestamp();
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let third = store.last_timestamp();
+
+    let expected = vec![
+        version(first, Some("value1")),
+        version(second, Some("value2")),
+        version(removed, None),
+        version(third, Some("value3")),
+    ];
+    assert_eq!(store.history("key1")?, expected);
+    assert_eq!(store.get_at("key1", first - 1)?, None);
+    assert_eq!(store.get_at("key1", first)?, Some("value1".to_owned()));
+    assert_eq!(store.get_at("key1", second - 1)?, Some("value1".to_owned()));
+    assert_eq!(store.get_at("key1", removed)?, None);
+    assert_eq!(store.get_at("key1", third + 10)?, Some("value3".to_owned()));
+    assert!(store.history("missing")?.is_empty());
+
+    store.compact(CompactOptions::new())?;
+    assert_eq!(store.history("key1")?, expected);
+
+    drop(store);
+    let store = open(temp_dir.path(), Retention::Versions(10))?;
+    assert_eq!(store.history("key1")?, expected);
+    assert_eq!(store.get_at("key1", second)?, Some("value2".to_owned()));
+
+    // without retention only the live version is known
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.history("key1")?, vec![version(third, Some("value3"))]);
+    assert_eq!(store.get_at("key1", second)?, None);
+
+    Ok(())
+}
+
+#[test]
+fn compaction_keeps_last_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path(), Retention::Versions(3))?;
+
+    for iter in 0..200 {
+        for key_id in 0..5 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.compact(CompactOptions::new())?;
+
+    for key_id in 0..5 {
+        let values: Vec<Option<String>> = store
+            .history(&format!("key{}", key_id))?
+            .into_iter()
+            .map(|version| version.value)
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                Some("value197".to_owned()),
+                Some("value198".to_owned()),
+                Some("value199".to_owned())
+            ]
+        );
+    }
+    // the kept versions count as live, so the policy finds nothing to do
+    let stats = store.stats();
+    assert!(stats.segments.iter().all(|segment| segment.dead_bytes == 0));
+    assert!(stats.live_bytes() > 3 * 5 * 20);
+
+    drop(store);
+    let store = open(temp_dir.path(), Retention::Versions(3))?;
+    assert_eq!(store.history("key0")?.len(), 3);
+    assert!(store.stats().segments.iter().all(|segment| segment.dead_bytes == 0));
+
+    Ok(())
+}
+
+#[test]
+fn compaction_keeps_versions_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+        store.set("key2".to_owned(), format!("value{}", iter))?;
+    }
+    let since = store.last_timestamp();
+    drop(store);
+
+    let mut store = open(temp_dir.path(), Retention::SinceTimestamp(since))?;
+    for iter in 100..200 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.remove("key1".to_owned())?;
+    store.compact(CompactOptions::new())?;
+
+    // the value at `since` is kept along with every later one
+    let history = store.history("key1")?;
+    assert_eq!(history.len(), 102);
+    assert_eq!(history[0].value, Some("value99".to_owned()));
+    assert_eq!(history[101].value, None);
+    assert_eq!(store.get_at("key1", since)?, Some("value99".to_owned()));
+    assert_eq!(store.get_at("key1", since + 50)?, Some("value149".to_owned()));
+    assert_eq!(store.get_at("key1", since - 10)?, None);
+    assert_eq!(store.history("key2")?.len(), 1);
+
+    Ok(())
+}
@@ -0,0 +1,181 @@
+use kvs::{ExportEncoding, ExportFormat, KvStore, KvsError, Options, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use tempfile::TempDir;
+
+// Indexes documents by their `email` field, and by each of their `tags`.
+fn open(path: &Path) -> Result<KvStore> {
+    let mut store = KvStore::open(path)?;
+    store.register_index("email", |value| field(value, "email"))?;
+    store.register_index("tags", |value| field(value, "tags"))?;
+    Ok(store)
+}
+
+fn field(value: &str, name: &str) -> Vec<String> {
+    let doc: Value = match serde_json::from_str(value) {
+        Ok(doc) => doc,
+        Err(_) => return Vec::new(),
+    };
+    match &doc[name] {
+        Value::String(s) => vec![s.clone()],
+        Value::Array(values) => values.iter().filter_map(|v| v.as_str().map(str::to_string)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn user(email: &str, tags: &[&str]) -> String {
+    serde_json::json!({ "email": email, "tags": tags }).to_string()
+}
+
+#[test]
+fn set_and_remove_maintain_indexes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    store.set("user1".to_owned(), user("a@example.com", &["admin", "staff"]))?;
+    store.set("user2".to_owned(), user("b@example.com", &["staff"]))?;
+    store.set("note".to_owned(), "not a document".to_owned())?;
+    store
+        .namespace("other")?
+        .set("user3".to_owned(), user("a@example.com", &[]))?;
+
+    assert_eq!(store.query_index("email", "a@example.com")?, vec!["user1".to_owned()]);
+    assert_eq!(
+        store.query_index("tags", "staff")?,
+        vec!["user1".to_owned(), "user2".to_owned()]
+    );
+    assert!(store.query_index("tags", "")?.is_empty());
+    assert!(matches!(store.query_index("name", "a"), Err(KvsError::UnknownIndex(_))));
+
+    store.set("user1".to_owned(), user("c@example.com", &["staff"]))?;
+    assert!(store.query_index("email", "a@example.com")?.is_empty());
+    assert_eq!(store.query_index("email", "c@example.com")?, vec!["user1".to_owned()]);
+    assert!(store.query_index("tags", "admin")?.is_empty());
+
+    store.remove("user2".to_owned())?;
+    assert!(store.query_index("email", "b@example.com")?.is_empty());
+    assert_eq!(store.query_index("tags", "staff")?, vec!["user1".to_owned()]);
+
+    // entries stay out of the way of the keys themselves
+    let keys: HashMap<String, String> = store.iter().collect::<Result<_>>()?;
+    assert_eq!(keys.len(), 2);
+    assert_eq!(store.namespaces(), vec!["other".to_owned()]);
+    let mut buf = Vec::new();
+    assert_eq!(store.export(&mut buf, ExportFormat::Jsonl, ExportEncoding::Text)?, 3);
+    assert_eq!(store.stats().live_keys, 3);
+
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.query_index("email", "c@example.com")?, vec!["user1".to_owned()]);
+    assert_eq!(store.query_index("tags", "staff")?, vec!["user1".to_owned()]);
+    assert!(store.query_index("email", "b@example.com")?.is_empty());
+
+    Ok(())
+}
+
+// Keys written while an index was not registered are picked up when it is registered again.
+#[test]
+fn indexes_catch_up_on_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("user{}", key_id), user(&format!("{}@example.com", key_id), &[]))?;
+    }
+    drop(store);
+
+    let mut store = open(temp_dir.path())?;
+    assert_eq!(store.query_index("email", "7@example.com")?, vec!["user7".to_owned()]);
+    store.set("user8".to_owned(), user("new@example.com", &[]))?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user7".to_owned(), user("moved@example.com", &[]))?;
+    store.remove("user8".to_owned())?;
+    drop(store);
+
+    let mut store = open(temp_dir.path())?;
+    assert!(store.query_index("email", "7@example.com")?.is_empty());
+    assert_eq!(
+        store.query_index("email", "moved@example.com")?,
+        vec!["user7".to_owned()]
+    );
+    assert!(store.query_index("email", "new@example.com")?.is_empty());
+    assert_eq!(store.query_index("email", "9@example.com")?, vec!["user9".to_owned()]);
+
+    // the stale entries were removed while catching up, so dropping the index finds one per key
+    assert_eq!(store.drop_index("email")?, 99);
+    assert!(matches!(
+        store.query_index("email", "9@example.com"),
+        Err(KvsError::UnknownIndex(_))
+    ));
+    assert!(matches!(store.drop_index(""), Err(KvsError::InvalidIndex(_))));
+
+    Ok(())
+}
+
+#[test]
+fn import_and_set_reader_maintain_indexes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    let rows: String = (0..10)
+        .map(|key_id| {
+            let row = serde_json::json!({ "key": format!("user{}", key_id), "value": user("a@example.com", &[]) });
+            format!("{}\n", row)
+        })
+        .collect();
+    assert_eq!(store.import(rows.as_bytes(), ExportFormat::Jsonl)?, 10);
+    assert_eq!(store.query_index("email", "a@example.com")?.len(), 10);
+
+    let value = user("b@example.com", &["streamed"]);
+    store.set_reader("user0".to_owned(), value.as_bytes(), value.len() as u64)?;
+    assert_eq!(store.query_index("email", "a@example.com")?.len(), 9);
+    assert_eq!(store.query_index("tags", "streamed")?, vec!["user0".to_owned()]);
+
+    // watchers and replays only hear about the keys
+    let watcher = store.watch("");
+    let since = store.last_timestamp();
+    store.set("user1".to_owned(), user("b@example.com", &[]))?;
+    assert_eq!(watcher.try_iter().count(), 1);
+    assert_eq!(store.changes_since(since)?.len(), 1);
+    assert_eq!(
+        store.query_index("email", "b@example.com")?,
+        vec!["user0".to_owned(), "user1".to_owned()]
+    );
+
+    Ok(())
+}
+
+// Moving values in the value log leaves their keys where the indexes found them.
+#[test]
+fn indexes_survive_value_log_collection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        value_log_threshold: Some(100),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+    store.register_index("email", |value| field(value, "email"))?;
+
+    let padding = "x".repeat(1000);
+    store.set("a".to_owned(), user("a@example.com", &[&padding]))?;
+    for iter in 0..100 {
+        store.set("b".to_owned(), user(&format!("{}@example.com", iter), &[&padding]))?;
+    }
+    let live_bytes = store.stats().live_data_bytes;
+
+    assert!(store.collect_value_log(0.1)? > 0);
+    assert_eq!(store.query_index("email", "a@example.com")?, vec!["a".to_owned()]);
+    assert_eq!(store.query_index("email", "99@example.com")?, vec!["b".to_owned()]);
+    assert_eq!(store.stats().live_data_bytes, live_bytes);
+
+    drop(store);
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.register_index("email", |value| field(value, "email"))?;
+    assert_eq!(store.query_index("email", "a@example.com")?, vec!["a".to_owned()]);
+    assert_eq!(store.query_index("email", "99@example.com")?, vec!["b".to_owned()]);
+
+    Ok(())
+}
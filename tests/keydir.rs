@@ -0,0 +1,63 @@
+use kvs::{CompactOptions, KvStore, Result};
+use tempfile::TempDir;
+
+#[test]
+fn contains_key_and_value_len() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(!store.contains_key("key1"));
+    assert_eq!(store.get_value_len("key1"), None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "".to_owned())?;
+    assert!(store.contains_key("key1"));
+    assert_eq!(store.get_value_len("key1"), Some(6));
+    assert_eq!(store.get_value_len("key2"), Some(0));
+
+    store.set("key1".to_owned(), "x".repeat(300))?;
+    assert_eq!(store.get_value_len("key1"), Some(300));
+
+    store.remove("key2".to_owned())?;
+    assert!(!store.contains_key("key2"));
+    assert_eq!(store.get_value_len("key2"), None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_value_len("key1"), Some(300));
+    assert!(!store.contains_key("key2"));
+
+    Ok(())
+}
+
+// Values longer than any read buffer still come back whole, before and after compaction moves them.
+#[test]
+fn get_large_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), format!("{}", key_id).repeat(5000))?;
+    }
+    for key_id in 0..20 {
+        let value = format!("{}", key_id).repeat(5000);
+        assert_eq!(store.get_value_len(&format!("key{}", key_id)), Some(value.len() as u64));
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value));
+    }
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("new{}", key_id))?;
+    }
+    store.compact(CompactOptions::new())?;
+    for key_id in 0..20 {
+        let value = if key_id < 10 {
+            format!("new{}", key_id)
+        } else {
+            format!("{}", key_id).repeat(5000)
+        };
+        assert_eq!(store.get_value_len(&format!("key{}", key_id)), Some(value.len() as u64));
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value));
+    }
+
+    Ok(())
+}
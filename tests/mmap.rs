@@ -0,0 +1,65 @@
+use kvs::{CompactOptions, KvStore, Options, Result};
+use std::path::Path;
+use tempfile::TempDir;
+
+fn open(path: &Path) -> Result<KvStore> {
+    KvStore::open_with_options(
+        path,
+        Options {
+            mmap: true,
+            ..Options::default()
+        },
+    )
+}
+
+#[test]
+fn mmap_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    for key_id in 0..500 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(store.stats().segments.len() > 1);
+    for key_id in 0..500 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    drop(store);
+    let mut store = open(temp_dir.path())?;
+    for key_id in 0..500 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    assert_eq!(store.iter().count(), 500);
+
+    Ok(())
+}
+
+// Segments rewritten by compaction must be mapped afresh, and the one written to next not at all.
+#[test]
+fn mmap_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    for iter in 0..50 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    assert!(store.stats().last_compaction.is_some());
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value49".to_owned()));
+    }
+
+    store.compact(CompactOptions::new())?;
+    for key_id in 0..100 {
+        store.set(format!("new{}", key_id), format!("value{}", key_id))?;
+        assert_eq!(store.get(format!("new{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value49".to_owned()));
+        assert_eq!(store.get(format!("new{}", key_id))?, Some(format!("value{}", key_id)));
+    }
+
+    Ok(())
+}
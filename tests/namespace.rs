@@ -0,0 +1,225 @@
+use assert_cmd::prelude::*;
+use kvs::{ExportEncoding, ExportFormat, KvStore, KvsError, Result};
+use predicates::ord::eq;
+use predicates::str::{contains, PredicateStrExt};
+use std::collections::HashMap;
+use std::process::Command;
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn namespaces_are_independent() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "default".to_owned())?;
+    store.namespace("users")?.set("key1".to_owned(), "users".to_owned())?;
+    store.namespace("orders")?.set("key1".to_owned(), "orders".to_owned())?;
+    store.namespace("orders")?.set("key2".to_owned(), "orders".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    let mut users = store.namespace("users")?;
+    assert_eq!(users.name(), "users");
+    assert_eq!(users.get("key1".to_owned())?, Some("users".to_owned()));
+    assert!(!users.contains_key("key2"));
+    assert_eq!(users.get_value_len("key1"), Some(5));
+
+    store.namespace("users")?.remove("key1".to_owned())?;
+    assert!(matches!(
+        store.namespace("users")?.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.namespaces(), vec!["orders".to_owned()]);
+
+    let iterated: HashMap<String, String> = store.iter().collect::<Result<_>>()?;
+    assert_eq!(iterated.len(), 1);
+    let iterated: HashMap<String, String> = store.namespace("orders")?.iter().collect::<Result<_>>()?;
+    assert_eq!(iterated.get("key2"), Some(&"orders".to_owned()));
+    assert_eq!(iterated.len(), 2);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.namespace("orders")?.get("key1".to_owned())?,
+        Some("orders".to_owned())
+    );
+    assert_eq!(store.namespace("users")?.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn namespace_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert!(matches!(store.namespace(""), Err(KvsError::InvalidNamespace(_))));
+    assert!(matches!(store.namespace("a\0b"), Err(KvsError::InvalidNamespace(_))));
+    assert!(matches!(store.drop_namespace(""), Err(KvsError::InvalidNamespace(_))));
+
+    // one name must not be mistaken for a prefix of another
+    store.namespace("user")?.set("key1".to_owned(), "value1".to_owned())?;
+    store.namespace("users")?.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.namespace("user")?.stats().live_keys, 1);
+    assert_eq!(store.drop_namespace("user")?, 1);
+    assert_eq!(
+        store.namespace("users")?.get("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
+
+// Keys set on the store itself must not reach into a namespace or forge index entries.
+#[test]
+fn reserved_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.namespace("users")?.set("alice".to_owned(), "value".to_owned())?;
+
+    let key = "\0users\0alice".to_owned();
+    assert!(matches!(
+        store.set(key.clone(), "forged".to_owned()),
+        Err(KvsError::InvalidKey(_))
+    ));
+    assert!(matches!(
+        store.set_reader(key.clone(), "forged".as_bytes(), 6),
+        Err(KvsError::InvalidKey(_))
+    ));
+    assert!(matches!(
+        store.set_with_ttl(key.clone(), "forged".to_owned(), Duration::from_secs(60)),
+        Err(KvsError::InvalidKey(_))
+    ));
+    assert!(matches!(store.remove(key), Err(KvsError::InvalidKey(_))));
+    assert_eq!(
+        store.namespace("users")?.get("alice".to_owned())?,
+        Some("value".to_owned())
+    );
+
+    // exports carry the keys of namespaces, but nothing else starting with a NUL character
+    for key in &["\\u0000\\u0000index\\u0000by_name\\u0000forged", "\\u0000users"] {
+        let row = format!("{{\"key\":\"{}\",\"value\":\"forged\"}}\n", key);
+        assert!(matches!(
+            store.import(row.as_bytes(), ExportFormat::Jsonl),
+            Err(KvsError::InvalidKey(_))
+        ));
+    }
+    assert_eq!(store.stats().live_keys, 1);
+
+    Ok(())
+}
+
+#[test]
+fn namespace_stats_and_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store
+            .namespace("users")?
+            .set(format!("key{}", key_id), "value".to_owned())?;
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+
+    let stats = store.namespace("users")?.stats();
+    assert_eq!(stats.live_keys, 100);
+    assert_eq!(stats.value_bytes, 500);
+    // the records of namespaced keys also hold the namespace
+    let total = store.stats().live_bytes();
+    assert!(stats.live_bytes > total / 2 && stats.live_bytes < total);
+    assert_eq!(store.namespace("orders")?.stats().live_keys, 0);
+
+    assert_eq!(store.drop_namespace("users")?, 100);
+    assert_eq!(store.drop_namespace("users")?, 0);
+    assert_eq!(store.namespace("users")?.stats().live_keys, 0);
+    assert_eq!(store.stats().live_keys, 100);
+    assert!(store.namespaces().is_empty());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.namespace("users")?.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Exports carry every namespace, and importing them puts each key back where it was.
+#[test]
+fn export_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let import_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "default".to_owned())?;
+    store.namespace("users")?.set("key1".to_owned(), "users".to_owned())?;
+
+    let mut buf = Vec::new();
+    assert_eq!(store.export(&mut buf, ExportFormat::Jsonl, ExportEncoding::Base64)?, 2);
+
+    let mut imported = KvStore::open(import_dir.path())?;
+    imported.import(&buf[..], ExportFormat::Jsonl)?;
+    assert_eq!(imported.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(
+        imported.namespace("users")?.get("key1".to_owned())?,
+        Some("users".to_owned())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn cli_namespace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live keys: 1"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("namespaces: users"));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key1", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--ns", "users"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+
+    Ok(())
+}
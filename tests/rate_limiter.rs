@@ -0,0 +1,147 @@
+use kvs::{CompactOptions, KvStore, RateLimiter, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+#[test]
+fn limits_throughput() {
+    let rate_limiter = RateLimiter::new(100_000);
+
+    let started = Instant::now();
+    for _ in 0..30 {
+        rate_limiter.acquire(1000);
+    }
+    let elapsed = started.elapsed();
+
+    // the last acquire is granted without waiting for its own bytes
+    assert!(elapsed >= Duration::from_millis(280), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+}
+
+#[test]
+fn unlimited_never_waits() {
+    let rate_limiter = RateLimiter::unlimited();
+
+    let started = Instant::now();
+    for _ in 0..1000 {
+        rate_limiter.acquire(1 << 20);
+    }
+    assert!(started.elapsed() < Duration::from_millis(100));
+}
+
+// Raising the rate should release a caller that is already waiting under the old one.
+#[test]
+fn rate_change_applies_to_waiters() {
+    let rate_limiter = RateLimiter::new(10);
+    rate_limiter.acquire(1000);
+
+    let waiter = {
+        let rate_limiter = rate_limiter.clone();
+        thread::spawn(move || {
+            let started = Instant::now();
+            rate_limiter.acquire(1);
+            started.elapsed()
+        })
+    };
+
+    thread::sleep(Duration::from_millis(100));
+    rate_limiter.set_rate(0);
+    assert_eq!(rate_limiter.rate(), 0);
+
+    let waited = waiter.join().unwrap();
+    assert!(waited < Duration::from_secs(1), "{:?}", waited);
+}
+
+// Explicit compactions use the store's limiter, whose rate can be changed while they run.
+#[test]
+fn store_compaction_rate_limiter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+        }
+    }
+
+    store.set_compaction_rate_limiter(RateLimiter::new(100));
+    let rate_limiter = store.compaction_rate_limiter();
+    assert_eq!(rate_limiter.rate(), 100);
+
+    let raise = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        rate_limiter.set_rate(0);
+    });
+
+    let started = Instant::now();
+    store.compact(CompactOptions::new())?;
+    let elapsed = started.elapsed();
+    raise.join().unwrap();
+
+    assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Compactions started by the policy go on under the store's limiter a little at a time, while the
+// store keeps serving reads and writes.
+#[test]
+fn automatic_compaction_limited() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_rate_limiter(RateLimiter::new(1000));
+    // live records at the start of the log, which the merge copies first
+    for key_id in 0..10 {
+        store.set(format!("fixed{}", key_id), "value".to_owned())?;
+    }
+
+    let mut slowest = Duration::from_secs(0);
+    for iter in 0..100 {
+        for key_id in 0..10 {
+            let started = Instant::now();
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", iter)));
+            slowest = slowest.max(started.elapsed());
+        }
+    }
+    assert!(slowest < Duration::from_millis(100), "{:?}", slowest);
+
+    // the merge is still under way
+    assert!(store.stats().last_compaction.is_none());
+    let merging = |path: &Path| -> Result<bool> {
+        for entry in fs::read_dir(path)? {
+            if entry?.path().extension() == Some(OsStr::new("merge")) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    };
+    assert!(merging(temp_dir.path())?);
+
+    store.compaction_rate_limiter().set_rate(0);
+    store.set("key0".to_owned(), "value100".to_owned())?;
+    assert!(store.stats().last_compaction.is_some());
+    assert_eq!(store.get("key0".to_owned())?, Some("value100".to_owned()));
+    for key_id in 1..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("value99".to_owned()));
+        assert_eq!(store.get(format!("fixed{}", key_id))?, Some("value".to_owned()));
+    }
+
+    // a merge left unfinished is cleaned up
+    store.set_compaction_rate_limiter(RateLimiter::new(1));
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    drop(store);
+    assert!(!merging(temp_dir.path())?);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+
+    Ok(())
+}
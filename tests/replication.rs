@@ -0,0 +1,352 @@
+use assert_cmd::prelude::*;
+use kvs::replication::{Follower, Leader};
+use kvs::{KvStore, Options, Result};
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn open_shared(temp_dir: &TempDir) -> Result<Arc<Mutex<KvStore>>> {
+    Ok(Arc::new(Mutex::new(KvStore::open(temp_dir.path())?)))
+}
+
+// Writes made on the leader before and after the follower connects should both arrive.
+#[test]
+fn follower_receives_backlog_and_stream() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let leader_store = open_shared(&leader_dir)?;
+    leader_store
+        .lock()
+        .unwrap()
+        .set("key1".to_owned(), "value1".to_owned())?;
+    leader_store
+        .lock()
+        .unwrap()
+        .set("key2".to_owned(), "value2".to_owned())?;
+    let leader = Leader::bind("127.0.0.1:0", leader_store.clone())?;
+
+    let follower = Follower::connect(leader.local_addr(), open_shared(&follower_dir)?)?;
+    assert!(follower.wait_synced());
+    assert_eq!(follower.position(), 2);
+
+    leader_store
+        .lock()
+        .unwrap()
+        .set("key3".to_owned(), "value3".to_owned())?;
+    leader_store.lock().unwrap().remove("key1".to_owned())?;
+    assert!(follower.wait_for(4, TIMEOUT));
+
+    let store = follower.promote()?;
+    let mut store = store.lock().unwrap();
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    // a promoted follower continues from the leader's timestamps
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(follower_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// A follower that fell behind while the leader compacted away tombstones it never saw
+// should still converge to the leader's contents.
+#[test]
+fn follower_catches_up_after_compaction() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let leader_store = open_shared(&leader_dir)?;
+    let leader = Leader::bind("127.0.0.1:0", leader_store.clone())?;
+
+    {
+        let mut store = leader_store.lock().unwrap();
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), "first".to_owned())?;
+        }
+    }
+
+    let follower = Follower::connect(leader.local_addr(), open_shared(&follower_dir)?)?;
+    assert!(follower.wait_synced());
+    drop(follower.promote()?);
+
+    let position = {
+        let mut store = leader_store.lock().unwrap();
+        for key_id in 0..50 {
+            store.remove(format!("key{}", key_id))?;
+        }
+        for iter in 0..20 {
+            for key_id in 50..100 {
+                store.set(format!("key{}", key_id), format!("{}", iter))?;
+            }
+        }
+        50 + 100 + 20 * 50
+    };
+
+    let follower = Follower::connect(leader.local_addr(), open_shared(&follower_dir)?)?;
+    assert!(follower.wait_for(position, TIMEOUT));
+
+    let store = follower.promote()?;
+    let mut store = store.lock().unwrap();
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    for key_id in 50..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A backlog read a chunk at a time should not miss the writes made while it is being sent.
+#[test]
+fn follower_catches_up_during_writes() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let leader_store = open_shared(&leader_dir)?;
+    {
+        let mut store = leader_store.lock().unwrap();
+        for key_id in 0..2000 {
+            store.set(format!("key{}", key_id), "first".to_owned())?;
+        }
+    }
+    let leader = Leader::bind("127.0.0.1:0", leader_store.clone())?;
+
+    let writer = {
+        let leader_store = leader_store.clone();
+        thread::spawn(move || -> Result<()> {
+            for key_id in 0..2000 {
+                let mut store = leader_store.lock().unwrap();
+                if key_id % 2 == 0 {
+                    store.set(format!("key{}", key_id), "second".to_owned())?;
+                } else {
+                    store.remove(format!("key{}", key_id))?;
+                }
+            }
+            Ok(())
+        })
+    };
+
+    let follower = Follower::connect(leader.local_addr(), open_shared(&follower_dir)?)?;
+    writer.join().unwrap()?;
+    let position = leader_store.lock().unwrap().last_timestamp();
+    assert!(follower.wait_for(position, TIMEOUT));
+
+    let store = follower.promote()?;
+    let mut store = store.lock().unwrap();
+    for key_id in 0..2000 {
+        let expected = if key_id % 2 == 0 {
+            Some("second".to_owned())
+        } else {
+            None
+        };
+        assert_eq!(store.get(format!("key{}", key_id))?, expected);
+    }
+
+    Ok(())
+}
+
+// `kvs follow <ADDR> --once` should copy the leader's data into the current directory and exit.
+#[test]
+fn cli_follow_once() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let leader_store = open_shared(&leader_dir)?;
+    leader_store
+        .lock()
+        .unwrap()
+        .set("key1".to_owned(), "value1".to_owned())?;
+    leader_store
+        .lock()
+        .unwrap()
+        .set("key2".to_owned(), "value2".to_owned())?;
+    let leader = Leader::bind("127.0.0.1:0", leader_store)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["follow", &leader.local_addr().to_string(), "--once"])
+        .current_dir(&follower_dir)
+        .assert()
+        .success();
+
+    let mut store = KvStore::open(follower_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Followers apply the leader's records as they are, with their TTLs, and leave evicting to it.
+#[test]
+fn follower_keeps_ttls_and_does_not_evict() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let leader_store = open_shared(&leader_dir)?;
+    leader_store
+        .lock()
+        .unwrap()
+        .set_with_ttl("short".to_owned(), "value".to_owned(), Duration::from_millis(50))?;
+    for key_id in 0..10 {
+        leader_store
+            .lock()
+            .unwrap()
+            .set(format!("key{}", key_id), "x".repeat(100))?;
+    }
+    let leader = Leader::bind("127.0.0.1:0", leader_store.clone())?;
+
+    let options = Options {
+        max_live_bytes: Some(200),
+        ..Options::default()
+    };
+    let follower_store = Arc::new(Mutex::new(KvStore::open_with_options(follower_dir.path(), options)?));
+    let follower = Follower::connect(leader.local_addr(), follower_store)?;
+    assert!(follower.wait_synced());
+
+    let store = follower.promote()?;
+    let mut store = store.lock().unwrap();
+    assert_eq!(store.stats().evictions, 0);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("x".repeat(100)));
+    }
+    thread::sleep(Duration::from_millis(60));
+    assert_eq!(store.get("short".to_owned())?, None);
+
+    Ok(())
+}
+
+// Clearing the leader should clear a follower that is already connected.
+#[test]
+fn follower_sees_clear() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let leader_store = open_shared(&leader_dir)?;
+    leader_store.lock().unwrap().set("a".to_owned(), "1".to_owned())?;
+    let leader = Leader::bind("127.0.0.1:0", leader_store.clone())?;
+
+    let follower = Follower::connect(leader.local_addr(), open_shared(&follower_dir)?)?;
+    assert!(follower.wait_synced());
+
+    let mut store = leader_store.lock().unwrap();
+    store.clear()?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    let last = store.last_timestamp();
+    drop(store);
+    assert!(follower.wait_for(last, TIMEOUT));
+
+    let store = follower.promote()?;
+    let mut store = store.lock().unwrap();
+    assert_eq!(store.get("a".to_owned())?, None);
+    assert_eq!(store.get("b".to_owned())?, Some("2".to_owned()));
+
+    Ok(())
+}
+
+// A follower should keep the indexes it registered up to date with the records it applies.
+#[test]
+fn follower_maintains_indexes() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+    let first = |value: &str| value.chars().take(1).map(String::from).collect();
+
+    let leader_store = open_shared(&leader_dir)?;
+    leader_store.lock().unwrap().register_index("first", first)?;
+    leader_store.lock().unwrap().set("k1".to_owned(), "xyz".to_owned())?;
+    let leader = Leader::bind("127.0.0.1:0", leader_store.clone())?;
+
+    let follower_store = open_shared(&follower_dir)?;
+    follower_store.lock().unwrap().register_index("first", first)?;
+    let follower = Follower::connect(leader.local_addr(), follower_store)?;
+    assert!(follower.wait_synced());
+    assert_eq!(
+        follower.store().lock().unwrap().query_index("first", "x")?,
+        vec!["k1".to_owned()]
+    );
+
+    let mut store = leader_store.lock().unwrap();
+    store.set("k1".to_owned(), "abc".to_owned())?;
+    store.set("k2".to_owned(), "xyz".to_owned())?;
+    let last = store.last_timestamp();
+    drop(store);
+    assert!(follower.wait_for(last, TIMEOUT));
+
+    let store = follower.promote()?;
+    let store = store.lock().unwrap();
+    assert_eq!(store.query_index("first", "x")?, vec!["k2".to_owned()]);
+    assert_eq!(store.query_index("first", "a")?, vec!["k1".to_owned()]);
+
+    // once promoted, its entries are written to the log like the leader's
+    drop(store);
+    let mut store = KvStore::open(follower_dir.path())?;
+    store.register_index("first", first)?;
+    assert_eq!(store.query_index("first", "x")?, vec!["k2".to_owned()]);
+    store.set("k3".to_owned(), "xyz".to_owned())?;
+    assert_eq!(store.query_index("first", "x")?, vec!["k2".to_owned(), "k3".to_owned()]);
+
+    Ok(())
+}
+
+// A follower process replicates a store served by a leader process, which takes its writes on stdin.
+#[test]
+fn cli_lead_follow() -> Result<()> {
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let follower_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut leader = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["lead", "127.0.0.1:0"])
+        .current_dir(&leader_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut stdin = leader.stdin.take().unwrap();
+    let mut stdout = BufReader::new(leader.stdout.take().unwrap()).lines();
+    let listening = stdout.next().unwrap()?;
+    let addr = listening.trim_start_matches("Listening on ").to_owned();
+
+    // each command is answered once it is written
+    let mut run = |command: &str| -> Result<String> {
+        writeln!(stdin, "{}", command)?;
+        Ok(stdout.next().unwrap()?)
+    };
+    assert_eq!(run("set key1 value1")?, "OK");
+    assert_eq!(run("set key2 value 2")?, "OK");
+    let follow = || {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["follow", &addr, "--once"])
+            .current_dir(&follower_dir)
+            .assert()
+            .success();
+    };
+    follow();
+
+    assert_eq!(run("rm key1")?, "OK");
+    assert_eq!(run("rm key1")?, "Key not found");
+    assert_eq!(run("set key3 value3")?, "OK");
+    follow();
+
+    drop(stdin);
+    assert!(leader.wait()?.success());
+
+    let mut store = KvStore::open(follower_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value 2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use assert_cmd::prelude::*;
+use kvs::{verify, CompactionPolicy, KvStore, Result, SegmentStats, Stats};
+use predicates::boolean::PredicateBooleanExt;
+use predicates::str::contains;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+// The incrementally maintained numbers should agree with a full scan of the segments.
+fn assert_matches_disk(stats: &Stats, path: &Path) -> Result<()> {
+    let report = verify::verify(path)?;
+    assert_eq!(stats.segments.len(), report.segments.len());
+
+    for (segment, scanned) in stats.segments.iter().zip(report.segments.iter()) {
+        assert_eq!(segment.file_name, scanned.file_name);
+        assert_eq!(segment.records, scanned.records);
+        assert_eq!(segment.tombstones, scanned.tombstones);
+        assert_eq!(segment.live_bytes, scanned.live_bytes);
+        assert_eq!(segment.dead_bytes, scanned.stale_bytes);
+    }
+
+    Ok(())
+}
+
+struct NeverCompact;
+
+impl CompactionPolicy for NeverCompact {
+    fn select(&self, _segments: &[SegmentStats]) -> Vec<usize> {
+        Vec::new()
+    }
+}
+
+#[test]
+fn stats_track_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_policy(NeverCompact);
+
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "other".to_owned())?;
+    }
+    for key_id in 10..15 {
+        store.remove(format!("key{}", key_id))?;
+    }
+
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 15);
+    assert_eq!(stats.tombstones(), 5);
+    assert_eq!(stats.live_bytes() + stats.dead_bytes(), stats.total_bytes());
+    assert_eq!(stats.last_compaction, None);
+    assert_matches_disk(&stats, temp_dir.path())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats(), stats);
+
+    Ok(())
+}
+
+#[test]
+fn stats_track_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut total_bytes = 0;
+    'outer: for iter in 0..100 {
+        for key_id in 0..20 {
+            store.set(format!("key{}", key_id), format!("value{}", iter))?;
+            if store.stats().last_compaction.is_some() {
+                break 'outer;
+            }
+            total_bytes = store.stats().total_bytes();
+        }
+    }
+
+    let stats = store.stats();
+    let compaction = stats.last_compaction.as_ref().expect("compaction should have run");
+    assert!(compaction.bytes_reclaimed > 0);
+    assert!(stats.total_bytes() < total_bytes);
+    assert_eq!(stats.live_keys, 20);
+    assert_matches_disk(&stats, temp_dir.path())?;
+
+    Ok(())
+}
+
+// The keydir estimate grows with the number and length of keys, and is released when the store is cleared.
+#[test]
+fn stats_keydir_bytes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    let one_key = store.stats().keydir_bytes;
+    assert!(one_key > 3);
+
+    for key_id in 0..1000 {
+        store.set(format!("{:0>100}", key_id), "value".to_owned())?;
+    }
+    let many_keys = store.stats().keydir_bytes;
+    assert!(many_keys > 1000 * 100);
+    assert!(many_keys < 1000 * 200);
+
+    // overwriting keys does not add to the index
+    for key_id in 0..1000 {
+        store.set(format!("{:0>100}", key_id), "other".to_owned())?;
+    }
+    assert_eq!(store.stats().keydir_bytes, many_keys);
+
+    store.clear()?;
+    assert_eq!(store.stats().keydir_bytes, 0);
+
+    Ok(())
+}
+
+#[test]
+fn cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("live keys: 1")
+                .and(contains("cache: 0 hits, 0 misses, 0 bytes"))
+                .and(contains("evictions: 0"))
+                .and(contains("live data: ")),
+        );
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("\"live_keys\":1")
+                .and(contains("\"last_compaction\":null"))
+                .and(contains("\"cache_hits\":0"))
+                .and(contains("\"evictions\":0"))
+                .and(contains("\"live_data_bytes\":")),
+        );
+
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use assert_cmd::prelude::*;
+use kvs::dump::SegmentDump;
+use kvs::{CompactOptions, KvStore, KvsError, Result};
+use predicates::str::contains;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+fn segments(path: &Path) -> Vec<PathBuf> {
+    let mut segments: Vec<PathBuf> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(OsStr::new("bcd")))
+        .collect();
+    segments.sort();
+    segments
+}
+
+fn read_value(store: &KvStore, key: &str) -> Result<Option<String>> {
+    match store.get_reader(key)? {
+        Some(mut rdr) => {
+            let mut value = String::new();
+            rdr.read_to_string(&mut value)?;
+            Ok(Some(value))
+        }
+        None => Ok(None),
+    }
+}
+
+// A value spanning many chunks, with characters split across their boundaries.
+fn large_value() -> String {
+    "€uro".repeat(50_000)
+}
+
+#[test]
+fn set_reader_get_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch("big");
+
+    let value = large_value();
+    store.set_reader("big".to_owned(), value.as_bytes(), value.len() as u64)?;
+    store.set("small".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.get_value_len("big"), Some(value.len() as u64));
+    assert_eq!(read_value(&store, "big")?, Some(value.clone()));
+    assert_eq!(store.get("big".to_owned())?, Some(value.clone()));
+    assert_eq!(read_value(&store, "small")?, Some("value".to_owned()));
+    assert_eq!(read_value(&store, "missing")?, None);
+    assert_eq!(watcher.try_recv().unwrap().value, Some(value.clone()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(read_value(&store, "big")?, Some(value.clone()));
+
+    store.compact(CompactOptions::new())?;
+    assert_eq!(read_value(&store, "big")?, Some(value.clone()));
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A reader keeps returning the value it was opened on.
+#[test]
+fn get_reader_outlives_overwrite() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut rdr = store.get_reader("key1")?.unwrap();
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.compact(CompactOptions::new())?;
+
+    let mut value = String::new();
+    rdr.read_to_string(&mut value)?;
+    assert_eq!(value, "value1");
+    assert_eq!(read_value(&store, "key1")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A failed write must leave neither the key nor a broken record behind.
+#[test]
+fn set_reader_failures() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let short = store.set_reader("key1".to_owned(), &b"abc"[..], 10);
+    assert!(matches!(short, Err(KvsError::Io(_))));
+    let invalid = store.set_reader("key1".to_owned(), &b"ab\xffcd"[..], 5);
+    assert!(matches!(invalid, Err(KvsError::Io(_))));
+    let cut = "ab€".as_bytes();
+    let cut = store.set_reader("key1".to_owned(), &cut[..3], 3);
+    assert!(matches!(cut, Err(KvsError::Io(_))));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A crash before the checksum of a streamed record is filled in leaves it whole but for that, which
+// opening the store cuts off.
+#[test]
+fn open_cuts_off_unchecksummed_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let segment = segments(temp_dir.path()).pop().unwrap();
+    let len = fs::metadata(&segment)?.len();
+    // crc, timestamp, flags, key and value lengths, key, value
+    let mut record = vec![0; 4];
+    record.extend_from_slice(&100u64.to_be_bytes());
+    record.push(0);
+    record.extend_from_slice(&4u32.to_be_bytes());
+    record.extend_from_slice(&6u32.to_be_bytes());
+    record.extend_from_slice(b"key2value2");
+    fs::OpenOptions::new().append(true).open(&segment)?.write_all(&record)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(fs::metadata(&segment)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+#[test]
+fn get_reader_detects_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let segment = segments(temp_dir.path()).pop().unwrap();
+    let record = SegmentDump::open(&segment)?.next().unwrap()?;
+    let mut buf = fs::read(&segment)?;
+    let last = (record.offset + record.len - 1) as usize;
+    buf[last] = b'x';
+    fs::write(&segment, &buf)?;
+
+    let mut value = String::new();
+    let err = store
+        .get_reader("key1")?
+        .unwrap()
+        .read_to_string(&mut value)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+    Ok(())
+}
+
+#[test]
+fn cli_set_file_get_out() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = large_value();
+    fs::write(temp_dir.path().join("in.txt"), &value)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "big", "--file", "in.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "big", "--out", "out.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(temp_dir.path().join("out.txt"))?, value);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "missing", "--out", "out.txt"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use kvs::{Codec, KvStore, KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tempfile::TempDir;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct User {
+    name: String,
+    age: u32,
+    tags: Vec<String>,
+}
+
+fn user(name: &str, age: u32) -> User {
+    User {
+        name: name.to_owned(),
+        age,
+        tags: vec!["staff".to_owned()],
+    }
+}
+
+#[test]
+fn typed_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for codec in [Codec::MsgPack, Codec::Bincode, Codec::Json] {
+        let name = format!("{:?}", codec);
+        let mut users = store.table::<u64, User>(&name, codec)?;
+        users.set(&1, &user("alice", 30))?;
+        users.set(&2, &user("bob", 40))?;
+        assert_eq!(users.get(&1)?, Some(user("alice", 30)));
+        assert_eq!(users.get(&3)?, None);
+        assert!(users.contains_key(&2)?);
+
+        users.remove(&2)?;
+        assert!(matches!(users.remove(&2), Err(KvsError::KeyNotFound)));
+        assert_eq!(users.iter()?, vec![(1, user("alice", 30))]);
+    }
+    assert_eq!(store.namespaces(), vec!["Bincode", "Json", "MsgPack"]);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.table::<u64, User>("Json", Codec::Json)?.get(&1)?,
+        Some(user("alice", 30))
+    );
+
+    Ok(())
+}
+
+// Keys come back in their own order, not in the order of their encodings' text.
+#[test]
+fn range_scans_follow_key_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut numbers = store.table::<i64, String>("numbers", Codec::Bincode)?;
+    for n in [-1000, -5, -1, 0, 1, 7, 300, 70_000, i64::MIN, i64::MAX] {
+        numbers.set(&n, &n.to_string())?;
+    }
+    let keys: Vec<i64> = numbers.range(-5..300)?.into_iter().map(|(n, _)| n).collect();
+    assert_eq!(keys, vec![-5, -1, 0, 1, 7]);
+    let keys: Vec<i64> = numbers.range(..=-5)?.into_iter().map(|(n, _)| n).collect();
+    assert_eq!(keys, vec![i64::MIN, -1000, -5]);
+    assert_eq!(numbers.range(7..)?.len(), 4);
+
+    let mut words = store.table::<(String, f64), ()>("words", Codec::MsgPack)?;
+    let mut expected = Vec::new();
+    for word in ["b", "a", "ab", "a\0", ""] {
+        for score in [2.5, -0.5, -3.0, 0.0] {
+            words.set(&(word.to_owned(), score), &())?;
+            expected.push((word.to_owned(), score));
+        }
+    }
+    expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let keys: Vec<(String, f64)> = words.iter()?.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, expected);
+    let keys: Vec<(String, f64)> = words
+        .range(("a".to_owned(), 0.0)..("ab".to_owned(), 0.0))?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(
+        keys,
+        vec![
+            ("a".to_owned(), 0.0),
+            ("a".to_owned(), 2.5),
+            ("a\0".to_owned(), -3.0),
+            ("a\0".to_owned(), -0.5),
+            ("a\0".to_owned(), 0.0),
+            ("a\0".to_owned(), 2.5),
+            ("ab".to_owned(), -3.0),
+            ("ab".to_owned(), -0.5),
+        ]
+    );
+
+    Ok(())
+}
+
+// Keys that compare equal are the same key, whatever their bits or iteration order.
+#[test]
+fn equal_keys_encode_the_same() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut scores = store.table::<f64, String>("scores", Codec::Bincode)?;
+    scores.set(&-0.0, &"zero".to_owned())?;
+    assert_eq!(scores.get(&0.0)?, Some("zero".to_owned()));
+    scores.set(&f64::NAN, &"nan".to_owned())?;
+    assert_eq!(scores.get(&-f64::NAN)?, Some("nan".to_owned()));
+    scores.set(&f64::INFINITY, &"infinity".to_owned())?;
+    let keys: Vec<String> = scores.iter()?.into_iter().map(|(_, value)| value).collect();
+    assert_eq!(keys, vec!["zero", "infinity", "nan"]);
+
+    let mut sets = store.table::<HashMap<String, u32>, ()>("sets", Codec::Json)?;
+    let mut forward = HashMap::new();
+    let mut backward = HashMap::new();
+    for i in 0..100 {
+        forward.insert(i.to_string(), i);
+        backward.insert((99 - i).to_string(), 99 - i);
+    }
+    sets.set(&forward, &())?;
+    assert!(sets.contains_key(&backward)?);
+    assert_eq!(sets.iter()?.len(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn decode_failures() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store
+        .table::<u64, User>("users", Codec::Json)?
+        .set(&1, &user("alice", 30))?;
+    assert!(matches!(
+        store.table::<u64, u32>("users", Codec::Json)?.get(&1),
+        Err(KvsError::Decode(_))
+    ));
+    assert!(matches!(
+        store.table::<u64, User>("users", Codec::Bincode)?.get(&1),
+        Err(KvsError::Decode(_))
+    ));
+    assert!(matches!(
+        store.table::<u64, User>("", Codec::Json),
+        Err(KvsError::InvalidNamespace(_))
+    ));
+
+    Ok(())
+}
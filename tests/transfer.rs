@@ -0,0 +1,138 @@
+use assert_cmd::prelude::*;
+use kvs::{ExportEncoding, ExportFormat, KvStore, KvsError, Result};
+use predicates::ord::eq;
+use predicates::str::PredicateStrExt;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn sample() -> Vec<(String, String)> {
+    vec![
+        ("plain".to_owned(), "value".to_owned()),
+        ("comma,key".to_owned(), "a \"quoted\", value".to_owned()),
+        ("multi\nline".to_owned(), "line1\nline2\r\n".to_owned()),
+        ("ключ".to_owned(), "значение 🎉".to_owned()),
+        ("empty".to_owned(), "".to_owned()),
+    ]
+}
+
+fn round_trip(format: ExportFormat, encoding: ExportEncoding) -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut src = KvStore::open(src_dir.path())?;
+    for (key, value) in sample() {
+        src.set(key, value)?;
+    }
+
+    let mut buf = Vec::new();
+    assert_eq!(src.export(&mut buf, format, encoding)?, 5);
+
+    let mut dst = KvStore::open(dst_dir.path())?;
+    assert_eq!(dst.import(&buf[..], format)?, 5);
+    for (key, value) in sample() {
+        assert_eq!(dst.get(key)?, Some(value));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn jsonl_round_trip() -> Result<()> {
+    round_trip(ExportFormat::Jsonl, ExportEncoding::Text)?;
+    round_trip(ExportFormat::Jsonl, ExportEncoding::Base64)
+}
+
+#[test]
+fn csv_round_trip() -> Result<()> {
+    round_trip(ExportFormat::Csv, ExportEncoding::Text)?;
+    round_trip(ExportFormat::Csv, ExportEncoding::Base64)
+}
+
+// Imports larger than one batch should all land, with later rows for a key winning.
+#[test]
+fn import_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let mut input = String::new();
+    for iter in 0..3 {
+        for key_id in 0..1000 {
+            input.push_str(&format!("{{\"key\":\"key{}\",\"value\":\"{}\"}}\n", key_id, iter));
+        }
+    }
+    assert_eq!(store.import(input.as_bytes(), ExportFormat::Jsonl)?, 3000);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("2".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn import_rejects_invalid_rows() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let inputs = [
+        "{\"key\":\"key1\"}\n",
+        "not json\n",
+        // base64 of the bytes ff fe, which are not UTF-8
+        "{\"key\":\"key1\",\"value_base64\":\"//4=\"}\n",
+    ];
+    for input in inputs.iter() {
+        match store.import(input.as_bytes(), ExportFormat::Jsonl) {
+            Err(KvsError::InvalidImport(_)) => {}
+            other => panic!("expected InvalidImport for {:?}, got {:?}", input, other),
+        }
+    }
+
+    match store.import(&b"k,v\nkey1,value1\n"[..], ExportFormat::Csv) {
+        Err(KvsError::InvalidImport(_)) => {}
+        other => panic!("expected InvalidImport, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// `kvs export` output fed to `kvs import --truncate` should replace the destination's contents.
+#[test]
+fn cli_export_import() -> Result<()> {
+    let src_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dst_dir = TempDir::new().expect("unable to create temporary working directory");
+    let export_path = src_dir.path().join("export.csv");
+
+    let mut store = KvStore::open(src_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(dst_dir.path())?;
+    store.set("stale".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "--output", export_path.to_str().unwrap()])
+        .current_dir(&src_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", export_path.to_str().unwrap(), "--format", "csv", "--truncate"])
+        .current_dir(&dst_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export"])
+        .current_dir(&dst_dir)
+        .assert()
+        .success()
+        .stdout(eq("{\"key\":\"key1\",\"value\":\"value1\"}").trim());
+
+    Ok(())
+}
@@ -0,0 +1,267 @@
+use kvs::{verify, CompactOptions, CompactionPolicy, KvStore, Options, RestorePoint, Result, Retention, SegmentStats};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+fn open(path: &Path) -> Result<KvStore> {
+    KvStore::open_with_options(
+        path,
+        Options {
+            value_log_threshold: Some(100),
+            ..Options::default()
+        },
+    )
+}
+
+fn files(path: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(OsStr::new(extension)))
+        .collect();
+    files.sort();
+    files
+}
+
+fn large(key_id: u64, iter: u64) -> String {
+    format!("{}:{}:", key_id, iter).repeat(500)
+}
+
+struct NeverCompact;
+
+impl CompactionPolicy for NeverCompact {
+    fn select(&self, _segments: &[SegmentStats]) -> Vec<usize> {
+        Vec::new()
+    }
+}
+
+#[test]
+fn large_values_go_to_value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    store.set("small".to_owned(), "value".to_owned())?;
+    store.set("large".to_owned(), large(0, 0))?;
+    let value = large(1, 0);
+    store.set_reader("streamed".to_owned(), value.as_bytes(), value.len() as u64)?;
+
+    let stats = store.stats();
+    assert_eq!(files(temp_dir.path(), "vlog").len(), 1);
+    assert!(stats.value_log_bytes > 2 * value.len() as u64);
+    assert!(stats.total_bytes() < 200);
+
+    assert_eq!(store.get("large".to_owned())?, Some(large(0, 0)));
+    assert_eq!(store.get_value_len("large"), Some(large(0, 0).len() as u64));
+    let mut streamed = String::new();
+    store.get_reader("streamed")?.unwrap().read_to_string(&mut streamed)?;
+    assert_eq!(streamed, value);
+
+    // the value log is read whatever the options
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("large".to_owned())?, Some(large(0, 0)));
+    assert_eq!(store.get("streamed".to_owned())?, Some(value));
+    assert_eq!(store.get_value_len("large"), Some(large(0, 0).len() as u64));
+
+    Ok(())
+}
+
+// Churning small keys must not copy the large values around.
+#[test]
+fn compaction_leaves_value_log_alone() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    for key_id in 0..10 {
+        store.set(format!("large{}", key_id), large(key_id, 0))?;
+    }
+    let value_log = fs::read(&files(temp_dir.path(), "vlog")[0])?;
+
+    for iter in 0..50 {
+        for key_id in 0..10 {
+            store.set(format!("small{}", key_id), format!("value{}", iter))?;
+        }
+    }
+    store.compact(CompactOptions::new())?;
+    assert!(store.stats().last_compaction.is_some());
+
+    assert_eq!(files(temp_dir.path(), "vlog").len(), 1);
+    assert_eq!(fs::read(&files(temp_dir.path(), "vlog")[0])?, value_log);
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("large{}", key_id))?, Some(large(key_id, 0)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn collect_value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), large(key_id, iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    let since = store.last_timestamp();
+    let before = store.stats().value_log_bytes;
+    assert!(files(temp_dir.path(), "vlog").len() > 2);
+
+    let reclaimed = store.collect_value_log(0.5)?;
+    assert!(reclaimed > 0);
+    assert_eq!(store.stats().value_log_bytes, before - reclaimed);
+    assert_eq!(store.collect_value_log(0.5)?, 0);
+
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(large(key_id, 9)));
+    }
+    // moving a value is not a change
+    assert_eq!(store.changes_since(since)?, vec![]);
+    store.changes_since(0)?;
+
+    drop(store);
+    let report = verify::verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{}", report);
+    let mut store = open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for key_id in 1..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(large(key_id, 9)));
+    }
+    store.compact(CompactOptions::new())?;
+    for key_id in 1..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(large(key_id, 9)));
+    }
+
+    Ok(())
+}
+
+// Moving a value should not add a version to its key, before or after reopening.
+#[test]
+fn collect_value_log_keeps_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        value_log_threshold: Some(100),
+        retention: Retention::Versions(2),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options.clone())?;
+
+    store.set("key".to_owned(), large(0, 0))?;
+    store.set("key".to_owned(), large(0, 1))?;
+    let written = store.last_timestamp();
+    for iter in 0..100 {
+        store.set("other".to_owned(), large(1, iter))?;
+    }
+    let history = store.history("key")?;
+    assert_eq!(history.len(), 2);
+
+    // the values of retained versions are moved along with live ones, under the timestamps they
+    // were written at
+    assert!(store.collect_value_log(0.5)? > 0);
+    assert_eq!(store.history("key")?, history);
+    assert_eq!(store.get_at("key", written - 1)?, Some(large(0, 0)));
+    assert_eq!(store.get_at("key", written)?, Some(large(0, 1)));
+    assert!(store.changes_since(written)?.iter().all(|event| event.key == "other"));
+
+    store.compact(CompactOptions::new())?;
+    assert_eq!(store.history("key")?, history);
+
+    drop(store);
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.history("key")?, history);
+
+    Ok(())
+}
+
+// Files in the directory that only look like value log files should be left alone.
+#[test]
+fn stray_value_log_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("foo.vlog"), b"")?;
+
+    let mut store = open(temp_dir.path())?;
+    store.set("key".to_owned(), large(0, 0))?;
+    drop(store);
+
+    let mut store = open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some(large(0, 0)));
+
+    Ok(())
+}
+
+#[test]
+fn checkpoint_and_repair_value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repair_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_path = backup_dir.path().join("backup");
+
+    let mut store = open(temp_dir.path())?;
+    for key_id in 0..20 {
+        store.set(format!("key{}", key_id), large(key_id, 0))?;
+    }
+    store.checkpoint(&backup_path)?;
+    drop(store);
+
+    KvStore::restore(&backup_path, restore_dir.path())?;
+    let mut store = KvStore::open(restore_dir.path())?;
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(large(key_id, 0)));
+    }
+
+    assert!(verify::verify(temp_dir.path())?.is_ok());
+    assert_eq!(verify::repair(temp_dir.path(), repair_dir.path())?, 20);
+    let mut store = KvStore::open(repair_dir.path())?;
+    for key_id in 0..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(large(key_id, 0)));
+    }
+
+    Ok(())
+}
+
+// Restoring to a point before values were moved by garbage collection finds them where they went,
+// and leaves out the keys whose value it deleted.
+#[test]
+fn restore_until_after_collect_value_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let mut store = open(temp_dir.path())?;
+    // compaction would drop the records left behind by the moves
+    store.set_compaction_policy(NeverCompact);
+    for key_id in 0..5 {
+        store.set(format!("key{}", key_id), large(key_id, 0))?;
+    }
+    for iter in 0..20 {
+        store.set("churn".to_owned(), large(9, iter))?;
+    }
+    let point = store.last_timestamp();
+    for iter in 20..40 {
+        store.set("churn".to_owned(), large(9, iter))?;
+    }
+    assert!(store.collect_value_log(0.5)? > 0);
+    store.checkpoint(&backup_dir.path().join("backup"))?;
+    drop(store);
+
+    let restored = KvStore::restore_until(
+        &backup_dir.path().join("backup"),
+        restore_dir.path(),
+        RestorePoint::Timestamp(point),
+    )?;
+    let mut store = open(restore_dir.path())?;
+    for key_id in 0..5 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(large(key_id, 0)));
+    }
+    assert_eq!(store.get("churn".to_owned())?, None);
+    assert_eq!(restored, 5);
+
+    Ok(())
+}
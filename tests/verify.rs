@@ -0,0 +1,193 @@
+use assert_cmd::prelude::*;
+use kvs::dump::SegmentDump;
+use kvs::verify::{self, CorruptionKind};
+use kvs::{CompactOptions, CompactionPolicy, KvStore, Result, SegmentHeader, SegmentStats};
+use predicates::boolean::PredicateBooleanExt;
+use predicates::str::contains;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+fn segments(path: &Path) -> Vec<PathBuf> {
+    let mut segments: Vec<PathBuf> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(OsStr::new("bcd")))
+        .collect();
+    segments.sort();
+    segments
+}
+
+fn populate(path: &Path) -> Result<()> {
+    let mut store = KvStore::open(path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.set("key3".to_owned(), "value4".to_owned())?;
+    store.remove("key3".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn verify_clean_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let report = verify::verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{}", report);
+
+    let records: u64 = report.segments.iter().map(|segment| segment.records).sum();
+    let tombstones: u64 = report.segments.iter().map(|segment| segment.tombstones).sum();
+    let live: u64 = report.segments.iter().map(|segment| segment.live_bytes).sum();
+    let stale: u64 = report.segments.iter().map(|segment| segment.stale_bytes).sum();
+    let total: u64 = segments(temp_dir.path())
+        .iter()
+        .map(|path| fs::metadata(path).unwrap().len() - SegmentHeader::LEN)
+        .sum();
+    assert_eq!(records, 5);
+    assert_eq!(tombstones, 1);
+    assert_eq!(live + stale, total);
+    assert!(live > 0 && stale > 0);
+
+    Ok(())
+}
+
+struct NeverCompact;
+
+impl CompactionPolicy for NeverCompact {
+    fn select(&self, _segments: &[SegmentStats]) -> Vec<usize> {
+        Vec::new()
+    }
+}
+
+// Compaction moves newer records into the files of older segments, so a healthy store can have
+// timestamps going back within a segment.
+#[test]
+fn verify_compacted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_policy(NeverCompact);
+
+    // three segments each holding one live key and otherwise overwritten values
+    for (segments, key) in [(2, "a"), (3, "b"), (4, "c")] {
+        store.set(key.to_owned(), "value".to_owned())?;
+        while store.stats().segments.len() < segments {
+            store.set("filler".to_owned(), "x".repeat(50))?;
+        }
+    }
+    let file_names: Vec<String> = store
+        .stats()
+        .segments
+        .into_iter()
+        .map(|segment| segment.file_name)
+        .collect();
+
+    // the first segment ends up with "a", then "c", then "b"
+    store.compact(CompactOptions::new().segments(vec![file_names[0].clone(), file_names[2].clone()]))?;
+    store.compact(CompactOptions::new().segments(vec![file_names[0].clone(), file_names[1].clone()]))?;
+    drop(store);
+
+    let report = verify::verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{}", report);
+
+    Ok(())
+}
+
+// Garbage in the middle of a segment should be skipped over, and a torn tail reported as truncated.
+#[test]
+fn verify_detects_corruption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let segment = segments(temp_dir.path()).pop().unwrap();
+    let second = SegmentDump::open(&segment)?.nth(1).unwrap()?.offset as usize;
+    let mut buf = fs::read(&segment)?;
+    for byte in buf[second + 8..second + 12].iter_mut() {
+        *byte = 0xff;
+    }
+    // the fixed part of a record whose key would take 256 bytes, followed by only 3 of them
+    let mut torn = vec![0; 13];
+    torn.extend_from_slice(&[0, 0, 1, 0, 0, 0, 0, 0, 1, 2, 3]);
+    buf.extend_from_slice(&torn);
+    fs::write(&segment, &buf)?;
+
+    let report = verify::verify(temp_dir.path())?;
+    assert!(!report.is_ok());
+
+    let corruptions = &report.segments.last().unwrap().corruptions;
+    assert_eq!(corruptions.len(), 2, "{}", report);
+    assert_eq!(corruptions[0].offset, second as u64);
+    assert_eq!(corruptions[0].kind, CorruptionKind::Undecodable);
+    assert_eq!(corruptions[1].kind, CorruptionKind::Truncated);
+    assert_eq!(corruptions[1].offset + corruptions[1].len, buf.len() as u64);
+
+    Ok(())
+}
+
+#[test]
+fn verify_reports_orphan_merge_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+    fs::write(temp_dir.path().join("000000001.merge"), b"")?;
+
+    let report = verify::verify(temp_dir.path())?;
+    assert!(!report.is_ok());
+    assert_eq!(report.orphan_merge_files, vec![temp_dir.path().join("000000001.merge")]);
+
+    Ok(())
+}
+
+// Repair should keep every key whose latest record survived the corruption.
+#[test]
+fn repair_salvages_live_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repair_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    let segment = segments(temp_dir.path()).pop().unwrap();
+    let second = SegmentDump::open(&segment)?.nth(1).unwrap()?.offset as usize;
+    let mut buf = fs::read(&segment)?;
+    buf[second + 8] = 0xff;
+    fs::write(&segment, &buf)?;
+
+    assert_eq!(verify::repair(temp_dir.path(), repair_dir.path())?, 1);
+
+    let mut store = KvStore::open(repair_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+#[test]
+fn cli_verify() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let repair_dir = TempDir::new().expect("unable to create temporary working directory");
+    populate(temp_dir.path())?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("no problems found"));
+
+    let segment = segments(temp_dir.path()).pop().unwrap();
+    let mut buf = fs::read(&segment)?;
+    buf.truncate(buf.len() - 1);
+    fs::write(&segment, &buf)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", "--repair", repair_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("truncated record").and(contains("salvaged 3 keys")));
+
+    Ok(())
+}
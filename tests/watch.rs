@@ -0,0 +1,188 @@
+use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsError, Options, Result, Tail, WatchEvent};
+use predicates::ord::eq;
+use predicates::str::PredicateStrExt;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+fn event(key: &str, value: Option<&str>, timestamp: u64) -> WatchEvent {
+    WatchEvent {
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+        timestamp,
+    }
+}
+
+// Only changes to keys under the watched prefix should be delivered.
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let users = store.watch("user/");
+    store.set("user/1".to_owned(), "alice".to_owned())?;
+    store.set("order/1".to_owned(), "book".to_owned())?;
+    store.set("user/2".to_owned(), "bob".to_owned())?;
+    store.remove("user/1".to_owned())?;
+
+    let events: Vec<WatchEvent> = users.try_iter().collect();
+    assert_eq!(
+        events,
+        vec![
+            event("user/1", Some("alice"), 1),
+            event("user/2", Some("bob"), 3),
+            event("user/1", None, 4),
+        ]
+    );
+
+    // a dropped receiver ends the watch without affecting writes
+    drop(users);
+    store.set("user/3".to_owned(), "carol".to_owned())?;
+
+    Ok(())
+}
+
+// The log should replay changes after a timestamp, across reopening the store.
+#[test]
+fn changes_since() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.last_timestamp(), 3);
+    assert_eq!(
+        store.changes_since(1)?,
+        vec![event("key2", Some("value2"), 2), event("key1", None, 3)]
+    );
+    assert_eq!(store.changes_since(3)?, vec![]);
+
+    Ok(())
+}
+
+// Once compaction has dropped a tombstone, older changes can no longer be replayed.
+#[test]
+fn changes_since_compacted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.remove("key".to_owned())?;
+    for iter in 0..1000 {
+        store.set("other".to_owned(), format!("{}", iter))?;
+    }
+
+    match store.changes_since(0) {
+        Err(KvsError::Compacted(horizon)) => assert!(horizon >= 2),
+        other => panic!("expected Compacted, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Reopening the store should keep the horizon compaction left, rather than guess it from the
+// timestamps missing from the log.
+#[test]
+fn changes_since_after_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    // overwritten values are compacted away, but no tombstone is
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    assert!(store.changes_since(0).is_ok());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.changes_since(0).is_ok());
+
+    store.remove("key".to_owned())?;
+    for iter in 0..1000 {
+        store.set("other".to_owned(), format!("{}", iter))?;
+    }
+    let horizon = match store.changes_since(0) {
+        Err(KvsError::Compacted(horizon)) => horizon,
+        other => panic!("expected Compacted, got {:?}", other),
+    };
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    match store.changes_since(0) {
+        Err(KvsError::Compacted(reopened)) => assert_eq!(reopened, horizon),
+        other => panic!("expected Compacted, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// A tail should pick up the changes of a store open elsewhere, values in the value log included,
+// without writing anything to its directory.
+#[test]
+fn tail_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        value_log_threshold: Some(100),
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let files = |dir: &TempDir| -> Vec<_> {
+        let mut files: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.file_name(), entry.metadata().unwrap().len())
+            })
+            .collect();
+        files.sort();
+        files
+    };
+
+    let mut tail = Tail::new(temp_dir.path())?;
+    assert_eq!(tail.position(), 1);
+    let mut since_start = Tail::since(temp_dir.path(), 0);
+
+    let large = "x".repeat(200);
+    store.set("key2".to_owned(), large.clone())?;
+    store.remove("key1".to_owned())?;
+    let before = files(&temp_dir);
+
+    assert_eq!(
+        tail.poll()?,
+        vec![event("key2", Some(&large), 2), event("key1", None, 3)]
+    );
+    assert_eq!(since_start.poll()?.len(), 3);
+    assert_eq!(tail.poll()?, vec![]);
+    assert_eq!(tail.position(), 3);
+    assert_eq!(files(&temp_dir), before);
+
+    Ok(())
+}
+
+// `kvs watch <PREFIX> --since <TS> --once` should print the matching changes and exit.
+#[test]
+fn cli_watch_once() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("user/1".to_owned(), "alice".to_owned())?;
+    store.set("order/1".to_owned(), "book".to_owned())?;
+    store.remove("user/1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["watch", "user/", "--since", "0", "--once"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("1 set user/1 alice\n3 rm user/1").trim());
+
+    Ok(())
+}